@@ -0,0 +1,33 @@
+#!/usr/bin/env bash
+# Records a mainnet block for the benchmarks, along with the outputs it spends
+# from earlier blocks as `txid:vout value script_pubkey` lines. Blocks already
+# recorded are left alone.
+
+set -euo pipefail
+export LC_ALL=C
+
+height=$1
+api=${ESPLORA_API:-https://mempool.space/api}
+benches="src/indexer/src/benches"
+block="$benches/mainnet_block_$height.raw"
+prevouts="$benches/mainnet_block_${height}_prevouts.txt"
+
+if [[ -f "$block" && -f "$prevouts" ]]; then
+  exit 0
+fi
+
+hash=$(curl -sSf "$api/block-height/$height")
+count=$(curl -sSf "$api/block/$hash" | jq .tx_count)
+curl -sSf "$api/block/$hash/raw" > "$block.tmp"
+
+txids=$(curl -sSf "$api/block/$hash/txids" | jq -r '.[]' | sort)
+: > "$prevouts.tmp"
+for ((start = 0; start < count; start += 25)); do
+  curl -sSf "$api/block/$hash/txs/$start" \
+    | jq -r '.[].vin[] | select(.is_coinbase | not) | "\(.txid):\(.vout) \(.prevout.value) \(.prevout.scriptpubkey)"' \
+    >> "$prevouts.tmp"
+done
+# outputs created and spent in the block are indexed with it
+sort "$prevouts.tmp" | join -v 1 -t : - <(echo "$txids") > "$prevouts"
+rm "$prevouts.tmp"
+mv "$block.tmp" "$block"
@@ -1,19 +1,25 @@
 use std::str::FromStr;
 
-use bitcoin::{OutPoint, Txid};
+use bitcoin::{block::Header, BlockHash, OutPoint, Txid};
 use candid::{candid_method, Principal};
 use ic_canister_log::log;
-use ic_canisters_http_types::{HttpRequest, HttpResponse};
-use ic_cdk::{api::management_canister::http_request::{HttpResponse as HttpResponse2 , TransformArgs}, init, post_upgrade, query, update};
+use ic_canisters_http_types::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use ic_cdk::{api::management_canister::http_request::{HttpResponse as HttpResponse2 , TransformArgs}, init, post_upgrade, pre_upgrade, query, update};
 use logs::{ERROR, INFO};
-use indexer_interface::{Error, GetEtchingResult, Inscription as InscriptionApi, InscriptionEntry, InscriptionQuery as InscriptionQueryApi, RuneBalance, RuneEntry, Terms};
+use ordinals::SatPoint;
+use serde_bytes::ByteBuf;
+use indexer_interface::{BackfillProgress, BlockHeader, BlockInfo, CertifiedInscriptionLocation, CertifiedRuneBalances, ConfigChange as ConfigChangeApi, Direction, Error, GetEtchingResult, IndexFeature as IndexFeatureApi, IndexerState, IndexerStatus, Inscription as InscriptionApi, InscriptionEntry, InscriptionPage, InscriptionQuery as InscriptionQueryApi, OutputAssets, Role as RoleApi, RuneAmount, RuneBalance, RuneEntry, SimulatedOutput, SnapshotChunk, SnapshotCursor, SnapshotManifest, SnapshotTable, Statistics, Terms, TransactionSimulation};
 
 use crate::{
-    config::InitIndexerArgs, 
-    index::{self, cancel_shutdown, entry::Entry, inscription_info, mem_get_config, mem_get_etching, mem_get_inscription_id_to_sequence_number, mem_get_outpoint_to_height, mem_get_outpoint_to_rune_balances, mem_get_rune_id_to_rune_entry, mem_get_rune_to_rune_id, mem_get_sequence_number_to_inscription_entry, mem_latest_block, mem_latest_block_height, mem_set_config, shut_down, updater::update_index, Index}, 
+    config::{url_host, Config, InitIndexerArgs, LogLevel, UpgradeArgs}, 
+    index::{self, cancel_shutdown, certification, checkpoint, entry::{ConfigChange, Entry, IndexFeature, Role, Snapshot}, inscription_info, migration, output_assets, reorg::Reorg, snapshot, mem_block_hash, mem_config_changes, mem_get_block_header, mem_get_block_height, mem_get_block_summary, mem_get_config, mem_get_height_to_last_sequence_number, mem_get_statistic_count, mem_get_etching, mem_get_inscription_id_to_sequence_number, mem_get_outpoint_to_height, mem_get_outpoint_to_rune_balances, mem_get_rune_id_to_rune_entry, mem_get_role, mem_get_rune_to_rune_id, mem_get_sequence_number_to_inscription_entry, mem_get_indexer_running, mem_latest_block, mem_latest_block_height, mem_insert_role, mem_record_config_change, mem_remove_role, mem_roles, mem_set_config, mem_set_indexer_running, shut_down, status, updater::{backfill, resume_index_loop, simulation, start_index_loop, start_watchdog}, Index, Statistic}, 
     inscriptions::{InscriptionId, InscriptionQuery}, rpc::should_keep
 };
 
+const MAX_OUTPOINTS: usize = 64;
+const MAX_INSCRIPTIONS_PER_PAGE: u32 = 1_000;
+const MAX_CONFIG_CHANGES_PER_PAGE: u32 = 100;
+
 #[init]
 #[candid_method(init)]
 fn init(init_indexer_args: InitIndexerArgs) {
@@ -25,7 +31,11 @@ fn init(init_indexer_args: InitIndexerArgs) {
     if config.index_runes.is_none() {
         config.index_runes = Some(true);
     }
+      logs::set_log_level(config.get_log_level().into());
       mem_set_config(config).unwrap();
+      migration::set_current().unwrap();
+      resume();
+      start_watchdog();
     }
     InitIndexerArgs::Upgrade(_) => ic_cdk::trap(
       "Cannot initialize the canister with an Upgrade argument. Please provide an Init argument.",
@@ -33,25 +43,101 @@ fn init(init_indexer_args: InitIndexerArgs) {
   }
 }
 
+/// Picks up where the canister left off once the stable maps are on the
+/// current schema.
+fn resume() {
+  if mem_get_config().certified_queries.unwrap_or_default() {
+    certification::resume();
+  }
+  snapshot::resume();
+  resume_index_loop();
+}
+
+/// Upgrades can't wait on the index loop, so one that would land in the middle
+/// of a block is rejected and has to be retried once the block is committed. A
+/// block left behind by a loop that trapped is kept in stable memory and rolled
+/// back once indexing resumes.
+#[pre_upgrade]
+fn pre_upgrade() {
+  if let Some(height) = status::block_in_flight() {
+    ic_cdk::trap(&format!(
+      "block {} is still being indexed, retry the upgrade once it has been committed",
+      height
+    ));
+  }
+}
+
 #[post_upgrade]
 fn post_upgrade(runes_indexer_args: Option<InitIndexerArgs>) {
   match runes_indexer_args {
     Some(InitIndexerArgs::Upgrade(Some(upgrade_args))) => {
       let mut config = mem_get_config();
-      if let Some(bitcoin_rpc_url) = upgrade_args.bitcoin_rpc_url {
+      if let Some(bitcoin_rpc_url) = upgrade_args.bitcoin_rpc_url.clone() {
         config.bitcoin_rpc_url = bitcoin_rpc_url;
       }
-      if let Some(subscribers) = upgrade_args.subscribers {
+      if let Some(subscribers) = upgrade_args.subscribers.clone() {
         config.subscribers = subscribers;
         log!(INFO, "subscribers updated: {:?}", config.subscribers);
       }
+      if let Some(transaction_cache_bytes) = upgrade_args.transaction_cache_bytes {
+        config.transaction_cache_bytes = Some(transaction_cache_bytes);
+      }
       mem_set_config(config).unwrap();
+      record_config_change(format!(
+        "upgrade {:?}",
+        UpgradeArgs {
+          bitcoin_rpc_url: upgrade_args.bitcoin_rpc_url.as_deref().map(|url| url_host(url).to_string()),
+          ..upgrade_args
+        }
+      ));
     }
     None | Some(InitIndexerArgs::Upgrade(None)) => {}
     _ => ic_cdk::trap(
       "Cannot upgrade the canister with an Init argument. Please provide an Upgrade argument.",
     ),
   }
+  logs::set_log_level(mem_get_config().get_log_level().into());
+  // indexing resumes once the stable maps are on the current schema
+  if let Err(e) = migration::migrate(resume) {
+    ic_cdk::trap(&format!("failed to migrate stable memory: {:?}", e));
+  }
+  start_watchdog();
+}
+
+/// Queries reading the index refuse to answer while a schema migration or a
+/// checkpoint restore has left its maps half-written.
+fn readable() -> Result<(), Error> {
+  if migration::is_pending() {
+    return Err(Error::MigrationPending);
+  }
+  if checkpoint::is_restoring() {
+    return Err(Error::CheckpointRestoring);
+  }
+  Ok(())
+}
+
+/// Like `readable`, for queries whose result has no room for an error.
+fn trap_unless_readable() {
+  if let Err(e) = readable() {
+    ic_cdk::trap(&e.to_string());
+  }
+}
+
+#[query]
+pub fn get_status() -> IndexerStatus {
+  let status = status::status();
+  IndexerStatus {
+    state: match status::state() {
+      status::State::Running => IndexerState::Running,
+      status::State::Stopped => IndexerState::Stopped,
+      status::State::Stalled => IndexerState::Stalled,
+    },
+    last_indexed_height: status.last_indexed.as_ref().map(|(height, _)| *height).or_else(mem_latest_block_height),
+    last_indexed_at: status.last_indexed.as_ref().map(|(_, at)| *at),
+    last_error_at: status.last_error.as_ref().map(|(_, at)| *at),
+    last_error: status.last_error.map(|(error, _)| error),
+    consecutive_failures: status.consecutive_failures,
+  }
 }
 
 #[query]
@@ -60,8 +146,146 @@ pub fn get_latest_block() -> (u32, String) {
   (height, hash.to_string())
 }
 
+fn block_header(height: u32, header: Header) -> BlockHeader {
+  BlockHeader {
+    height,
+    hash: header.block_hash().to_string(),
+    version: header.version.to_consensus(),
+    previous_block_hash: header.prev_blockhash.to_string(),
+    merkle_root: header.merkle_root.to_string(),
+    timestamp: header.time,
+    bits: header.bits.to_consensus(),
+    nonce: header.nonce,
+  }
+}
+
+#[query]
+pub fn get_block_header(height: u32) -> Option<BlockHeader> {
+  mem_get_block_header(height).map(|header| block_header(height, header))
+}
+
+#[query]
+pub fn get_block_hash(height: u32) -> Option<String> {
+  mem_block_hash(height).map(|hash| hash.to_string())
+}
+
+/// Hex-encoded rolling commitment to everything indexed up to `height`, for
+/// comparing indexers block by block. `None` if the canister indexed blocks
+/// before commitments were recorded, see the README on how the chain is seeded.
+#[query]
+pub fn get_state_commitment(height: u32) -> Option<String> {
+  index::mem_get_commitment(height).map(hex::encode)
+}
+
+/// Balances of `outpoint` with a certificate and witness, so they can be
+/// verified without an update call.
+#[query]
+pub fn get_certified_rune_balances(outpoint: String) -> Result<CertifiedRuneBalances, String> {
+  readable().map_err(|e| e.to_string())?;
+  let outpoint = OutPoint::from_str(&outpoint).map_err(|e| e.to_string())?.store();
+  let (height, certificate, witness) = certification::rune_balances_witness(&outpoint)?;
+  let balances = mem_get_outpoint_to_rune_balances(outpoint).map(|rune_balances| {
+    rune_balances
+      .balances
+      .iter()
+      .map(|balance| RuneAmount {
+        rune_id: balance.rune_id.to_string(),
+        amount: balance.balance,
+      })
+      .collect()
+  });
+
+  Ok(CertifiedRuneBalances {
+    height,
+    balances,
+    certificate: ByteBuf::from(certificate),
+    witness: ByteBuf::from(witness),
+  })
+}
+
+/// Location of an inscription with a certificate and witness.
+#[query]
+pub fn get_certified_inscription_location(inscription_id: String) -> Result<CertifiedInscriptionLocation, String> {
+  readable().map_err(|e| e.to_string())?;
+  let inscription_id = InscriptionId::from_str(&inscription_id).map_err(|e| e.to_string())?;
+  let (height, certificate, witness) = certification::inscription_witness(&inscription_id)?;
+  let satpoint = mem_get_inscription_id_to_sequence_number(&inscription_id)
+    .and_then(index::mem_get_sequence_number_to_satpoint)
+    .map(|satpoint| SatPoint::load(satpoint).to_string());
+
+  Ok(CertifiedInscriptionLocation {
+    height,
+    satpoint,
+    certificate: ByteBuf::from(certificate),
+    witness: ByteBuf::from(witness),
+  })
+}
+
+/// Blocks indexed before summaries were recorded return `None`.
+#[query]
+pub fn get_block_info(height_or_hash: String) -> Result<Option<BlockInfo>, String> {
+  let height = match height_or_hash.parse::<u32>() {
+    Ok(height) => height,
+    Err(_) => {
+      let block_hash = BlockHash::from_str(&height_or_hash).map_err(|e| e.to_string())?;
+      let Some(height) = mem_get_block_height(block_hash) else {
+        return Ok(None);
+      };
+      height
+    }
+  };
+
+  let (Some(header), Some(summary)) = (mem_get_block_header(height), mem_get_block_summary(height)) else {
+    return Ok(None);
+  };
+
+  let inscriptions_created = match mem_get_height_to_last_sequence_number(height) {
+    Some(last) => last - mem_get_height_to_last_sequence_number(height.saturating_sub(1)).unwrap_or(0),
+    None => 0,
+  };
+
+  Ok(Some(BlockInfo {
+    header: block_header(height, header),
+    transaction_count: summary.transaction_count,
+    fees: summary.fees,
+    inscriptions_created: inscriptions_created.into(),
+    inscription_transfers: summary.inscription_transfers,
+    runes_etched: summary.rune_etchings,
+    rune_mints: summary.rune_mints,
+    rune_burns: summary.rune_burns,
+    rune_transfers: summary.rune_transfers,
+  }))
+}
+
+#[query]
+pub fn get_statistics() -> Statistics {
+  let indexed_height = mem_latest_block_height();
+  let rpc_tip_height = index::rpc_tip_height();
+
+  Statistics {
+    blessed_inscriptions: mem_get_statistic_count(Statistic::BlessedInscriptions),
+    cursed_inscriptions: mem_get_statistic_count(Statistic::CursedInscriptions),
+    unbound_inscriptions: mem_get_statistic_count(Statistic::UnboundInscriptions),
+    lost_sats: mem_get_statistic_count(Statistic::LostSats),
+    outputs_traversed: mem_get_statistic_count(Statistic::OutputsTraversed),
+    sat_ranges: mem_get_statistic_count(Statistic::SatRanges),
+    commits: mem_get_statistic_count(Statistic::Commits),
+    runes: index::mem_statistic_runes(),
+    reserved_runes: index::mem_statistic_reserved_runes(),
+    transaction_cache_bytes: mem_get_statistic_count(Statistic::TransactionCacheBytes),
+    indexed_height,
+    rpc_tip_height,
+    sync_lag: rpc_tip_height.map(|tip| tip.saturating_sub(indexed_height.unwrap_or_default())),
+    tables: index::mem_table_sizes()
+      .into_iter()
+      .map(|(name, entries)| (name.to_string(), entries))
+      .collect(),
+  }
+}
+
 #[query]
 pub fn get_etching(txid: String) -> Option<GetEtchingResult> {
+  trap_unless_readable();
   let txid = Txid::from_str(&txid).ok()?;
   let cur_height = mem_latest_block_height().expect("No block height found");
 
@@ -73,6 +297,7 @@ pub fn get_etching(txid: String) -> Option<GetEtchingResult> {
 
 #[query]
 pub fn get_rune(str_spaced_rune: String) -> Option<RuneEntry> {
+  trap_unless_readable();
   let spaced_rune = ordinals::SpacedRune::from_str(&str_spaced_rune).ok()?;
   let rune_id_value = mem_get_rune_to_rune_id(spaced_rune.rune.0)?;
   let rune_entry = mem_get_rune_id_to_rune_entry(rune_id_value)?;
@@ -102,6 +327,7 @@ pub fn get_rune(str_spaced_rune: String) -> Option<RuneEntry> {
 
 #[query]
 pub fn get_rune_by_id(str_rune_id: String) -> Option<RuneEntry> {
+  trap_unless_readable();
   let rune_id = ordinals::RuneId::from_str(&str_rune_id).ok()?;
   let rune_entry = mem_get_rune_id_to_rune_entry(rune_id.store())?;
   let cur_height = mem_latest_block_height().expect("No block height found");
@@ -132,9 +358,10 @@ pub fn get_rune_by_id(str_rune_id: String) -> Option<RuneEntry> {
 pub fn get_rune_balances_for_outputs(
   outpoints: Vec<String>,
 ) -> Result<Vec<Option<Vec<RuneBalance>>>, Error> {
-  if outpoints.len() > 64 {
+  if outpoints.len() > MAX_OUTPOINTS {
     return Err(Error::MaxOutpointsExceeded);
   }
+  readable()?;
 
   let cur_height = mem_latest_block_height().expect("No block height found");
   let mut piles = Vec::new();
@@ -191,8 +418,77 @@ pub fn get_rune_balances_for_outputs(
   Ok(piles)
 }
 
-#[update]
-pub async fn get_inscription_info(arg: InscriptionQueryApi, child: Option<usize>) -> Result<Option<InscriptionApi>, String> {
+#[query]
+pub fn get_output_assets(outpoints: Vec<String>) -> Result<Vec<Option<OutputAssets>>, Error> {
+  if outpoints.len() > MAX_OUTPOINTS {
+    return Err(Error::MaxOutpointsExceeded);
+  }
+  readable()?;
+
+  let index = Index::from_config(&mem_get_config());
+
+  Ok(
+    outpoints
+      .iter()
+      .map(|str_outpoint| match OutPoint::from_str(str_outpoint) {
+        Ok(outpoint) => output_assets(&index, outpoint).map(|assets| OutputAssets {
+          inscriptions: assets.inscriptions,
+          runes: assets.runes,
+          rare_sats: assets.rare_sats,
+        }),
+        Err(e) => {
+          log!(ERROR, "Failed to parse outpoint {}: {}", str_outpoint, e);
+          None
+        }
+      })
+      .collect(),
+  )
+}
+
+/// Simulates a hex encoded PSBT or raw transaction against the indexed state
+/// of its inputs, reporting where inscriptions, runes and rare sats would go.
+#[query]
+pub fn simulate_transaction(hex: String) -> Result<TransactionSimulation, String> {
+  readable().map_err(|e| e.to_string())?;
+  let (tx, input_values) = simulation::decode_transaction(&hex).map_err(|e| e.to_string())?;
+  let index = Index::from_config(&mem_get_config());
+  let simulation = simulation::simulate_transaction(&index, &tx, &input_values).map_err(|e| e.to_string())?;
+
+  let rune_amounts = |runes: Vec<(ordinals::RuneId, u128)>| {
+    runes
+      .into_iter()
+      .map(|(rune_id, amount)| RuneAmount {
+        rune_id: rune_id.to_string(),
+        amount,
+      })
+      .collect::<Vec<RuneAmount>>()
+  };
+
+  Ok(TransactionSimulation {
+    outputs: simulation
+      .outputs
+      .into_iter()
+      .map(|output| SimulatedOutput {
+        inscriptions: output.inscriptions.iter().map(|id| id.to_string()).collect(),
+        runes: rune_amounts(output.runes),
+        rare_sats: output.rare_sats.map(|sats| sats.iter().map(|sat| sat.n()).collect()),
+        op_return: output.op_return,
+      })
+      .collect(),
+    inscriptions_to_fee: simulation.inscriptions_to_fee.iter().map(|id| id.to_string()).collect(),
+    unbound_inscriptions: simulation.unbound_inscriptions.iter().map(|id| id.to_string()).collect(),
+    rare_sats_to_fee: simulation.rare_sats_to_fee.map(|sats| sats.iter().map(|sat| sat.n()).collect()),
+    runes_burned: rune_amounts(simulation.runes_burned),
+    cenotaph: simulation.cenotaph,
+    etched_rune: simulation.etched_rune.map(|rune| rune.to_string()),
+  })
+}
+
+/// `address` is unknown for inscriptions that haven't moved since an upgrade,
+/// unless addresses or transactions are indexed.
+#[query]
+pub fn get_inscription_info(arg: InscriptionQueryApi, child: Option<usize>) -> Result<Option<InscriptionApi>, String> {
+  readable().map_err(|e| e.to_string())?;
   let query = match arg {
     InscriptionQueryApi::Id(id) => {
       let inscription_id = match InscriptionId::from_str(&id) {
@@ -214,9 +510,9 @@ pub async fn get_inscription_info(arg: InscriptionQueryApi, child: Option<usize>
       return Err("Sat index is disabled".to_string());
     }
   }
-  let info = inscription_info(&index, query, child).await.map_err(|e| e.to_string())?;
+  let info = inscription_info(&index, query, child).map_err(|e| e.to_string())?;
   match info {
-    Some((info,_,_)) => Ok(Some(InscriptionApi {
+    Some(info) => Ok(Some(InscriptionApi {
       address: info.address,
       charms: info.charms.iter().map(|c| c.to_string()).collect(),
       child_count: info.child_count,
@@ -244,6 +540,7 @@ pub async fn get_inscription_info(arg: InscriptionQueryApi, child: Option<usize>
 
 #[query]
 pub fn get_inscription_entry(inscription_id_str: String) -> Result<Option<InscriptionEntry>, String> {
+  readable().map_err(|e| e.to_string())?;
   let inscription_id = match InscriptionId::from_str(&inscription_id_str) {
     Ok(id) => id,
     Err(e) => return Err(e.to_string()),
@@ -274,6 +571,7 @@ pub fn get_inscription_entry(inscription_id_str: String) -> Result<Option<Inscri
 
 #[query]
 pub fn get_inscriptions_in_block(block_height: u32) -> Result<Vec<String>, String> {
+  readable().map_err(|e| e.to_string())?;
   let r = index::get_inscriptions_in_block(block_height);
   match r {
     Ok(inscriptions) => Ok(inscriptions.iter().map(|id| id.to_string()).collect()),
@@ -281,19 +579,58 @@ pub fn get_inscriptions_in_block(block_height: u32) -> Result<Vec<String>, Strin
   }
 }
 
+fn inscription_page(
+  range: std::ops::Range<u32>,
+  from_sequence: Option<u32>,
+  limit: u32,
+  direction: Direction,
+) -> InscriptionPage {
+  let (inscriptions, next) = index::inscriptions_page(
+    range,
+    from_sequence,
+    limit.min(MAX_INSCRIPTIONS_PER_PAGE) as usize,
+    matches!(direction, Direction::Descending),
+  );
+  InscriptionPage {
+    inscriptions: inscriptions.iter().map(|id| id.to_string()).collect(),
+    next,
+  }
+}
+
+#[query]
+pub fn list_inscriptions_in_block(block_height: u32, from_sequence: Option<u32>, limit: u32) -> InscriptionPage {
+  trap_unless_readable();
+  inscription_page(
+    index::block_sequence_numbers(block_height),
+    from_sequence,
+    limit,
+    Direction::Ascending,
+  )
+}
+
+#[query]
+pub fn list_inscriptions(from_sequence: Option<u32>, limit: u32, direction: Direction) -> InscriptionPage {
+  trap_unless_readable();
+  inscription_page(0..u32::MAX, from_sequence, limit, direction)
+}
+
+/// The most recent inscriptions shown on the home page, newest first, at most 100.
+#[query]
+pub fn get_latest_inscriptions(limit: u32) -> Vec<String> {
+  trap_unless_readable();
+  index::mem_latest_home_inscriptions(limit as usize)
+    .iter()
+    .map(|id| id.to_string())
+    .collect()
+}
 
 #[update(hidden = true)]
 pub fn start() -> Result<(), String> {
-  let caller = ic_cdk::api::caller();
-  if !ic_cdk::api::is_controller(&caller) {
-    return Err("Not authorized".to_string());
-  }
+  authorize(Role::Operator)?;
 
   cancel_shutdown();
-  let config = mem_get_config();
-  let indexer = Index::from_config(&config);
-  let _ = update_index(config.network, indexer, config.subscribers);
-  Ok(())
+  mem_set_indexer_running(true).map_err(|e| e.to_string())?;
+  start_index_loop().map_err(|e| e.to_string())
 }
 
 #[query(hidden = true)]
@@ -313,38 +650,497 @@ pub fn rpc_transform(args: TransformArgs) -> HttpResponse2 {
 
 #[update(hidden = true)]
 pub fn stop() -> Result<(), String> {
-  let caller = ic_cdk::api::caller();
-  if !ic_cdk::api::is_controller(&caller) {
-    return Err("Not authorized".to_string());
-  }
+  authorize(Role::Operator)?;
 
   shut_down();
+  mem_set_indexer_running(false).map_err(|e| e.to_string())?;
   log!(INFO, "Waiting for index thread to finish...");
 
   Ok(())
 }
 
-#[update(hidden = true)]
-pub fn set_bitcoin_rpc_url(url: String) -> Result<(), String> {
+fn record_config_change(change: String) {
   let caller = ic_cdk::api::caller();
-  if !ic_cdk::api::is_controller(&caller) {
-    return Err("Not authorized".to_string());
+  log!(INFO, "config changed by {}: {}", caller, change);
+  mem_record_config_change(ConfigChange {
+    timestamp: ic_cdk::api::time(),
+    caller,
+    change,
+  });
+}
+
+/// Controllers hold every role.
+fn authorize(role: Role) -> Result<(), String> {
+  let caller = ic_cdk::api::caller();
+  if ic_cdk::api::is_controller(&caller) || mem_get_role(caller).is_some_and(|granted| granted >= role) {
+    Ok(())
+  } else {
+    Err("Not authorized".to_string())
   }
+}
+
+/// Applies `update` to the stored config if the caller holds `role`, and records it.
+fn update_config(
+  role: Role,
+  change: String,
+  update: impl FnOnce(&mut Config) -> Result<(), String>,
+) -> Result<(), String> {
+  authorize(role)?;
   let mut config = mem_get_config();
-  config.bitcoin_rpc_url = url;
-  mem_set_config(config).unwrap();
+  update(&mut config)?;
+  mem_set_config(config).map_err(|e| e.to_string())?;
+  record_config_change(change);
+
+  Ok(())
+}
+
+#[update(hidden = true)]
+pub fn set_bitcoin_rpc_url(url: String) -> Result<(), String> {
+  update_config(Role::Operator, format!("set_bitcoin_rpc_url {}", url_host(&url)), |config| {
+    config.bitcoin_rpc_url = url;
+    Ok(())
+  })
+}
+
+/// The first url becomes the primary endpoint, the rest are tried in order when it fails.
+#[update(hidden = true)]
+pub fn set_bitcoin_rpc_urls(urls: Vec<String>) -> Result<(), String> {
+  let hosts: Vec<&str> = urls.iter().map(|url| url_host(url)).collect();
+  update_config(Role::Operator, format!("set_bitcoin_rpc_urls {:?}", hosts), |config| {
+    let mut urls = urls.into_iter();
+    config.bitcoin_rpc_url = urls.next().ok_or("At least one url is required")?;
+    config.bitcoin_rpc_fallback_urls = Some(urls.collect());
+    Ok(())
+  })
+}
+
+#[update(hidden = true)]
+pub fn add_subscriber(subscriber: Principal) -> Result<(), String> {
+  update_config(Role::Admin, format!("add_subscriber {}", subscriber), |config| {
+    if config.subscribers.contains(&subscriber) {
+      return Err("Already subscribed".to_string());
+    }
+    config.subscribers.push(subscriber);
+    Ok(())
+  })
+}
+
+#[update(hidden = true)]
+pub fn remove_subscriber(subscriber: Principal) -> Result<(), String> {
+  update_config(Role::Admin, format!("remove_subscriber {}", subscriber), |config| {
+    let len = config.subscribers.len();
+    config.subscribers.retain(|s| *s != subscriber);
+    if config.subscribers.len() == len {
+      return Err("Not subscribed".to_string());
+    }
+    Ok(())
+  })
+}
+
+/// `None` removes the corresponding limit.
+#[update(hidden = true)]
+pub fn set_rpc_limits(timeout_seconds: Option<u64>, max_cycles_per_outcall: Option<u64>) -> Result<(), String> {
+  update_config(
+    Role::Admin,
+    format!("set_rpc_limits {:?} {:?}", timeout_seconds, max_cycles_per_outcall),
+    |config| {
+      config.rpc_timeout_seconds = timeout_seconds;
+      config.max_cycles_per_outcall = max_cycles_per_outcall;
+      Ok(())
+    },
+  )
+}
+
+/// Lowering the window drops the change records that fall outside it once the
+/// next block is indexed. Raising it takes as many blocks to fill: until then,
+/// deeper reorgs are recovered from a checkpoint or by indexing again.
+#[update(hidden = true)]
+pub fn set_reorg_window(window: u32) -> Result<(), String> {
+  update_config(Role::Admin, format!("set_reorg_window {}", window), |config| {
+    if window == 0 {
+      return Err("The reorg window must be at least one block".to_string());
+    }
+    config.reorg_window = Some(window);
+    Ok(())
+  })
+}
+
+/// Building the trees takes a few messages, until then the certified queries
+/// return an error.
+#[update(hidden = true)]
+pub fn set_certified_queries(enabled: bool) -> Result<(), String> {
+  update_config(Role::Admin, format!("set_certified_queries {}", enabled), |config| {
+    config.certified_queries = Some(enabled);
+    Ok(())
+  })?;
+  match (enabled, certification::enabled()) {
+    (true, false) => certification::enable().map_err(|e| e.to_string())?,
+    (false, true) => certification::disable().map_err(|e| e.to_string())?,
+    _ => {}
+  }
+
+  Ok(())
+}
+
+/// Writes a checkpoint every `interval` blocks, or never when it is zero.
+#[update(hidden = true)]
+pub fn set_checkpoint_interval(interval: u32) -> Result<(), String> {
+  update_config(Role::Admin, format!("set_checkpoint_interval {}", interval), |config| {
+    config.checkpoint_interval = Some(interval);
+    Ok(())
+  })
+}
+
+#[update(hidden = true)]
+pub fn set_log_level(level: LogLevel) -> Result<(), String> {
+  update_config(Role::Admin, format!("set_log_level {:?}", level), |config| {
+    config.log_level = Some(level);
+    Ok(())
+  })?;
+  logs::set_log_level(level.into());
+
+  Ok(())
+}
+
+#[query(hidden = true)]
+pub fn get_config_changes(from: u64, limit: u32) -> Result<Vec<ConfigChangeApi>, String> {
+  authorize(Role::Reader)?;
+
+  Ok(
+    mem_config_changes(from, limit.min(MAX_CONFIG_CHANGES_PER_PAGE) as usize)
+      .into_iter()
+      .map(|(index, change)| ConfigChangeApi {
+        index,
+        timestamp: change.timestamp,
+        caller: change.caller,
+        change: change.change,
+      })
+      .collect(),
+  )
+}
+
+#[update(hidden = true)]
+pub fn grant_role(principal: Principal, role: RoleApi) -> Result<(), String> {
+  authorize(Role::Admin)?;
+  let role = Role::from(role);
+  mem_insert_role(principal, role);
+  record_config_change(format!("grant_role {} {:?}", principal, role));
+
+  Ok(())
+}
+
+#[update(hidden = true)]
+pub fn revoke_role(principal: Principal) -> Result<(), String> {
+  authorize(Role::Admin)?;
+  let role = mem_remove_role(principal).ok_or("No role granted")?;
+  record_config_change(format!("revoke_role {} {:?}", principal, role));
+
+  Ok(())
+}
+
+#[query(hidden = true)]
+pub fn get_roles() -> Result<Vec<(Principal, RoleApi)>, String> {
+  authorize(Role::Admin)?;
+
+  Ok(
+    mem_roles()
+      .into_iter()
+      .map(|(principal, role)| (principal, role.into()))
+      .collect(),
+  )
+}
+
+impl From<RoleApi> for Role {
+  fn from(role: RoleApi) -> Self {
+    match role {
+      RoleApi::Reader => Role::Reader,
+      RoleApi::Operator => Role::Operator,
+      RoleApi::Admin => Role::Admin,
+    }
+  }
+}
+
+impl From<Role> for RoleApi {
+  fn from(role: Role) -> Self {
+    match role {
+      Role::Reader => RoleApi::Reader,
+      Role::Operator => RoleApi::Operator,
+      Role::Admin => RoleApi::Admin,
+    }
+  }
+}
+
+/// Features enabled on an indexed chain are backfilled from their first height
+/// and switched on once they catch up, see `get_backfill_progress`.
+#[update(hidden = true)]
+pub fn enable_index_features(features: Vec<IndexFeatureApi>) -> Result<(), String> {
+  authorize(Role::Admin)?;
+  let features: Vec<IndexFeature> = features.into_iter().map(IndexFeature::from).collect();
+  backfill::enable_features(&features).map_err(|e| e.to_string())?;
+  record_config_change(format!("enable_index_features {:?}", features));
+
+  Ok(())
+}
+
+#[update(hidden = true)]
+pub fn disable_index_features(features: Vec<IndexFeatureApi>) -> Result<(), String> {
+  authorize(Role::Admin)?;
+  let features: Vec<IndexFeature> = features.into_iter().map(IndexFeature::from).collect();
+  backfill::disable_features(&features).map_err(|e| e.to_string())?;
+  record_config_change(format!("disable_index_features {:?}", features));
+
+  Ok(())
+}
+
+fn ensure_stopped() -> Result<(), String> {
+  match status::state() {
+    status::State::Stopped => {}
+    // a loop that trapped or hangs would otherwise never count as stopped
+    status::State::Stalled if !mem_get_indexer_running() => status::abandon(),
+    _ => return Err("Stop indexing and wait for the current block to finish first".to_string()),
+  }
+  if snapshot::in_progress() {
+    return Err("Wait for the snapshot export or import to finish first".to_string());
+  }
+  Ok(())
+}
+
+/// Rewinds exactly to `height` within the blocks whose change records are still
+/// kept, and otherwise to the newest checkpoint at or below it, which is loaded
+/// once indexing is started again. Returns the height rewound to.
+///
+/// Inscriptions, sats and addresses have no change records, so with any of
+/// them on, as with the default config, only a checkpoint can rewind. Nor do
+/// change records cover a block a trapped loop left partly indexed.
+#[update(hidden = true)]
+pub fn rewind_to(height: u32) -> Result<u32, String> {
+  authorize(Role::Admin)?;
+  ensure_stopped()?;
+  snapshot::discard_export().map_err(|e| e.to_string())?;
+  let rewound = match status::unfinished_block() {
+    Some(unfinished) => {
+      let rewound = checkpoint::restore(height)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("block {} is partly indexed and there is no checkpoint at or below height {} to roll it back", unfinished, height))?;
+      status::end_block().map_err(|e| e.to_string())?;
+      rewound
+    }
+    None => Reorg::rewind(height, &Index::from_config(&mem_get_config())).map_err(|e| e.to_string())?,
+  };
+  record_config_change(format!("rewind_to {} (rewound to {})", height, rewound));
+  certification::certify();
+
+  Ok(rewound)
+}
+
+/// Clears the feature's maps, together with those of features sharing them, and
+/// backfills it from its first height once indexing is started again.
+#[update(hidden = true)]
+pub fn reset_index(feature: IndexFeatureApi) -> Result<(), String> {
+  authorize(Role::Admin)?;
+  ensure_stopped()?;
+  let feature = IndexFeature::from(feature);
+  snapshot::discard_export().map_err(|e| e.to_string())?;
+  backfill::reset_feature(feature).map_err(|e| e.to_string())?;
+  record_config_change(format!("reset_index {:?}", feature));
+  certification::certify();
+
+  Ok(())
+}
+
+fn hash_from_hex(hex: &str) -> Result<[u8; 32], String> {
+  hex::decode(hex)
+    .ok()
+    .and_then(|bytes| bytes.try_into().ok())
+    .ok_or_else(|| format!("Invalid hash: {}", hex))
+}
+
+/// Starts hashing every map at the indexed tip for a snapshot, whose manifest
+/// is returned by `get_snapshot_manifest` once done. Starting the indexer
+/// again discards it.
+#[update(hidden = true)]
+pub fn begin_snapshot_export() -> Result<(), String> {
+  authorize(Role::Admin)?;
+  ensure_stopped()?;
+  snapshot::begin_export().map_err(|e| e.to_string())?;
+  record_config_change("begin_snapshot_export".to_string());
 
   Ok(())
 }
 
 #[query(hidden = true)]
-pub fn get_subscribers() -> Vec<Principal> {
-  mem_get_config().subscribers
+pub fn get_snapshot_manifest() -> Result<SnapshotManifest, String> {
+  authorize(Role::Admin)?;
+  let snapshot = snapshot::exported().map_err(|e| e.to_string())?;
+  let config = mem_get_config();
+
+  Ok(SnapshotManifest {
+    schema_version: migration::SCHEMA_VERSION,
+    network: snapshot::network_name(&config),
+    features: backfill::enabled_features(&config)
+      .into_iter()
+      .map(IndexFeatureApi::from)
+      .collect(),
+    height: snapshot.height,
+    block_hash: BlockHash::load(snapshot.block_hash).to_string(),
+    state_commitment: snapshot.commitment.map(hex::encode),
+    tables: snapshot::TABLES
+      .iter()
+      .zip(snapshot.entries)
+      .map(|(table, entries)| SnapshotTable {
+        name: format!("{:?}", table),
+        entries,
+      })
+      .collect(),
+    chunks: snapshot.chunks,
+    hash: hex::encode(snapshot.hash),
+  })
 }
 
+/// The chunk of the exported snapshot at `cursor`, or the first one without a
+/// cursor. Returns `None` past the last chunk.
+#[query(hidden = true)]
+pub fn export_snapshot_chunk(cursor: Option<SnapshotCursor>) -> Result<Option<SnapshotChunk>, String> {
+  authorize(Role::Admin)?;
+  snapshot::exported().map_err(|e| e.to_string())?;
+  let (table, after) = cursor.map_or((0, None), |cursor| {
+    (cursor.table, cursor.after.map(ByteBuf::into_vec))
+  });
+
+  Ok(snapshot::read_chunk(table, after).map(|chunk| SnapshotChunk {
+    table: chunk.table,
+    hash: hex::encode(snapshot::chunk_hash(chunk.table, &chunk.entries)),
+    entries: chunk
+      .entries
+      .into_iter()
+      .map(|(key, value)| (ByteBuf::from(key), ByteBuf::from(value)))
+      .collect(),
+    next: chunk.next.map(|(table, after)| SnapshotCursor {
+      table,
+      after: after.map(ByteBuf::from),
+    }),
+  }))
+}
+
+/// Prepares a freshly installed indexer, on the same network and with the same
+/// features as the exporting one, to receive the chunks of `manifest`.
+#[update(hidden = true)]
+pub fn begin_snapshot_import(manifest: SnapshotManifest) -> Result<(), String> {
+  authorize(Role::Admin)?;
+  ensure_stopped()?;
+  let names: Vec<String> = snapshot::TABLES.iter().map(|table| format!("{:?}", table)).collect();
+  if manifest.tables.iter().map(|table| &table.name).ne(names.iter()) {
+    return Err("The snapshot tables don't match those of this indexer".to_string());
+  }
+  let block_hash = BlockHash::from_str(&manifest.block_hash).map_err(|e| e.to_string())?;
+  let snapshot = Snapshot {
+    height: manifest.height,
+    block_hash: block_hash.store(),
+    commitment: manifest
+      .state_commitment
+      .as_deref()
+      .map(hash_from_hex)
+      .transpose()?,
+    entries: manifest.tables.iter().map(|table| table.entries).collect(),
+    chunks: manifest.chunks,
+    hash: hash_from_hex(&manifest.hash)?,
+    ..Default::default()
+  };
+  let features: Vec<IndexFeature> = manifest.features.into_iter().map(IndexFeature::from).collect();
+  snapshot::begin_import(
+    snapshot,
+    manifest.schema_version,
+    &manifest.network,
+    &features,
+    &mem_get_config(),
+  )
+  .map_err(|e| e.to_string())?;
+  record_config_change(format!(
+    "begin_snapshot_import {} {}",
+    manifest.height, manifest.hash
+  ));
+
+  Ok(())
+}
+
+/// Imports the chunks in the order `export_snapshot_chunk` returned them.
+#[update(hidden = true)]
+pub fn import_snapshot_chunk(chunk: SnapshotChunk) -> Result<(), String> {
+  authorize(Role::Admin)?;
+  let hash = hash_from_hex(&chunk.hash)?;
+  let entries = chunk
+    .entries
+    .into_iter()
+    .map(|(key, value)| (key.into_vec(), value.into_vec()))
+    .collect();
+  snapshot::import_chunk(chunk.table, entries, hash).map_err(|e| e.to_string())
+}
+
+/// Checks the imported maps against the manifest. Indexing continues from the
+/// returned height once started.
+#[update(hidden = true)]
+pub fn finish_snapshot_import() -> Result<u32, String> {
+  authorize(Role::Admin)?;
+  let height = snapshot::finish_import().map_err(|e| e.to_string())?;
+  record_config_change(format!("finish_snapshot_import {}", height));
+
+  Ok(height)
+}
+
+#[query]
+pub fn get_backfill_progress() -> Vec<BackfillProgress> {
+  index::mem_backfills()
+    .into_iter()
+    .map(|(feature, backfill)| BackfillProgress {
+      feature: feature.into(),
+      start_height: backfill.start_height,
+      next_height: backfill.next_height,
+      target_height: mem_latest_block_height(),
+    })
+    .collect()
+}
+
+impl From<IndexFeatureApi> for IndexFeature {
+  fn from(feature: IndexFeatureApi) -> Self {
+    match feature {
+      IndexFeatureApi::Addresses => IndexFeature::Addresses,
+      IndexFeatureApi::Inscriptions => IndexFeature::Inscriptions,
+      IndexFeatureApi::Runes => IndexFeature::Runes,
+      IndexFeatureApi::Sats => IndexFeature::Sats,
+      IndexFeatureApi::Transactions => IndexFeature::Transactions,
+    }
+  }
+}
+
+impl From<IndexFeature> for IndexFeatureApi {
+  fn from(feature: IndexFeature) -> Self {
+    match feature {
+      IndexFeature::Addresses => IndexFeatureApi::Addresses,
+      IndexFeature::Inscriptions => IndexFeatureApi::Inscriptions,
+      IndexFeature::Runes => IndexFeatureApi::Runes,
+      IndexFeature::Sats => IndexFeatureApi::Sats,
+      IndexFeature::Transactions => IndexFeatureApi::Transactions,
+    }
+  }
+}
+
+#[query(hidden = true)]
+pub fn get_subscribers() -> Result<Vec<Principal>, String> {
+  authorize(Role::Reader)?;
+
+  Ok(mem_get_config().subscribers)
+}
+
+/// The canister logs, which name RPC hosts and principals, for readers only.
+/// Gateway requests come from the anonymous principal and are refused.
 #[ic_cdk::query(hidden = true)]
 fn http_request(req: HttpRequest) -> HttpResponse {
     use logs::http;
+    if let Err(e) = authorize(Role::Reader) {
+      let mut response = HttpResponseBuilder::bad_request().with_body_and_content_length(e).build();
+      response.status_code = 403;
+      return response;
+    }
     http::to_http_response(&req)
 }
 
@@ -0,0 +1,6 @@
+//! Benchmarks for `canbench`, see the README. Every benchmark runs in a query,
+//! so it starts from a freshly installed canister and sets up its own state.
+
+mod blocks;
+mod entries;
+mod queries;
@@ -0,0 +1,90 @@
+//! Each benchmark indexes one block, so the reported instructions and stable
+//! memory increase are per block. The scopes split them into
+//! `index_utxo_entries` with its `commit`, `index_rune`, `block_summary` and
+//! `commitment`.
+
+use bitcoin::{consensus, constants::genesis_block, Amount, Block, Network, OutPoint, ScriptBuf, TxOut};
+use canbench_rs::{bench, bench_fn, BenchResult};
+
+use crate::{index::updater::BlockData, test::blocks::Chain};
+
+/// Mainnet block 170, whose second transaction is the first payment between
+/// two people: 10 BTC from Satoshi to Hal Finney.
+const MAINNET_BLOCK_170: &[u8] = include_bytes!("mainnet_block_170.raw");
+
+/// The coinbase of mainnet block 9, whose output block 170 spends.
+const MAINNET_BLOCK_9_COINBASE: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d0134ffffffff0100f2052a0100000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000";
+
+/// Mainnet block 840000, the first with runes, holding etchings, mints,
+/// transfers and inscriptions. Recorded with `scripts/record_block.sh 840000`.
+const MAINNET_BLOCK_840000: &[u8] = include_bytes!("mainnet_block_840000.raw");
+
+/// The outputs of earlier blocks that block 840000 spends.
+const MAINNET_BLOCK_840000_PREVOUTS: &str = include_str!("mainnet_block_840000_prevouts.txt");
+
+/// Parses recorded `txid:vout value script_pubkey` lines.
+fn prevouts(recorded: &str) -> impl Iterator<Item = (OutPoint, TxOut)> + '_ {
+  recorded.lines().map(|line| {
+    let mut fields = line.split_whitespace();
+    let outpoint = fields.next().unwrap().parse().unwrap();
+    let value = Amount::from_sat(fields.next().unwrap().parse().unwrap());
+    let script_pubkey = ScriptBuf::from_hex(fields.next().unwrap_or_default()).unwrap();
+    (outpoint, TxOut { value, script_pubkey })
+  })
+}
+
+/// The recorded regtest genesis block, whose coinbase is the only transaction.
+#[bench(raw)]
+fn index_genesis_block() -> BenchResult {
+  let mut chain = Chain::new();
+  let block = BlockData::from(genesis_block(Network::Regtest));
+  bench_fn(|| chain.index_block(block))
+}
+
+/// Mainnet block 170 on top of the coinbase it spends, indexed as regtest.
+#[bench(raw)]
+fn index_mainnet_block_170() -> BenchResult {
+  let mut chain = Chain::new();
+  let coinbase = consensus::encode::deserialize_hex(MAINNET_BLOCK_9_COINBASE).unwrap();
+  let block = chain.coinbase_block(coinbase);
+  chain.index_block(block);
+  let block = BlockData::from(consensus::deserialize::<Block>(MAINNET_BLOCK_170).unwrap());
+  bench_fn(|| chain.index_block(block))
+}
+
+/// Mainnet block 840000 at its height, with the outputs it spends unspent,
+/// indexed as regtest.
+#[bench(raw)]
+fn index_mainnet_block_840000() -> BenchResult {
+  let mut chain = Chain::new();
+  chain.skip_to(840_000, prevouts(MAINNET_BLOCK_840000_PREVOUTS));
+  let block = BlockData::from(consensus::deserialize::<Block>(MAINNET_BLOCK_840000).unwrap());
+  bench_fn(|| chain.index_block(block))
+}
+
+#[bench(raw)]
+fn index_funding_block() -> BenchResult {
+  let mut chain = Chain::new();
+  let block = chain.funding_block();
+  bench_fn(|| chain.index_block(block))
+}
+
+#[bench(raw)]
+fn index_etching_block() -> BenchResult {
+  let mut chain = Chain::new();
+  let block = chain.funding_block();
+  chain.index_block(block);
+  let block = chain.etching_block();
+  bench_fn(|| chain.index_block(block))
+}
+
+#[bench(raw)]
+fn index_transfer_block() -> BenchResult {
+  let mut chain = Chain::new();
+  let block = chain.funding_block();
+  chain.index_block(block);
+  let block = chain.etching_block();
+  chain.index_block(block);
+  let block = chain.transfer_block();
+  bench_fn(|| chain.index_block(block))
+}
@@ -0,0 +1,137 @@
+//! Each benchmark fills a map of its own memory, so the reported stable memory
+//! increase is the footprint of the encoding used.
+
+use bitcoin::{hashes::Hash, OutPoint, Txid};
+use canbench_rs::{bench, bench_fn, BenchResult};
+use ic_stable_structures::{memory_manager::MemoryId, StableBTreeMap, Storable};
+use ordinals::{Rune, RuneId, Sat, SpacedRune, Terms};
+
+use crate::{
+  index::entry::{Bincode, Entry, InscriptionEntry, RuneBalance, RuneBalances, RuneEntry},
+  memory::{get_virtual_memory, VMemory},
+};
+
+const ENTRIES: u32 = 10_000;
+
+fn map<K: Storable + Ord + Clone, V: Storable>(id: u8) -> StableBTreeMap<K, V, VMemory> {
+  StableBTreeMap::init(get_virtual_memory(MemoryId::new(id)))
+}
+
+fn rune_entry(n: u32) -> RuneEntry {
+  RuneEntry {
+    block: 840_000 + u64::from(n),
+    burned: 0,
+    divisibility: 2,
+    etching: Txid::from_byte_array([n as u8; 32]),
+    mints: u128::from(n),
+    number: u64::from(n),
+    premine: 1_000_000,
+    spaced_rune: SpacedRune {
+      rune: Rune(u128::from(n) << 40),
+      spacers: 0,
+    },
+    symbol: Some('ᚠ'),
+    terms: Some(Terms {
+      cap: Some(10_000),
+      height: (None, Some(900_000)),
+      amount: Some(1_000),
+      offset: (None, None),
+    }),
+    timestamp: 1_713_571_767,
+    turbo: false,
+  }
+}
+
+fn inscription_entry(n: u32) -> InscriptionEntry {
+  InscriptionEntry {
+    charms: 0,
+    fee: 10_000,
+    height: 840_000,
+    id: format!("{}i0", Txid::from_byte_array([n as u8; 32])).parse().unwrap(),
+    inscription_number: n as i32,
+    parents: if n % 10 == 0 { vec![n / 2] } else { Vec::new() },
+    sat: Some(Sat(u64::from(n) * 1_000)),
+    sequence_number: n,
+    timestamp: 1_713_571_767,
+  }
+}
+
+fn rune_balances(n: u32) -> RuneBalances {
+  RuneBalances {
+    balances: (0..1 + n % 3)
+      .map(|i| RuneBalance {
+        rune_id: RuneId {
+          block: 840_000 + u64::from(n),
+          tx: i,
+        },
+        balance: u128::from(n) * 100_000_000,
+      })
+      .collect(),
+  }
+}
+
+fn outpoint(n: u32) -> [u8; 36] {
+  OutPoint {
+    txid: Txid::from_byte_array([n as u8; 32]),
+    vout: n,
+  }
+  .store()
+}
+
+fn rune_id(n: u32) -> (u64, u32) {
+  RuneId {
+    block: 840_000 + u64::from(n),
+    tx: n,
+  }
+  .store()
+}
+
+/// Inserts `ENTRIES` values, then reads them all back.
+fn insert_and_get<K: Storable + Ord + Clone, V: Storable>(
+  mut map: StableBTreeMap<K, V, VMemory>,
+  key: fn(u32) -> K,
+  value: impl Fn(u32) -> V,
+) -> BenchResult {
+  bench_fn(|| {
+    {
+      let _p = canbench_rs::bench_scope("insert");
+      for n in 0..ENTRIES {
+        map.insert(key(n), value(n));
+      }
+    }
+    let _p = canbench_rs::bench_scope("get");
+    for n in 0..ENTRIES {
+      map.get(&key(n)).unwrap();
+    }
+  })
+}
+
+#[bench(raw)]
+fn rune_entry_bincode() -> BenchResult {
+  insert_and_get(map(200), rune_id, |n| Bincode(rune_entry(n)))
+}
+
+#[bench(raw)]
+fn rune_entry_compact() -> BenchResult {
+  insert_and_get(map(201), rune_id, rune_entry)
+}
+
+#[bench(raw)]
+fn inscription_entry_bincode() -> BenchResult {
+  insert_and_get(map(202), |n| n, |n| Bincode(inscription_entry(n)))
+}
+
+#[bench(raw)]
+fn inscription_entry_compact() -> BenchResult {
+  insert_and_get(map(203), |n| n, inscription_entry)
+}
+
+#[bench(raw)]
+fn rune_balances_bincode() -> BenchResult {
+  insert_and_get(map(204), outpoint, |n| Bincode(rune_balances(n)))
+}
+
+#[bench(raw)]
+fn rune_balances_compact() -> BenchResult {
+  insert_and_get(map(205), outpoint, rune_balances)
+}
@@ -0,0 +1,47 @@
+//! Queries against the state left by the synthetic etching block.
+
+use canbench_rs::{bench, bench_fn, BenchResult};
+
+use crate::{
+  api::get_rune_balances_for_outputs,
+  index::inscription_info,
+  inscriptions::{InscriptionId, InscriptionQuery},
+  test::blocks::{Chain, RUNE_OUTPUTS},
+};
+
+fn etched_chain() -> Chain {
+  let mut chain = Chain::new();
+  let block = chain.funding_block();
+  chain.index_block(block);
+  let block = chain.etching_block();
+  chain.index_block(block);
+  chain
+}
+
+/// Every rune output of the etching, 64 per query.
+#[bench(raw)]
+fn get_rune_balances_for_outputs_of_etching() -> BenchResult {
+  let chain = etched_chain();
+  let outpoints = (1..=RUNE_OUTPUTS)
+    .map(|vout| format!("{}:{vout}", chain.etching()))
+    .collect::<Vec<String>>();
+  bench_fn(|| {
+    for chunk in outpoints.chunks(64) {
+      get_rune_balances_for_outputs(chunk.to_vec()).unwrap();
+    }
+  })
+}
+
+/// Every inscription of the etching block, by id.
+#[bench(raw)]
+fn inscription_info_by_id() -> BenchResult {
+  let chain = etched_chain();
+  bench_fn(|| {
+    for txid in chain.inscriptions() {
+      let id = InscriptionId { txid: *txid, index: 0 };
+      inscription_info(chain.index(), InscriptionQuery::Id(id), None)
+        .unwrap()
+        .unwrap();
+    }
+  })
+}
@@ -0,0 +1,236 @@
+use std::{cell::RefCell, rc::Rc};
+
+use anyhow::anyhow;
+use bitcoin::{block::Header, hashes::Hash, BlockHash, CompactTarget, Txid};
+use futures::future::LocalBoxFuture;
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+
+use crate::{index::updater::BlockData, Result};
+
+/// A transaction as serialized on the wire, with the block it was mined in if
+/// it is confirmed.
+pub(crate) struct RawTransaction {
+  pub(crate) bytes: Vec<u8>,
+  pub(crate) block_hash: Option<BlockHash>,
+}
+
+/// What the indexer needs of a block header it looked up by hash.
+pub(crate) struct HeaderInfo {
+  pub(crate) header: Header,
+  pub(crate) height: u32,
+}
+
+/// Where the indexer gets the chain from. The canister uses [`Outcalls`], tests
+/// can install a fake with [`set`] to drive the indexer natively.
+pub(crate) trait BlockSource {
+  /// Height of the tip.
+  fn block_count(&self) -> LocalBoxFuture<'_, Result<u32>>;
+
+  /// Hash of the block at `height` on the best chain, `None` if it isn't known.
+  fn block_hash(&self, network: BitcoinNetwork, height: u32) -> LocalBoxFuture<'_, Result<Option<BlockHash>>>;
+
+  fn block(&self, hash: BlockHash) -> LocalBoxFuture<'_, Result<BlockData>>;
+
+  fn raw_transaction(&self, txid: Txid) -> LocalBoxFuture<'_, Result<RawTransaction>>;
+
+  fn header(&self, hash: BlockHash) -> LocalBoxFuture<'_, Result<HeaderInfo>>;
+}
+
+/// The Bitcoin canister and the RPC endpoints of the config, over HTTPS
+/// outcalls.
+pub(crate) struct Outcalls;
+
+impl BlockSource for Outcalls {
+  fn block_count(&self) -> LocalBoxFuture<'_, Result<u32>> {
+    Box::pin(crate::rpc::get_block_count())
+  }
+
+  fn block_hash(&self, network: BitcoinNetwork, height: u32) -> LocalBoxFuture<'_, Result<Option<BlockHash>>> {
+    Box::pin(crate::bitcoin_api::get_block_hash(network, height))
+  }
+
+  fn block(&self, hash: BlockHash) -> LocalBoxFuture<'_, Result<BlockData>> {
+    Box::pin(crate::rpc::get_block(hash))
+  }
+
+  fn raw_transaction(&self, txid: Txid) -> LocalBoxFuture<'_, Result<RawTransaction>> {
+    Box::pin(async move {
+      let info = crate::rpc::get_raw_transaction_info(&txid, None).await?;
+      Ok(RawTransaction {
+        bytes: info.hex,
+        block_hash: info.blockhash,
+      })
+    })
+  }
+
+  fn header(&self, hash: BlockHash) -> LocalBoxFuture<'_, Result<HeaderInfo>> {
+    Box::pin(async move {
+      let info = crate::rpc::get_block_header_info(&hash).await?;
+      let header = Header {
+        version: info.version,
+        prev_blockhash: info.previous_block_hash.unwrap_or_else(BlockHash::all_zeros),
+        merkle_root: info.merkle_root,
+        time: info.time.try_into()?,
+        bits: CompactTarget::from_unprefixed_hex(&info.bits)?,
+        nonce: info.nonce,
+      };
+      if header.block_hash() != hash {
+        return Err(anyhow!("wrong block header: {}", hash));
+      }
+      Ok(HeaderInfo {
+        header,
+        height: info.height.try_into()?,
+      })
+    })
+  }
+}
+
+thread_local! {
+  static SOURCE: RefCell<Rc<dyn BlockSource>> = RefCell::new(Rc::new(Outcalls));
+}
+
+pub(crate) fn get() -> Rc<dyn BlockSource> {
+  SOURCE.with(|s| s.borrow().clone())
+}
+
+#[cfg(test)]
+pub(crate) fn set(source: Rc<dyn BlockSource>) {
+  SOURCE.with(|s| *s.borrow_mut() = source);
+}
+
+/// A chain kept in memory, which can be extended and reorganized.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct Fake {
+  blocks: RefCell<Vec<bitcoin::Block>>,
+  mined: std::cell::Cell<u32>,
+}
+
+#[cfg(test)]
+impl Fake {
+  /// Appends a block on top of the tip.
+  pub(crate) fn mine(&self, transactions: Vec<bitcoin::Transaction>) -> BlockHash {
+    use bitcoin::{block::Version, Block, TxMerkleNode};
+
+    let mut blocks = self.blocks.borrow_mut();
+    let height = u32::try_from(blocks.len()).unwrap();
+    let mut block = Block {
+      header: Header {
+        version: Version::TWO,
+        prev_blockhash: blocks
+          .last()
+          .map_or(BlockHash::all_zeros(), |block| block.block_hash()),
+        merkle_root: TxMerkleNode::all_zeros(),
+        time: height,
+        bits: CompactTarget::from_consensus(0x207f_ffff),
+        // tells apart blocks of competing branches
+        nonce: self.mined.get(),
+      },
+      txdata: transactions,
+    };
+    if let Some(root) = block.compute_merkle_root() {
+      block.header.merkle_root = root;
+    }
+    let hash = block.block_hash();
+    blocks.push(block);
+    self.mined.set(self.mined.get() + 1);
+    hash
+  }
+
+  /// Drops the blocks above `height`, so the next ones mined replace them.
+  pub(crate) fn invalidate_above(&self, height: u32) {
+    self.blocks.borrow_mut().truncate(height as usize + 1);
+  }
+}
+
+#[cfg(test)]
+impl BlockSource for Fake {
+  fn block_count(&self) -> LocalBoxFuture<'_, Result<u32>> {
+    let count = self.blocks.borrow().len().checked_sub(1);
+    Box::pin(async move { count.map(|count| count.try_into().unwrap()).ok_or_else(|| anyhow!("no blocks")) })
+  }
+
+  fn block_hash(&self, _: BitcoinNetwork, height: u32) -> LocalBoxFuture<'_, Result<Option<BlockHash>>> {
+    let hash = self.blocks.borrow().get(height as usize).map(|block| block.block_hash());
+    Box::pin(async move { Ok(hash) })
+  }
+
+  fn block(&self, hash: BlockHash) -> LocalBoxFuture<'_, Result<BlockData>> {
+    let block = self
+      .blocks
+      .borrow()
+      .iter()
+      .find(|block| block.block_hash() == hash)
+      .cloned();
+    Box::pin(async move {
+      block
+        .map(BlockData::from)
+        .ok_or_else(|| anyhow!("unknown block: {}", hash))
+    })
+  }
+
+  fn raw_transaction(&self, txid: Txid) -> LocalBoxFuture<'_, Result<RawTransaction>> {
+    let transaction = self.blocks.borrow().iter().find_map(|block| {
+      block
+        .txdata
+        .iter()
+        .find(|tx| tx.compute_txid() == txid)
+        .map(|tx| RawTransaction {
+          bytes: bitcoin::consensus::serialize(tx),
+          block_hash: Some(block.block_hash()),
+        })
+    });
+    Box::pin(async move { transaction.ok_or_else(|| anyhow!("unknown transaction: {}", txid)) })
+  }
+
+  fn header(&self, hash: BlockHash) -> LocalBoxFuture<'_, Result<HeaderInfo>> {
+    let header = self
+      .blocks
+      .borrow()
+      .iter()
+      .enumerate()
+      .find(|(_, block)| block.block_hash() == hash)
+      .map(|(height, block)| HeaderInfo {
+        header: block.header,
+        height: height.try_into().unwrap(),
+      });
+    Box::pin(async move { header.ok_or_else(|| anyhow!("unknown block: {}", hash)) })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::executor::block_on;
+
+  use super::*;
+  use crate::test::{address, outpoint, tx_in, tx_out};
+
+  #[test]
+  fn fake_follows_reorgs() {
+    let fake = Fake::default();
+    let transaction = bitcoin::Transaction {
+      version: bitcoin::transaction::Version::TWO,
+      lock_time: bitcoin::absolute::LockTime::ZERO,
+      input: vec![tx_in(outpoint(1))],
+      output: vec![tx_out(1000, address(0))],
+    };
+    fake.mine(Vec::new());
+    let stale = fake.mine(vec![transaction.clone()]);
+
+    fake.invalidate_above(0);
+    assert_eq!(block_on(fake.block_hash(BitcoinNetwork::Regtest, 1)).unwrap(), None);
+    fake.mine(Vec::new());
+    let hash = fake.mine(vec![transaction.clone()]);
+    assert_ne!(hash, stale);
+
+    assert_eq!(block_on(fake.block_count()).unwrap(), 2);
+    assert_eq!(block_on(fake.block_hash(BitcoinNetwork::Regtest, 2)).unwrap(), Some(hash));
+    assert_eq!(block_on(fake.header(hash)).unwrap().height, 2);
+    assert_eq!(block_on(fake.block(hash)).unwrap().txdata[0].0, transaction);
+    assert!(block_on(fake.block(stale)).is_err());
+
+    let raw = block_on(fake.raw_transaction(transaction.compute_txid())).unwrap();
+    assert_eq!(raw.bytes, bitcoin::consensus::serialize(&transaction));
+    assert_eq!(raw.block_hash, Some(hash));
+  }
+}
@@ -1,23 +1,30 @@
-use std::{cell::RefCell,sync::atomic::{self, AtomicBool}};
-use bitcoin::{block::Header, consensus, BlockHash, OutPoint, Transaction, TxOut, Txid};
-use entry::{ChangeRecordRune, Entry, HeaderValue, InscriptionEntry, InscriptionNumber, MyOutPoints, OutPointValue, RuneBalances, RuneEntry, RuneIdValue, SatPointValue, SequenceNumbers, TxidValue};
+use std::{borrow::Cow, cell::{Cell, RefCell}, ops::{Bound, Range}, sync::atomic::{self, AtomicBool}, thread::LocalKey};
+use bitcoin::{block::Header, consensus, BlockHash, OutPoint, ScriptBuf, Transaction, Txid};
+use candid::Principal;
+use entry::{Backfill, Bincode, BlockHashValue, BlockSummary, CachedTransaction, CertifiedNode, CertifiedNodeId, CertifiedRebuild, ChangeRecordRune, Checkpoint, CheckpointChunk, CheckpointUndo, CheckpointUndoKey, ConfigChange, Entry, HeaderValue, IndexFeature, InscriptionAttributes, InscriptionEntry, InscriptionNumber, MyOutPoints, OutPointValue, PrincipalValue, Role, RuneBalances, RuneEntry, RuneIdValue, SatPointValue, SatRange, SchemaVersion, ScriptPubkeyOutPoint, SequenceNumbers, Snapshot, TxidValue, UnfinishedBlock};
 use event::Events;
-use ic_stable_structures::{StableCell, StableBTreeMap};
-use ordinals::{Charm, RuneId, SatPoint};
+use ic_stable_structures::{memory_manager::MemoryId, StableCell, StableBTreeMap, Storable};
+use ordinals::{Charm, RuneId, Sat, SatPoint};
 use utxo_entry::UtxoEntry;
 use anyhow::anyhow;
 
 use crate::{
-  chain::Chain, config::Config, inscriptions::{envelope::ParsedEnvelope, Inscription, InscriptionId, InscriptionQuery, InscriptionResp}, memory::{
-    get_virtual_memory, VMemory, CONFIG_MEMORY_ID, HEIGHT_TO_BLOCK_HEADER_MEMORY_ID, HEIGHT_TO_CHANGE_RECORD_RUNE_MEMORY_ID, HEIGHT_TO_LAST_SEQUENCE_NUMBER_MEMORY_ID, HEIGHT_TO_STATISTIC_RESERVED_RUNES_MEMORY_ID, HEIGHT_TO_STATISTIC_RUNES_MEMORY_ID, HOME_INSCRIPTIONS_MEMORY_ID, INSCRIPTION_ID_TO_SEQUENCE_NUMBER_MEMORY_ID, INSCRIPTION_NUMBER_TO_SEQUENCE_NUMBER_MEMORY_ID, OUTPOINT_TO_HEIGHT_MEMORY_ID, OUTPOINT_TO_RUNE_BALANCES_MEMORY_ID, OUTPOINT_TO_UTXO_ENTRY_MEMORY_ID, RUNE_ID_TO_RUNE_ENTRY_MEMORY_ID, RUNE_TO_RUNE_ID_MEMORY_ID, SAT_TO_SATPOINT_MEMORY_ID, SAT_TO_SEQUENCE_NUMBERS_MEMORY_ID, SCRIPT_PUBKEY_TO_OUTPOINTS_MEMORY_ID, SEQUENCE_NUMBER_TO_CHILDRENS_MEMORY_ID, SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY_MEMORY_ID, SEQUENCE_NUMBER_TO_RUNE_ID_MEMORY_ID, SEQUENCE_NUMBER_TO_SATPOINT_MEMORY_ID, STATISTIC_TO_COUNT_MEMORY_ID, TRANSACTION_ID_TO_RUNE_MEMORY_ID, TRANSACTION_ID_TO_TRANSACTION_MEMORY_ID
+  chain::Chain, config::Config, into_usize::IntoUsize, inscriptions::{InscriptionId, InscriptionQuery, InscriptionResp}, memory::{
+    get_virtual_memory, VMemory, BLOCK_HASH_TO_HEIGHT_MEMORY_ID, CERTIFIED_DIRTY_MEMORY_ID, CERTIFIED_INSCRIPTION_SATPOINTS_MEMORY_ID, CERTIFIED_REBUILD_MEMORY_ID, CERTIFIED_RUNE_BALANCES_MEMORY_ID, CHECKPOINT_CHUNKS_MEMORY_ID, CHECKPOINT_UNDO_MEMORY_ID, CONFIG_CHANGES_MEMORY_ID, CONFIG_MEMORY_ID, FEATURE_TO_BACKFILL_MEMORY_ID, HEIGHT_TO_BLOCK_HEADER_MEMORY_ID, HEIGHT_TO_BLOCK_SUMMARY_MEMORY_ID, HEIGHT_TO_CHANGE_RECORD_RUNE_MEMORY_ID, HEIGHT_TO_CHECKPOINT_MEMORY_ID, HEIGHT_TO_COMMITMENT_MEMORY_ID, HEIGHT_TO_LAST_SEQUENCE_NUMBER_MEMORY_ID, HEIGHT_TO_STATISTIC_RESERVED_RUNES_MEMORY_ID, HEIGHT_TO_STATISTIC_RUNES_MEMORY_ID, HOME_INSCRIPTIONS_MEMORY_ID, INSCRIPTION_ID_TO_SEQUENCE_NUMBER_MEMORY_ID, INSCRIPTION_NUMBER_TO_SEQUENCE_NUMBER_MEMORY_ID, INDEXER_RUNNING_MEMORY_ID, LAST_USED_TO_CACHED_TRANSACTION_ID_MEMORY_ID, LEGACY_OUTPOINT_TO_RUNE_BALANCES_MEMORY_ID, LEGACY_RUNE_ID_TO_RUNE_ENTRY_MEMORY_ID, LEGACY_SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY_MEMORY_ID, OUTPOINT_TO_HEIGHT_MEMORY_ID, OUTPOINT_TO_RUNE_BALANCES_MEMORY_ID, OUTPOINT_TO_UTXO_ENTRY_MEMORY_ID, PRINCIPAL_TO_ROLE_MEMORY_ID, RUNE_ID_TO_RUNE_ENTRY_MEMORY_ID, RUNE_TO_RUNE_ID_MEMORY_ID, SAT_TO_SATPOINT_MEMORY_ID, SAT_TO_SEQUENCE_NUMBERS_MEMORY_ID, SAT_TO_SEQUENCE_NUMBER_MEMORY_ID, SCHEMA_VERSION_MEMORY_ID, SCRIPT_PUBKEY_TO_OUTPOINTS_MEMORY_ID, SCRIPT_PUBKEY_TO_OUTPOINT_MEMORY_ID, SEQUENCE_NUMBER_TO_CHILDRENS_MEMORY_ID, SEQUENCE_NUMBER_TO_CHILD_MEMORY_ID, SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES_MEMORY_ID, SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY_MEMORY_ID, SEQUENCE_NUMBER_TO_RUNE_ID_MEMORY_ID, SEQUENCE_NUMBER_TO_SATPOINT_MEMORY_ID, SEQUENCE_NUMBER_TO_SCRIPT_PUBKEY_MEMORY_ID, SNAPSHOT_MEMORY_ID, STATISTIC_TO_COUNT_MEMORY_ID, UNFINISHED_BLOCK_MEMORY_ID, TRANSACTION_ID_TO_CACHED_TRANSACTION_MEMORY_ID, TRANSACTION_ID_TO_RUNE_MEMORY_ID, TRANSACTION_ID_TO_TRANSACTION_MEMORY_ID
   }, timestamp, unbound_outpoint, Result
 };
 
+pub(crate) mod certification;
+pub(crate) mod checkpoint;
+pub(crate) mod commitment;
 pub mod entry;
 mod event;
-mod reorg;
+pub(crate) mod migration;
+pub(crate) mod reorg;
+pub(crate) mod snapshot;
+pub(crate) mod status;
 pub mod updater;
-mod utxo_entry;
+pub(crate) mod utxo_entry;
 mod lot;
 
 #[derive(Copy, Clone)]
@@ -39,6 +46,7 @@ pub(crate) enum Statistic {
   SatRanges = 14,
   UnboundInscriptions = 16,
   // LastSavepointHeight = 17,
+  TransactionCacheBytes = 18,
 }
 
 impl Statistic {
@@ -172,20 +180,55 @@ thread_local! {
   static CONFIG: RefCell<StableCell<Config, VMemory>> = RefCell::new(
     StableCell::init(get_virtual_memory(CONFIG_MEMORY_ID), Config::default()).unwrap()
   );
-  /// multimap memories
-  static SAT_TO_SEQUENCE_NUMBERS: RefCell<StableBTreeMap<u64, SequenceNumbers, VMemory>> = RefCell::new(
-    StableBTreeMap::init(get_virtual_memory(SAT_TO_SEQUENCE_NUMBERS_MEMORY_ID))
+  static INDEXER_RUNNING: RefCell<StableCell<bool, VMemory>> = RefCell::new(
+    StableCell::init(get_virtual_memory(INDEXER_RUNNING_MEMORY_ID), false).unwrap()
   );
-  static SAT_TO_SATPOINT: RefCell<StableBTreeMap<u64, SatPointValue, VMemory>> = RefCell::new(
-    StableBTreeMap::init(get_virtual_memory(SAT_TO_SATPOINT_MEMORY_ID))
+  static SCHEMA_VERSION: RefCell<StableCell<SchemaVersion, VMemory>> = RefCell::new(
+    StableCell::init(get_virtual_memory(SCHEMA_VERSION_MEMORY_ID), SchemaVersion::default()).unwrap()
+  );
+  static SNAPSHOT: RefCell<StableCell<Snapshot, VMemory>> = RefCell::new(
+    StableCell::init(get_virtual_memory(SNAPSHOT_MEMORY_ID), Snapshot::default()).unwrap()
+  );
+  static UNFINISHED_BLOCK: RefCell<StableCell<UnfinishedBlock, VMemory>> = RefCell::new(
+    StableCell::init(get_virtual_memory(UNFINISHED_BLOCK_MEMORY_ID), UnfinishedBlock::default()).unwrap()
+  );
+  static CERTIFIED_REBUILD: RefCell<StableCell<CertifiedRebuild, VMemory>> = RefCell::new(
+    StableCell::init(get_virtual_memory(CERTIFIED_REBUILD_MEMORY_ID), CertifiedRebuild::default()).unwrap()
+  );
+  /// multimap memories, one entry per member
+  static SAT_TO_SEQUENCE_NUMBER: RefCell<StableBTreeMap<(u64, u32), (), VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(SAT_TO_SEQUENCE_NUMBER_MEMORY_ID))
   );
-  static SEQUENCE_NUMBER_TO_CHILDRENS: RefCell<StableBTreeMap<u32, SequenceNumbers, VMemory>> = RefCell::new(
+  static SEQUENCE_NUMBER_TO_CHILD: RefCell<StableBTreeMap<(u32, u32), (), VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(SEQUENCE_NUMBER_TO_CHILD_MEMORY_ID))
+  );
+  static SCRIPT_PUBKEY_TO_OUTPOINT: RefCell<StableBTreeMap<ScriptPubkeyOutPoint, (), VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(SCRIPT_PUBKEY_TO_OUTPOINT_MEMORY_ID))
+  );
+  /// Vec-valued multimaps of schema version 1, only read by the migration
+  static LEGACY_SAT_TO_SEQUENCE_NUMBERS: RefCell<StableBTreeMap<u64, SequenceNumbers, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(SAT_TO_SEQUENCE_NUMBERS_MEMORY_ID))
+  );
+  static LEGACY_SEQUENCE_NUMBER_TO_CHILDRENS: RefCell<StableBTreeMap<u32, SequenceNumbers, VMemory>> = RefCell::new(
     StableBTreeMap::init(get_virtual_memory(SEQUENCE_NUMBER_TO_CHILDRENS_MEMORY_ID))
   );
-  static SCRIPT_PUBKEY_TO_OUTPOINTS: RefCell<StableBTreeMap<Vec<u8>, MyOutPoints, VMemory>> = RefCell::new(
+  static LEGACY_SCRIPT_PUBKEY_TO_OUTPOINTS: RefCell<StableBTreeMap<Vec<u8>, MyOutPoints, VMemory>> = RefCell::new(
     StableBTreeMap::init(get_virtual_memory(SCRIPT_PUBKEY_TO_OUTPOINTS_MEMORY_ID))
   );
+  /// bincode-encoded maps of schema version 2, only read by the migration
+  static LEGACY_OUTPOINT_TO_RUNE_BALANCES: RefCell<StableBTreeMap<OutPointValue, Bincode<RuneBalances>, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(LEGACY_OUTPOINT_TO_RUNE_BALANCES_MEMORY_ID))
+  );
+  static LEGACY_RUNE_ID_TO_RUNE_ENTRY: RefCell<StableBTreeMap<RuneIdValue, Bincode<RuneEntry>, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(LEGACY_RUNE_ID_TO_RUNE_ENTRY_MEMORY_ID))
+  );
+  static LEGACY_SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY: RefCell<StableBTreeMap<u32, Bincode<InscriptionEntry>, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(LEGACY_SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY_MEMORY_ID))
+  );
   /// map memories
+  static SAT_TO_SATPOINT: RefCell<StableBTreeMap<u64, SatPointValue, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(SAT_TO_SATPOINT_MEMORY_ID))
+  );
   static HEIGHT_TO_BLOCK_HEADER: RefCell<StableBTreeMap<u32, HeaderValue, VMemory>> = RefCell::new(
     StableBTreeMap::init(get_virtual_memory(HEIGHT_TO_BLOCK_HEADER_MEMORY_ID))
   );
@@ -219,6 +262,10 @@ thread_local! {
   static SEQUENCE_NUMBER_TO_SATPOINT: RefCell<StableBTreeMap<u32, SatPointValue, VMemory>> = RefCell::new(
     StableBTreeMap::init(get_virtual_memory(SEQUENCE_NUMBER_TO_SATPOINT_MEMORY_ID))
   );
+  // script pubkey of the output each inscription is on, for its address
+  static SEQUENCE_NUMBER_TO_SCRIPT_PUBKEY: RefCell<StableBTreeMap<u32, Vec<u8>, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(SEQUENCE_NUMBER_TO_SCRIPT_PUBKEY_MEMORY_ID))
+  );
   static SEQUENCE_NUMBER_TO_RUNE_ID: RefCell<StableBTreeMap<u32, RuneIdValue, VMemory>> = RefCell::new(
     StableBTreeMap::init(get_virtual_memory(SEQUENCE_NUMBER_TO_RUNE_ID_MEMORY_ID))
   );
@@ -250,6 +297,70 @@ thread_local! {
   static HEIGHT_TO_STATISTIC_RESERVED_RUNES: RefCell<StableBTreeMap<u32, u64, VMemory>> = RefCell::new(
     StableBTreeMap::init(get_virtual_memory(HEIGHT_TO_STATISTIC_RESERVED_RUNES_MEMORY_ID))
   );
+
+  static SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES: RefCell<StableBTreeMap<u32, InscriptionAttributes, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES_MEMORY_ID))
+  );
+
+  static TRANSACTION_ID_TO_CACHED_TRANSACTION: RefCell<StableBTreeMap<TxidValue, CachedTransaction, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(TRANSACTION_ID_TO_CACHED_TRANSACTION_MEMORY_ID))
+  );
+
+  static LAST_USED_TO_CACHED_TRANSACTION_ID: RefCell<StableBTreeMap<u64, TxidValue, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(LAST_USED_TO_CACHED_TRANSACTION_ID_MEMORY_ID))
+  );
+
+  static HEIGHT_TO_BLOCK_SUMMARY: RefCell<StableBTreeMap<u32, BlockSummary, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(HEIGHT_TO_BLOCK_SUMMARY_MEMORY_ID))
+  );
+
+  static BLOCK_HASH_TO_HEIGHT: RefCell<StableBTreeMap<BlockHashValue, u32, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(BLOCK_HASH_TO_HEIGHT_MEMORY_ID))
+  );
+
+  static CONFIG_CHANGES: RefCell<StableBTreeMap<u64, ConfigChange, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(CONFIG_CHANGES_MEMORY_ID))
+  );
+
+  static PRINCIPAL_TO_ROLE: RefCell<StableBTreeMap<PrincipalValue, Role, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(PRINCIPAL_TO_ROLE_MEMORY_ID))
+  );
+
+  static FEATURE_TO_BACKFILL: RefCell<StableBTreeMap<u8, Backfill, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(FEATURE_TO_BACKFILL_MEMORY_ID))
+  );
+
+  static HEIGHT_TO_CHECKPOINT: RefCell<StableBTreeMap<u32, Checkpoint, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(HEIGHT_TO_CHECKPOINT_MEMORY_ID))
+  );
+
+  static CHECKPOINT_CHUNKS: RefCell<StableBTreeMap<(u32, u32), CheckpointChunk, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(CHECKPOINT_CHUNKS_MEMORY_ID))
+  );
+
+  static HEIGHT_TO_COMMITMENT: RefCell<StableBTreeMap<u32, [u8; 32], VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(HEIGHT_TO_COMMITMENT_MEMORY_ID))
+  );
+
+  static CHECKPOINT_UNDO: RefCell<StableBTreeMap<CheckpointUndoKey, CheckpointUndo, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(CHECKPOINT_UNDO_MEMORY_ID))
+  );
+  /// certified trees, and the nodes whose hashes are out of date by tree
+  static CERTIFIED_RUNE_BALANCES: RefCell<StableBTreeMap<CertifiedNodeId, CertifiedNode, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(CERTIFIED_RUNE_BALANCES_MEMORY_ID))
+  );
+  static CERTIFIED_INSCRIPTION_SATPOINTS: RefCell<StableBTreeMap<CertifiedNodeId, CertifiedNode, VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(CERTIFIED_INSCRIPTION_SATPOINTS_MEMORY_ID))
+  );
+  static CERTIFIED_DIRTY: RefCell<StableBTreeMap<(u8, CertifiedNodeId), (), VMemory>> = RefCell::new(
+    StableBTreeMap::init(get_virtual_memory(CERTIFIED_DIRTY_MEMORY_ID))
+  );
+}
+
+thread_local! {
+  /// Undo log epoch UTXO map changes are recorded in, `Some(None)` while no
+  /// checkpoint covers them. Cached, since every change looks it up.
+  static UNDO_EPOCH: Cell<Option<Option<u32>>> = const { Cell::new(None) };
 }
 
 
@@ -267,6 +378,83 @@ pub fn is_shutting_down() -> bool {
   SHUTTING_DOWN.load(atomic::Ordering::Relaxed)
 }
 
+/// How long the RPC tip is trusted before the updater asks for it again, so
+/// `sync_lag` stays current once the indexer has caught up.
+const RPC_TIP_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+thread_local! {
+  // heap only: refreshed by the updater, not worth persisting across upgrades.
+  // the height and when it was fetched
+  static RPC_TIP_HEIGHT: Cell<Option<(u32, u64)>> = const { Cell::new(None) };
+}
+
+pub(crate) fn set_rpc_tip_height(height: u32) {
+  RPC_TIP_HEIGHT.with(|tip| tip.set(Some((height, crate::time()))));
+}
+
+pub(crate) fn rpc_tip_height() -> Option<u32> {
+  RPC_TIP_HEIGHT.with(|tip| tip.get()).map(|(height, _)| height)
+}
+
+/// Whether the updater should fetch the RPC tip before indexing `height`:
+/// once it passed the last tip seen, or once that is too old.
+pub(crate) fn rpc_tip_is_stale(height: u32) -> bool {
+  RPC_TIP_HEIGHT.with(|tip| tip.get()).map_or(true, |(tip, fetched_at)| {
+    height > tip + 1 || crate::time().saturating_sub(fetched_at) >= RPC_TIP_MAX_AGE.as_nanos() as u64
+  })
+}
+
+/// Entry counts of every stable map, by name.
+pub(crate) fn mem_table_sizes() -> Vec<(&'static str, u64)> {
+  vec![
+    ("sat_to_sequence_number", SAT_TO_SEQUENCE_NUMBER.with(|m| m.borrow().len())),
+    ("sequence_number_to_child", SEQUENCE_NUMBER_TO_CHILD.with(|m| m.borrow().len())),
+    ("script_pubkey_to_outpoint", SCRIPT_PUBKEY_TO_OUTPOINT.with(|m| m.borrow().len())),
+    ("legacy_sat_to_sequence_numbers", LEGACY_SAT_TO_SEQUENCE_NUMBERS.with(|m| m.borrow().len())),
+    ("legacy_sequence_number_to_childrens", LEGACY_SEQUENCE_NUMBER_TO_CHILDRENS.with(|m| m.borrow().len())),
+    ("legacy_script_pubkey_to_outpoints", LEGACY_SCRIPT_PUBKEY_TO_OUTPOINTS.with(|m| m.borrow().len())),
+    ("legacy_outpoint_to_rune_balances", LEGACY_OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow().len())),
+    ("legacy_rune_id_to_rune_entry", LEGACY_RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow().len())),
+    ("legacy_sequence_number_to_inscription_entry", LEGACY_SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY.with(|m| m.borrow().len())),
+    ("sat_to_satpoint", SAT_TO_SATPOINT.with(|m| m.borrow().len())),
+    ("height_to_block_header", HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow().len())),
+    ("height_to_last_sequence_number", HEIGHT_TO_LAST_SEQUENCE_NUMBER.with(|m| m.borrow().len())),
+    ("sequence_number_to_inscription_entry", SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY.with(|m| m.borrow().len())),
+    ("inscription_id_to_sequence_number", INSCRIPTION_ID_TO_SEQUENCE_NUMBER.with(|m| m.borrow().len())),
+    ("transaction_id_to_transaction", TRANSACTION_ID_TO_TRANSACTION.with(|m| m.borrow().len())),
+    ("inscription_number_to_sequence_number", INSCRIPTION_NUMBER_TO_SEQUENCE_NUMBER.with(|m| m.borrow().len())),
+    ("home_inscriptions", HOME_INSCRIPTIONS.with(|m| m.borrow().len())),
+    ("statistic_to_count", STATISTIC_TO_COUNT.with(|m| m.borrow().len())),
+    ("outpoint_to_utxo_entry", OUTPOINT_TO_UTXO_ENTRY.with(|m| m.borrow().len())),
+    ("sequence_number_to_satpoint", SEQUENCE_NUMBER_TO_SATPOINT.with(|m| m.borrow().len())),
+    ("sequence_number_to_script_pubkey", SEQUENCE_NUMBER_TO_SCRIPT_PUBKEY.with(|m| m.borrow().len())),
+    ("sequence_number_to_rune_id", SEQUENCE_NUMBER_TO_RUNE_ID.with(|m| m.borrow().len())),
+    ("rune_id_to_rune_entry", RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow().len())),
+    ("outpoint_to_rune_balances", OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow().len())),
+    ("outpoint_to_height", OUTPOINT_TO_HEIGHT.with(|m| m.borrow().len())),
+    ("height_to_change_record_rune", HEIGHT_TO_CHANGE_RECORD_RUNE.with(|m| m.borrow().len())),
+    ("rune_to_rune_id", RUNE_TO_RUNE_ID.with(|m| m.borrow().len())),
+    ("transaction_id_to_rune", TRANSACTION_ID_TO_RUNE.with(|m| m.borrow().len())),
+    ("height_to_statistic_runes", HEIGHT_TO_STATISTIC_RUNES.with(|m| m.borrow().len())),
+    ("height_to_statistic_reserved_runes", HEIGHT_TO_STATISTIC_RESERVED_RUNES.with(|m| m.borrow().len())),
+    ("sequence_number_to_inscription_attributes", SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES.with(|m| m.borrow().len())),
+    ("transaction_id_to_cached_transaction", TRANSACTION_ID_TO_CACHED_TRANSACTION.with(|m| m.borrow().len())),
+    ("last_used_to_cached_transaction_id", LAST_USED_TO_CACHED_TRANSACTION_ID.with(|m| m.borrow().len())),
+    ("height_to_block_summary", HEIGHT_TO_BLOCK_SUMMARY.with(|m| m.borrow().len())),
+    ("block_hash_to_height", BLOCK_HASH_TO_HEIGHT.with(|m| m.borrow().len())),
+    ("config_changes", CONFIG_CHANGES.with(|m| m.borrow().len())),
+    ("principal_to_role", PRINCIPAL_TO_ROLE.with(|m| m.borrow().len())),
+    ("feature_to_backfill", FEATURE_TO_BACKFILL.with(|m| m.borrow().len())),
+    ("height_to_checkpoint", HEIGHT_TO_CHECKPOINT.with(|m| m.borrow().len())),
+    ("checkpoint_chunks", CHECKPOINT_CHUNKS.with(|m| m.borrow().len())),
+    ("checkpoint_undo", CHECKPOINT_UNDO.with(|m| m.borrow().len())),
+    ("certified_rune_balances", CERTIFIED_RUNE_BALANCES.with(|m| m.borrow().len())),
+    ("certified_inscription_satpoints", CERTIFIED_INSCRIPTION_SATPOINTS.with(|m| m.borrow().len())),
+    ("certified_dirty", CERTIFIED_DIRTY.with(|m| m.borrow().len())),
+    ("height_to_commitment", HEIGHT_TO_COMMITMENT.with(|m| m.borrow().len())),
+  ]
+}
+
 
 pub fn mem_get_config() -> Config {
   CONFIG.with(|m| m.borrow().get().clone())
@@ -278,6 +466,412 @@ pub fn mem_set_config(config: Config) -> Result<Config> {
     .map_err(|e| anyhow::anyhow!("Failed to set config: {:?}", e))
 }
 
+pub(crate) fn mem_record_config_change(change: ConfigChange) {
+  CONFIG_CHANGES.with(|m| {
+    let mut m = m.borrow_mut();
+    let next = m.last_key_value().map(|(index, _)| index + 1).unwrap_or(0);
+    m.insert(next, change);
+  });
+}
+
+/// Config changes starting at index `from`, oldest first.
+pub(crate) fn mem_config_changes(from: u64, limit: usize) -> Vec<(u64, ConfigChange)> {
+  CONFIG_CHANGES.with(|m| m.borrow().range(from..).take(limit).collect())
+}
+
+pub(crate) fn mem_get_role(principal: Principal) -> Option<Role> {
+  PRINCIPAL_TO_ROLE.with(|m| m.borrow().get(&principal.store()))
+}
+
+pub(crate) fn mem_insert_role(principal: Principal, role: Role) -> Option<Role> {
+  PRINCIPAL_TO_ROLE.with(|m| m.borrow_mut().insert(principal.store(), role))
+}
+
+pub(crate) fn mem_remove_role(principal: Principal) -> Option<Role> {
+  PRINCIPAL_TO_ROLE.with(|m| m.borrow_mut().remove(&principal.store()))
+}
+
+pub(crate) fn mem_roles() -> Vec<(Principal, Role)> {
+  PRINCIPAL_TO_ROLE.with(|m| {
+    m.borrow()
+      .iter()
+      .map(|(principal, role)| (Principal::load(principal), role))
+      .collect()
+  })
+}
+
+/// Replaces `map` with an empty one in its memory, which takes constant time
+/// regardless of how many entries it held.
+fn mem_reset_map<K: Storable + Ord + Clone, V: Storable>(
+  map: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMemory>>>,
+  id: MemoryId,
+) {
+  map.with(|m| *m.borrow_mut() = StableBTreeMap::new(get_virtual_memory(id)));
+}
+
+/// Clears everything built by the rune updater.
+pub(crate) fn mem_reset_runes() {
+  certification::clear_rune_balances();
+  mem_reset_map(&RUNE_ID_TO_RUNE_ENTRY, RUNE_ID_TO_RUNE_ENTRY_MEMORY_ID);
+  mem_reset_map(&RUNE_TO_RUNE_ID, RUNE_TO_RUNE_ID_MEMORY_ID);
+  mem_reset_map(&OUTPOINT_TO_RUNE_BALANCES, OUTPOINT_TO_RUNE_BALANCES_MEMORY_ID);
+  mem_reset_map(&OUTPOINT_TO_HEIGHT, OUTPOINT_TO_HEIGHT_MEMORY_ID);
+  mem_reset_map(&SEQUENCE_NUMBER_TO_RUNE_ID, SEQUENCE_NUMBER_TO_RUNE_ID_MEMORY_ID);
+  mem_reset_map(&TRANSACTION_ID_TO_RUNE, TRANSACTION_ID_TO_RUNE_MEMORY_ID);
+  mem_reset_map(&HEIGHT_TO_CHANGE_RECORD_RUNE, HEIGHT_TO_CHANGE_RECORD_RUNE_MEMORY_ID);
+  mem_reset_map(&HEIGHT_TO_STATISTIC_RUNES, HEIGHT_TO_STATISTIC_RUNES_MEMORY_ID);
+  mem_reset_map(&HEIGHT_TO_STATISTIC_RESERVED_RUNES, HEIGHT_TO_STATISTIC_RESERVED_RUNES_MEMORY_ID);
+}
+
+/// Clears the UTXO entries and everything derived from them: inscriptions, sats,
+/// addresses and transactions.
+pub(crate) fn mem_reset_transactions() {
+  mem_reset_map(&TRANSACTION_ID_TO_TRANSACTION, TRANSACTION_ID_TO_TRANSACTION_MEMORY_ID);
+}
+
+pub(crate) fn mem_reset_utxo_entries() {
+  certification::clear_inscription_satpoints();
+  mem_reset_map(&OUTPOINT_TO_UTXO_ENTRY, OUTPOINT_TO_UTXO_ENTRY_MEMORY_ID);
+  mem_reset_map(&SCRIPT_PUBKEY_TO_OUTPOINT, SCRIPT_PUBKEY_TO_OUTPOINT_MEMORY_ID);
+  mem_reset_map(&SAT_TO_SATPOINT, SAT_TO_SATPOINT_MEMORY_ID);
+  mem_reset_map(&SAT_TO_SEQUENCE_NUMBER, SAT_TO_SEQUENCE_NUMBER_MEMORY_ID);
+  mem_reset_map(&SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY, SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY_MEMORY_ID);
+  mem_reset_map(&SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES, SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES_MEMORY_ID);
+  mem_reset_map(&SEQUENCE_NUMBER_TO_SATPOINT, SEQUENCE_NUMBER_TO_SATPOINT_MEMORY_ID);
+  mem_reset_map(&SEQUENCE_NUMBER_TO_SCRIPT_PUBKEY, SEQUENCE_NUMBER_TO_SCRIPT_PUBKEY_MEMORY_ID);
+  mem_reset_map(&SEQUENCE_NUMBER_TO_CHILD, SEQUENCE_NUMBER_TO_CHILD_MEMORY_ID);
+  mem_reset_map(&INSCRIPTION_ID_TO_SEQUENCE_NUMBER, INSCRIPTION_ID_TO_SEQUENCE_NUMBER_MEMORY_ID);
+  mem_reset_map(&INSCRIPTION_NUMBER_TO_SEQUENCE_NUMBER, INSCRIPTION_NUMBER_TO_SEQUENCE_NUMBER_MEMORY_ID);
+  mem_reset_map(&HOME_INSCRIPTIONS, HOME_INSCRIPTIONS_MEMORY_ID);
+  mem_reset_map(&HEIGHT_TO_LAST_SEQUENCE_NUMBER, HEIGHT_TO_LAST_SEQUENCE_NUMBER_MEMORY_ID);
+  mem_reset_map(&TRANSACTION_ID_TO_TRANSACTION, TRANSACTION_ID_TO_TRANSACTION_MEMORY_ID);
+  for statistic in [
+    Statistic::BlessedInscriptions,
+    Statistic::CursedInscriptions,
+    Statistic::LostSats,
+    Statistic::OutputsTraversed,
+    Statistic::SatRanges,
+    Statistic::UnboundInscriptions,
+  ] {
+    mem_insert_statistic_to_count(statistic, 0);
+  }
+}
+
+pub(crate) fn mem_backfills() -> Vec<(IndexFeature, Backfill)> {
+  FEATURE_TO_BACKFILL.with(|m| {
+    m.borrow()
+      .iter()
+      .map(|(feature, backfill)| (IndexFeature::load(feature), backfill))
+      .collect()
+  })
+}
+
+pub(crate) fn mem_insert_backfill(feature: IndexFeature, backfill: Backfill) {
+  FEATURE_TO_BACKFILL.with(|m| m.borrow_mut().insert(feature.store(), backfill));
+}
+
+pub(crate) fn mem_remove_backfill(feature: IndexFeature) -> Option<Backfill> {
+  FEATURE_TO_BACKFILL.with(|m| m.borrow_mut().remove(&feature.store()))
+}
+
+pub(crate) fn mem_checkpoints() -> Vec<(u32, Checkpoint)> {
+  HEIGHT_TO_CHECKPOINT.with(|m| m.borrow().iter().collect())
+}
+
+pub(crate) fn mem_insert_checkpoint(height: u32, checkpoint: Checkpoint) {
+  HEIGHT_TO_CHECKPOINT.with(|m| m.borrow_mut().insert(height, checkpoint));
+  UNDO_EPOCH.with(|epoch| epoch.set(None));
+}
+
+/// Removes the checkpoint at `height` together with its chunks. Its undo log
+/// stays, since restoring an older checkpoint goes through it as well.
+pub(crate) fn mem_remove_checkpoint(height: u32) {
+  HEIGHT_TO_CHECKPOINT.with(|m| m.borrow_mut().remove(&height));
+  UNDO_EPOCH.with(|epoch| epoch.set(None));
+  CHECKPOINT_CHUNKS.with(|m| {
+    let mut m = m.borrow_mut();
+    let keys: Vec<(u32, u32)> = m
+      .range((height, 0)..=(height, u32::MAX))
+      .map(|(key, _)| key)
+      .collect();
+    for key in keys {
+      m.remove(&key);
+    }
+  });
+}
+
+fn mem_last_undo_epoch() -> Option<u32> {
+  CHECKPOINT_UNDO.with(|m| m.borrow().last_key_value().map(|(key, _)| key.epoch))
+}
+
+/// The epoch UTXO map changes are recorded in: the newest one of a checkpoint
+/// covering them, or a later one left by checkpoints discarded since.
+fn mem_undo_epoch() -> Option<u32> {
+  if let Some(epoch) = UNDO_EPOCH.with(Cell::get) {
+    return epoch;
+  }
+  let epoch = HEIGHT_TO_CHECKPOINT
+    .with(|m| {
+      m.borrow()
+        .iter()
+        .filter(|(_, checkpoint)| checkpoint.utxo_entries)
+        .map(|(_, checkpoint)| checkpoint.epoch)
+        .max()
+    })
+    .map(|epoch| epoch.max(mem_last_undo_epoch().unwrap_or_default()));
+  UNDO_EPOCH.with(|cell| cell.set(Some(epoch)));
+  epoch
+}
+
+/// Epoch for the changes after a new checkpoint, past every one in use.
+pub(crate) fn mem_next_undo_epoch() -> u32 {
+  mem_checkpoints()
+    .iter()
+    .map(|(_, checkpoint)| checkpoint.epoch + 1)
+    .chain(mem_last_undo_epoch().map(|epoch| epoch + 1))
+    .max()
+    .unwrap_or_default()
+}
+
+/// Changes `key` of a UTXO map through `change`, after recording the value it
+/// had in the undo log if this is its first change in the current epoch.
+fn mem_change<K: Storable + Ord + Clone, V: Storable, R>(
+  map: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMemory>>>,
+  table: checkpoint::Table,
+  key: &K,
+  change: impl FnOnce(&mut StableBTreeMap<K, V, VMemory>) -> R,
+) -> R {
+  map.with(|m| {
+    let mut m = m.borrow_mut();
+    if let Some(epoch) = mem_undo_epoch() {
+      let undo_key = CheckpointUndoKey {
+        epoch,
+        table: table as u8,
+        key: key.to_bytes().into_owned(),
+      };
+      CHECKPOINT_UNDO.with(|u| {
+        let mut u = u.borrow_mut();
+        if !u.contains_key(&undo_key) {
+          let value = m.get(key).map(|value| value.to_bytes().into_owned());
+          u.insert(undo_key, CheckpointUndo { value });
+        }
+      });
+    }
+    change(&mut m)
+  })
+}
+
+/// Drops up to `limit` undo log entries of epochs before `epoch`, which no
+/// checkpoint goes back to anymore.
+pub(crate) fn mem_prune_undo_below(epoch: u32, limit: usize) {
+  CHECKPOINT_UNDO.with(|m| {
+    let mut m = m.borrow_mut();
+    for _ in 0..limit {
+      if !m.first_key_value().is_some_and(|(key, _)| key.epoch < epoch) {
+        break;
+      }
+      m.pop_first();
+    }
+  });
+}
+
+pub(crate) fn mem_get_checkpoint_chunk(height: u32, chunk: u32) -> Option<CheckpointChunk> {
+  CHECKPOINT_CHUNKS.with(|m| m.borrow().get(&(height, chunk)))
+}
+
+pub(crate) fn mem_insert_checkpoint_chunk(height: u32, chunk: u32, entries: CheckpointChunk) {
+  CHECKPOINT_CHUNKS.with(|m| m.borrow_mut().insert((height, chunk), entries));
+}
+
+/// Up to `limit` raw entries of `map` that come after the key `after`, stopping
+/// early once `max_bytes` of keys and values are read.
+fn mem_read_map<K: Storable + Ord + Clone, V: Storable>(
+  map: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMemory>>>,
+  after: Option<Vec<u8>>,
+  limit: usize,
+  max_bytes: usize,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+  map.with(|m| {
+    let m = m.borrow();
+    let entries = match after {
+      Some(key) => m.range((Bound::Excluded(K::from_bytes(Cow::Owned(key))), Bound::Unbounded)),
+      None => m.range(..),
+    };
+    let mut bytes = 0;
+    entries
+      .take(limit)
+      .map(|(key, value)| (key.to_bytes().into_owned(), value.to_bytes().into_owned()))
+      .take_while(|(key, value)| {
+        let within = bytes < max_bytes;
+        bytes += key.len() + value.len();
+        within
+      })
+      .collect()
+  })
+}
+
+fn mem_write_map<K: Storable + Ord + Clone, V: Storable>(
+  map: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMemory>>>,
+  entries: Vec<(Vec<u8>, Vec<u8>)>,
+) {
+  map.with(|m| {
+    let mut m = m.borrow_mut();
+    for (key, value) in entries {
+      m.insert(K::from_bytes(Cow::Owned(key)), V::from_bytes(Cow::Owned(value)));
+    }
+  });
+}
+
+/// Calls `$f` with the map backing a checkpoint or snapshot table.
+macro_rules! with_checkpoint_table {
+  ($table:expr, $f:ident($($arg:expr),*)) => {
+    match $table {
+      checkpoint::Table::RuneIdToRuneEntry => $f(&RUNE_ID_TO_RUNE_ENTRY, $($arg),*),
+      checkpoint::Table::RuneToRuneId => $f(&RUNE_TO_RUNE_ID, $($arg),*),
+      checkpoint::Table::OutpointToRuneBalances => $f(&OUTPOINT_TO_RUNE_BALANCES, $($arg),*),
+      checkpoint::Table::OutpointToHeight => $f(&OUTPOINT_TO_HEIGHT, $($arg),*),
+      checkpoint::Table::SequenceNumberToRuneId => $f(&SEQUENCE_NUMBER_TO_RUNE_ID, $($arg),*),
+      checkpoint::Table::TransactionIdToRune => $f(&TRANSACTION_ID_TO_RUNE, $($arg),*),
+      checkpoint::Table::HeightToChangeRecordRune => $f(&HEIGHT_TO_CHANGE_RECORD_RUNE, $($arg),*),
+      checkpoint::Table::HeightToStatisticRunes => $f(&HEIGHT_TO_STATISTIC_RUNES, $($arg),*),
+      checkpoint::Table::HeightToStatisticReservedRunes => $f(&HEIGHT_TO_STATISTIC_RESERVED_RUNES, $($arg),*),
+      checkpoint::Table::OutpointToUtxoEntry => $f(&OUTPOINT_TO_UTXO_ENTRY, $($arg),*),
+      checkpoint::Table::ScriptPubkeyToOutpoint => $f(&SCRIPT_PUBKEY_TO_OUTPOINT, $($arg),*),
+      checkpoint::Table::SatToSatpoint => $f(&SAT_TO_SATPOINT, $($arg),*),
+      checkpoint::Table::SatToSequenceNumber => $f(&SAT_TO_SEQUENCE_NUMBER, $($arg),*),
+      checkpoint::Table::SequenceNumberToInscriptionEntry => $f(&SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY, $($arg),*),
+      checkpoint::Table::SequenceNumberToInscriptionAttributes => $f(&SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES, $($arg),*),
+      checkpoint::Table::SequenceNumberToSatpoint => $f(&SEQUENCE_NUMBER_TO_SATPOINT, $($arg),*),
+      checkpoint::Table::SequenceNumberToScriptPubkey => $f(&SEQUENCE_NUMBER_TO_SCRIPT_PUBKEY, $($arg),*),
+      checkpoint::Table::SequenceNumberToChild => $f(&SEQUENCE_NUMBER_TO_CHILD, $($arg),*),
+      checkpoint::Table::InscriptionIdToSequenceNumber => $f(&INSCRIPTION_ID_TO_SEQUENCE_NUMBER, $($arg),*),
+      checkpoint::Table::InscriptionNumberToSequenceNumber => $f(&INSCRIPTION_NUMBER_TO_SEQUENCE_NUMBER, $($arg),*),
+      checkpoint::Table::HomeInscriptions => $f(&HOME_INSCRIPTIONS, $($arg),*),
+      checkpoint::Table::HeightToLastSequenceNumber => $f(&HEIGHT_TO_LAST_SEQUENCE_NUMBER, $($arg),*),
+      checkpoint::Table::TransactionIdToTransaction => $f(&TRANSACTION_ID_TO_TRANSACTION, $($arg),*),
+      checkpoint::Table::StatisticToCount => $f(&STATISTIC_TO_COUNT, $($arg),*),
+      checkpoint::Table::HeightToBlockHeader => $f(&HEIGHT_TO_BLOCK_HEADER, $($arg),*),
+      checkpoint::Table::HeightToBlockSummary => $f(&HEIGHT_TO_BLOCK_SUMMARY, $($arg),*),
+      checkpoint::Table::BlockHashToHeight => $f(&BLOCK_HASH_TO_HEIGHT, $($arg),*),
+      checkpoint::Table::HeightToCommitment => $f(&HEIGHT_TO_COMMITMENT, $($arg),*),
+    }
+  };
+}
+
+pub(crate) fn mem_read_checkpoint_table(
+  table: checkpoint::Table,
+  after: Option<Vec<u8>>,
+  limit: usize,
+  max_bytes: usize,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+  with_checkpoint_table!(table, mem_read_map(after, limit, max_bytes))
+}
+
+fn mem_map_len<K: Storable + Ord + Clone, V: Storable>(
+  map: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMemory>>>,
+) -> u64 {
+  map.with(|m| m.borrow().len())
+}
+
+pub(crate) fn mem_checkpoint_table_len(table: checkpoint::Table) -> u64 {
+  with_checkpoint_table!(table, mem_map_len())
+}
+
+/// Inserts entries read from a checkpoint or snapshot table. The transaction
+/// cache isn't part of either, so its size statistic is left as it is.
+pub(crate) fn mem_write_checkpoint_table(table: checkpoint::Table, entries: Vec<(Vec<u8>, Vec<u8>)>) {
+  let cache_bytes = mem_get_statistic_count(Statistic::TransactionCacheBytes);
+  with_checkpoint_table!(table, mem_write_map(entries));
+  mem_insert_statistic_to_count(Statistic::TransactionCacheBytes, cache_bytes);
+}
+
+fn mem_put_back<K: Storable + Ord + Clone, V: Storable>(
+  map: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMemory>>>,
+  key: Vec<u8>,
+  value: Option<Vec<u8>>,
+) {
+  map.with(|m| {
+    let mut m = m.borrow_mut();
+    let key = K::from_bytes(Cow::Owned(key));
+    match value {
+      Some(value) => m.insert(key, V::from_bytes(Cow::Owned(value))),
+      None => m.remove(&key),
+    };
+  });
+}
+
+/// Puts back the newest undo log entry recorded in `epoch` or later. Returns
+/// false once there is none left.
+pub(crate) fn mem_undo_last(epoch: u32) -> bool {
+  let Some((key, undo)) = CHECKPOINT_UNDO.with(|m| {
+    let mut m = m.borrow_mut();
+    m.last_key_value().filter(|(key, _)| key.epoch >= epoch)?;
+    m.pop_last()
+  }) else {
+    return false;
+  };
+  let table = checkpoint::TABLES[usize::from(key.table)];
+  with_checkpoint_table!(table, mem_put_back(key.key, undo.value));
+  true
+}
+
+/// Drops the headers and summaries of every block above `height`.
+pub(crate) fn mem_remove_blocks_above(height: u32) {
+  let mut orphaned = Vec::new();
+  while let Some(tip) = mem_latest_block_height().filter(|tip| *tip > height) {
+    orphaned.extend(mem_remove_block_header(tip).map(|header| Header::load(header).block_hash()));
+    mem_remove_block_summary(tip);
+    mem_remove_commitment(tip);
+  }
+  mem_evict_cached_transactions_in(&orphaned);
+}
+
+pub(crate) fn mem_length_outpoint_to_utxo_entry() -> u64 {
+  OUTPOINT_TO_UTXO_ENTRY.with(|m| m.borrow().len())
+}
+
+/// Canisters installed before schema versions were recorded read as version 0.
+pub(crate) fn mem_get_schema_version() -> SchemaVersion {
+  SCHEMA_VERSION.with(|m| m.borrow().get().clone())
+}
+
+pub(crate) fn mem_set_schema_version(schema: SchemaVersion) -> Result<SchemaVersion> {
+  SCHEMA_VERSION
+    .with(|m| m.borrow_mut().set(schema))
+    .map_err(|e| anyhow::anyhow!("Failed to set schema version: {:?}", e))
+}
+
+pub(crate) fn mem_get_snapshot() -> Snapshot {
+  SNAPSHOT.with(|m| m.borrow().get().clone())
+}
+
+pub(crate) fn mem_set_snapshot(snapshot: Snapshot) -> Result<Snapshot> {
+  SNAPSHOT
+    .with(|m| m.borrow_mut().set(snapshot))
+    .map_err(|e| anyhow::anyhow!("Failed to set snapshot: {:?}", e))
+}
+
+pub(crate) fn mem_get_unfinished_block() -> Option<u32> {
+  UNFINISHED_BLOCK.with(|m| m.borrow().get().height)
+}
+
+pub(crate) fn mem_set_unfinished_block(height: Option<u32>) -> Result<UnfinishedBlock> {
+  UNFINISHED_BLOCK
+    .with(|m| m.borrow_mut().set(UnfinishedBlock { height }))
+    .map_err(|e| anyhow::anyhow!("Failed to set unfinished block: {:?}", e))
+}
+
+/// Whether the indexing loop should be running, kept across upgrades.
+pub fn mem_get_indexer_running() -> bool {
+  INDEXER_RUNNING.with(|m| *m.borrow().get())
+}
+
+pub fn mem_set_indexer_running(running: bool) -> Result<bool> {
+  INDEXER_RUNNING
+    .with(|m| m.borrow_mut().set(running))
+    .map_err(|e| anyhow::anyhow!("Failed to set indexer running: {:?}", e))
+}
+
 
 pub fn mem_latest_block() -> Option<(u32, BlockHash)> {
   HEIGHT_TO_BLOCK_HEADER.with(|m| {
@@ -292,6 +886,11 @@ pub fn mem_latest_block() -> Option<(u32, BlockHash)> {
   })
 }
 
+/// The oldest header kept, which the header backfill continues below.
+pub(crate) fn mem_first_block_header() -> Option<(u32, Header)> {
+  HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow().first_key_value().map(|(height, header_value)| (height, Header::load(header_value))))
+}
+
 pub(crate) fn mem_latest_block_height() -> Option<u32> {
   HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow().iter().rev().next().map(|(height, _)| height))
 }
@@ -304,24 +903,64 @@ pub(crate) fn mem_block_hash(height: u32) -> Option<BlockHash> {
   })
 }
 
+/// Headers are kept for every indexed height, not just the reorg window.
+pub(crate) fn mem_get_block_header(height: u32) -> Option<Header> {
+  HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow().get(&height).map(Header::load))
+}
+
 pub(crate) fn mem_insert_block_header(height: u32, header_value: HeaderValue) {
+  let block_hash = Header::load(header_value).block_hash();
+  BLOCK_HASH_TO_HEIGHT.with(|m| m.borrow_mut().insert(block_hash.store(), height));
   HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow_mut().insert(height, header_value));
 }
 
 pub(crate) fn mem_remove_block_header(height: u32) -> Option<HeaderValue> {
-  HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow_mut().remove(&height))
+  let header_value = HEIGHT_TO_BLOCK_HEADER.with(|m| m.borrow_mut().remove(&height))?;
+  let block_hash = Header::load(header_value).block_hash();
+  BLOCK_HASH_TO_HEIGHT.with(|m| m.borrow_mut().remove(&block_hash.store()));
+  Some(header_value)
 }
 
-pub(crate) fn mem_prune_block_header(height: u32) {
-  HEIGHT_TO_BLOCK_HEADER.with(|m| {
-    let mut map = m.borrow_mut();
-    let keys_to_remove: Vec<u32> = map
-      .iter()
-      .take_while(|(h, _)| *h <= height)
-      .map(|(h, _)| h)
-      .collect();
-    for key in keys_to_remove {
-      map.remove(&key);
+pub(crate) fn mem_get_block_height(block_hash: BlockHash) -> Option<u32> {
+  BLOCK_HASH_TO_HEIGHT.with(|m| m.borrow().get(&block_hash.store()))
+}
+
+pub(crate) fn mem_get_block_summary(height: u32) -> Option<BlockSummary> {
+  HEIGHT_TO_BLOCK_SUMMARY.with(|m| m.borrow().get(&height))
+}
+
+pub(crate) fn mem_insert_block_summary(height: u32, summary: BlockSummary) {
+  HEIGHT_TO_BLOCK_SUMMARY.with(|m| m.borrow_mut().insert(height, summary));
+}
+
+pub(crate) fn mem_remove_block_summary(height: u32) -> Option<BlockSummary> {
+  HEIGHT_TO_BLOCK_SUMMARY.with(|m| m.borrow_mut().remove(&height))
+}
+
+pub(crate) fn mem_get_commitment(height: u32) -> Option<[u8; 32]> {
+  HEIGHT_TO_COMMITMENT.with(|m| m.borrow().get(&height))
+}
+
+pub(crate) fn mem_insert_commitment(height: u32, commitment: [u8; 32]) {
+  HEIGHT_TO_COMMITMENT.with(|m| m.borrow_mut().insert(height, commitment));
+}
+
+pub(crate) fn mem_remove_commitment(height: u32) -> Option<[u8; 32]> {
+  HEIGHT_TO_COMMITMENT.with(|m| m.borrow_mut().remove(&height))
+}
+
+/// Drops the commitments at `height` and above, all at once when none are
+/// below it.
+pub(crate) fn mem_remove_commitments_from(height: u32) {
+  let first = HEIGHT_TO_COMMITMENT.with(|m| m.borrow().first_key_value().map(|(first, _)| first));
+  if first.is_some_and(|first| first >= height) {
+    mem_reset_map(&HEIGHT_TO_COMMITMENT, HEIGHT_TO_COMMITMENT_MEMORY_ID);
+    return;
+  }
+  HEIGHT_TO_COMMITMENT.with(|m| {
+    let mut m = m.borrow_mut();
+    while m.last_key_value().is_some_and(|(last, _)| last >= height) {
+      m.pop_last();
     }
   });
 }
@@ -333,73 +972,171 @@ pub(crate) fn next_block(index: &Index) -> (u32, Option<BlockHash>) {
 }
 
 pub(crate) fn mem_insert_sat_to_sequence_numbers(sat: u64, seq: u32) -> bool {
-  SAT_TO_SEQUENCE_NUMBERS.with(|m| {
-    let mut map = m.borrow_mut();
-    let mut sequence_numbers = map.get(&sat).unwrap_or_default();
-    if sequence_numbers.contains(seq) {
-      return false;
-    }
-    sequence_numbers.push(seq);
-    map.insert(sat, sequence_numbers);
-    return true;
+  mem_change(&SAT_TO_SEQUENCE_NUMBER, checkpoint::Table::SatToSequenceNumber, &(sat, seq), |m| {
+    m.insert((sat, seq), ()).is_none()
   })
 }
 
+/// Sequence numbers only grow, so the first member is the oldest inscription.
 pub(crate) fn mem_get_first_seq_of_sat_to_sequence_numbers(sat: u64) -> Option<u32> {
-  SAT_TO_SEQUENCE_NUMBERS.with(|m| m.borrow().get(&sat).map(|seqs| seqs.0[0]))
+  SAT_TO_SEQUENCE_NUMBER.with(|m| {
+    m.borrow()
+      .range((sat, 0)..=(sat, u32::MAX))
+      .next()
+      .map(|((_, seq), _)| seq)
+  })
 }
 
 pub(crate) fn mem_insert_sequence_number_to_childrens(seq: u32, seq_children: u32) -> bool {
-  SEQUENCE_NUMBER_TO_CHILDRENS.with(|m| {
-    let mut map = m.borrow_mut();
-    let mut sequence_numbers = map.get(&seq).unwrap_or_default();
-    if sequence_numbers.contains(seq_children) {
-      return false;
-    }
-    sequence_numbers.push(seq_children);
-    map.insert(seq, sequence_numbers);
-    return true;
+  mem_change(&SEQUENCE_NUMBER_TO_CHILD, checkpoint::Table::SequenceNumberToChild, &(seq, seq_children), |m| {
+    m.insert((seq, seq_children), ()).is_none()
   })
 }
 
-pub(crate) fn mem_get_sequence_number_to_childrens(seq: u32) -> Option<SequenceNumbers> {
-  SEQUENCE_NUMBER_TO_CHILDRENS.with(|m| m.borrow().get(&seq))
+/// Up to `limit` children of `seq` after skipping `skip`, oldest first.
+pub(crate) fn mem_get_sequence_number_to_childrens(seq: u32, skip: usize, limit: usize) -> Vec<u32> {
+  SEQUENCE_NUMBER_TO_CHILD.with(|m| {
+    m.borrow()
+      .range((seq, 0)..=(seq, u32::MAX))
+      .skip(skip)
+      .take(limit)
+      .map(|((_, child), _)| child)
+      .collect()
+  })
+}
+
+pub(crate) fn mem_count_sequence_number_to_childrens(seq: u32) -> u64 {
+  SEQUENCE_NUMBER_TO_CHILD.with(|m| m.borrow().range((seq, 0)..=(seq, u32::MAX)).count() as u64)
 }
 
 pub(crate) fn mem_insert_script_pubkey_to_outpoints(script_pubkey: Vec<u8>, outpoint: OutPoint) -> bool {
-  SCRIPT_PUBKEY_TO_OUTPOINTS.with(|m| {
-    let mut map = m.borrow_mut();
-    let mut outpoints = map.get(&script_pubkey).unwrap_or_default();
-    if outpoints.contains(&outpoint) {
-      return false;
-    }
-    outpoints.push(outpoint);
-    map.insert(script_pubkey, outpoints);
-    return true;
+  let key = ScriptPubkeyOutPoint {
+    script_pubkey,
+    outpoint: outpoint.store(),
+  };
+  mem_change(&SCRIPT_PUBKEY_TO_OUTPOINT, checkpoint::Table::ScriptPubkeyToOutpoint, &key.clone(), |m| {
+    m.insert(key, ()).is_none()
   })
 }
 
 pub(crate) fn mem_remove_script_pubkey_to_outpoints(script_pubkey: Vec<u8>, outpoint: &OutPoint) -> bool {
-  SCRIPT_PUBKEY_TO_OUTPOINTS.with(|m| {
-    let mut map = m.borrow_mut();
-    let mut outpoints = map.get(&script_pubkey).unwrap_or_default();
-    if !outpoints.contains(outpoint) {
-      return false;
-    }
-    outpoints.retain(|&o| o != *outpoint);
-    map.insert(script_pubkey, outpoints);
-    return true;
+  let key = ScriptPubkeyOutPoint {
+    script_pubkey,
+    outpoint: outpoint.store(),
+  };
+  mem_change(&SCRIPT_PUBKEY_TO_OUTPOINT, checkpoint::Table::ScriptPubkeyToOutpoint, &key, |m| {
+    m.remove(&key).is_some()
   })
 }
 
+/// Moves the members of `legacy` into `split` one entry each, for as long as
+/// the migration budget lasts, putting back whatever is left of the list it
+/// stopped in. Returns whether any are left.
+fn mem_split_multimap<K: Storable + Ord + Clone, V: Storable, M, S: Storable + Ord + Clone>(
+  legacy: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMemory>>>,
+  split: &'static LocalKey<RefCell<StableBTreeMap<S, (), VMemory>>>,
+  members: impl Fn(V) -> Vec<M>,
+  list: impl Fn(Vec<M>) -> V,
+  key: impl Fn(&K, M) -> S,
+) -> bool {
+  while migration::within_budget() {
+    let Some((k, v)) = legacy.with(|m| m.borrow_mut().pop_first()) else {
+      return false;
+    };
+    let mut members = members(v).into_iter();
+    split.with(|m| {
+      let mut m = m.borrow_mut();
+      for member in members.by_ref() {
+        m.insert(key(&k, member), ());
+        if !migration::within_budget() {
+          break;
+        }
+      }
+    });
+    let rest: Vec<M> = members.collect();
+    if !rest.is_empty() {
+      legacy.with(|m| m.borrow_mut().insert(k, list(rest)));
+    }
+  }
+  true
+}
 
+/// Moves the entries of `legacy` into `map`, re-encoding them on the way, for
+/// as long as the migration budget lasts. Returns whether any are left.
+fn mem_reencode_map<K: Storable + Ord + Clone, V: Storable>(
+  legacy: &'static LocalKey<RefCell<StableBTreeMap<K, Bincode<V>, VMemory>>>,
+  map: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMemory>>>,
+) -> bool {
+  while migration::within_budget() {
+    let Some((key, Bincode(value))) = legacy.with(|m| m.borrow_mut().pop_first()) else {
+      return false;
+    };
+    map.with(|m| m.borrow_mut().insert(key, value));
+  }
+  true
+}
+
+/// Moves the bincode-encoded maps of schema version 2 into the compact ones.
+/// Returns whether any entries are left.
+pub(crate) fn mem_reencode_legacy_entries() -> bool {
+  mem_reencode_map(&LEGACY_RUNE_ID_TO_RUNE_ENTRY, &RUNE_ID_TO_RUNE_ENTRY)
+    || mem_reencode_map(&LEGACY_SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY, &SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY)
+    || mem_reencode_map(&LEGACY_OUTPOINT_TO_RUNE_BALANCES, &OUTPOINT_TO_RUNE_BALANCES)
+}
+
+/// Moves the Vec-valued multimaps of schema version 1 into the composite-key
+/// ones. Returns whether any members are left.
+pub(crate) fn mem_split_legacy_multimaps() -> bool {
+  mem_split_multimap(
+    &LEGACY_SAT_TO_SEQUENCE_NUMBERS,
+    &SAT_TO_SEQUENCE_NUMBER,
+    |seqs| seqs.0,
+    SequenceNumbers,
+    |sat, seq| (*sat, seq),
+  ) || mem_split_multimap(
+    &LEGACY_SEQUENCE_NUMBER_TO_CHILDRENS,
+    &SEQUENCE_NUMBER_TO_CHILD,
+    |children| children.0,
+    SequenceNumbers,
+    |seq, child| (*seq, child),
+  ) || mem_split_multimap(
+    &LEGACY_SCRIPT_PUBKEY_TO_OUTPOINTS,
+    &SCRIPT_PUBKEY_TO_OUTPOINT,
+    |outpoints| outpoints.outpoints,
+    |outpoints| MyOutPoints { outpoints },
+    |script_pubkey, outpoint| ScriptPubkeyOutPoint {
+      script_pubkey: script_pubkey.clone(),
+      outpoint: outpoint.store(),
+    },
+  )
+}
 
 pub(crate) fn mem_get_sequence_number_to_inscription_entry(seq: u32) -> Option<InscriptionEntry> {
   SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY.with(|m| m.borrow().get(&seq))
 }
 
 pub(crate) fn mem_insert_sequence_number_to_inscription_entry(seq: u32, entry: InscriptionEntry) {
-  SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY.with(|m| m.borrow_mut().insert(seq, entry));
+  mem_change(&SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY, checkpoint::Table::SequenceNumberToInscriptionEntry, &seq, |m| {
+    m.insert(seq, entry)
+  });
+}
+
+pub(crate) fn mem_get_sequence_number_to_inscription_attributes(seq: u32) -> Option<InscriptionAttributes> {
+  SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES.with(|m| m.borrow().get(&seq))
+}
+
+pub(crate) fn mem_insert_sequence_number_to_inscription_attributes(seq: u32, attributes: InscriptionAttributes) {
+  mem_change(&SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES, checkpoint::Table::SequenceNumberToInscriptionAttributes, &seq, |m| {
+    m.insert(seq, attributes)
+  });
+}
+
+/// Inscriptions below this sequence number were indexed before their
+/// attributes were recorded, and still lack them.
+pub(crate) fn mem_inscriptions_without_attributes() -> u32 {
+  SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES
+    .with(|m| m.borrow().first_key_value().map(|(seq, _)| seq))
+    .unwrap_or_else(mem_get_next_sequence_of_sequence_number_to_inscription_entry)
 }
 
 pub(crate) fn mem_get_next_sequence_of_sequence_number_to_inscription_entry()-> u32 {
@@ -411,19 +1148,97 @@ pub(crate) fn mem_get_inscription_id_to_sequence_number(id: &InscriptionId) -> O
 }
 
 pub(crate) fn mem_insert_inscription_id_to_sequence_number(id: InscriptionId, seq: u32) {
-  INSCRIPTION_ID_TO_SEQUENCE_NUMBER.with(|m| m.borrow_mut().insert(id, seq));
+  mem_change(&INSCRIPTION_ID_TO_SEQUENCE_NUMBER, checkpoint::Table::InscriptionIdToSequenceNumber, &id, |m| {
+    m.insert(id, seq)
+  });
 }
 
 pub(crate) fn mem_insert_transaction_id_to_transaction(id: TxidValue, tx: Vec<u8>) {
-  TRANSACTION_ID_TO_TRANSACTION.with(|m| m.borrow_mut().insert(id, tx));
+  mem_change(&TRANSACTION_ID_TO_TRANSACTION, checkpoint::Table::TransactionIdToTransaction, &id, |m| {
+    m.insert(id, tx)
+  });
 }
 
 pub(crate) fn mem_get_transaction_id_to_transaction(id: TxidValue) -> Option<Vec<u8>> {
   TRANSACTION_ID_TO_TRANSACTION.with(|m| m.borrow().get(&id))
 }
 
+fn mem_next_transaction_cache_tick() -> u64 {
+  LAST_USED_TO_CACHED_TRANSACTION_ID.with(|m| m.borrow().iter().next_back().map(|(tick, _)| tick + 1).unwrap_or(0))
+}
+
+/// Looks up a transaction fetched earlier and marks it as most recently used.
+pub(crate) fn mem_get_cached_transaction(txid: Txid) -> Option<CachedTransaction> {
+  let mut cached = TRANSACTION_ID_TO_CACHED_TRANSACTION.with(|m| m.borrow().get(&txid.store()))?;
+
+  let tick = mem_next_transaction_cache_tick();
+  LAST_USED_TO_CACHED_TRANSACTION_ID.with(|m| {
+    let mut m = m.borrow_mut();
+    m.remove(&cached.last_used);
+    m.insert(tick, txid.store());
+  });
+  cached.last_used = tick;
+  TRANSACTION_ID_TO_CACHED_TRANSACTION.with(|m| m.borrow_mut().insert(txid.store(), cached.clone()));
+
+  Some(cached)
+}
+
+/// Caches a fetched transaction, evicting the least recently used ones until
+/// the cache fits in `budget` bytes. Transactions larger than the budget are
+/// not cached.
+pub(crate) fn mem_insert_cached_transaction(txid: Txid, transaction: Vec<u8>, block_hash: Option<BlockHash>, budget: u64) {
+  let size = transaction.len() as u64;
+  if size > budget || TRANSACTION_ID_TO_CACHED_TRANSACTION.with(|m| m.borrow().contains_key(&txid.store())) {
+    return;
+  }
+
+  let mut used = mem_get_statistic_count(Statistic::TransactionCacheBytes);
+  while used + size > budget {
+    let Some((_, evicted)) = LAST_USED_TO_CACHED_TRANSACTION_ID.with(|m| m.borrow_mut().pop_first()) else {
+      break;
+    };
+    if let Some(evicted) = TRANSACTION_ID_TO_CACHED_TRANSACTION.with(|m| m.borrow_mut().remove(&evicted)) {
+      used = used.saturating_sub(evicted.transaction.len() as u64);
+    }
+  }
+
+  let last_used = mem_next_transaction_cache_tick();
+  LAST_USED_TO_CACHED_TRANSACTION_ID.with(|m| m.borrow_mut().insert(last_used, txid.store()));
+  TRANSACTION_ID_TO_CACHED_TRANSACTION.with(|m| m.borrow_mut().insert(txid.store(), CachedTransaction {
+    block_hash,
+    last_used,
+    transaction,
+  }));
+  mem_insert_statistic_to_count(Statistic::TransactionCacheBytes, used + size);
+}
+
+/// Evicts cached transactions confirmed in `blocks`, whose confirmations a
+/// reorg or rollback no longer holds.
+pub(crate) fn mem_evict_cached_transactions_in(blocks: &[BlockHash]) {
+  if blocks.is_empty() {
+    return;
+  }
+
+  let evicted: Vec<(TxidValue, CachedTransaction)> = TRANSACTION_ID_TO_CACHED_TRANSACTION.with(|m| {
+    m.borrow()
+      .iter()
+      .filter(|(_, cached)| cached.block_hash.is_some_and(|block_hash| blocks.contains(&block_hash)))
+      .collect()
+  });
+
+  let mut used = mem_get_statistic_count(Statistic::TransactionCacheBytes);
+  for (txid, cached) in evicted {
+    LAST_USED_TO_CACHED_TRANSACTION_ID.with(|m| m.borrow_mut().remove(&cached.last_used));
+    TRANSACTION_ID_TO_CACHED_TRANSACTION.with(|m| m.borrow_mut().remove(&txid));
+    used = used.saturating_sub(cached.transaction.len() as u64);
+  }
+  mem_insert_statistic_to_count(Statistic::TransactionCacheBytes, used);
+}
+
 pub(crate) fn mem_insert_inscription_number_to_sequence_number(num: &InscriptionNumber, seq: u32) {
-  INSCRIPTION_NUMBER_TO_SEQUENCE_NUMBER.with(|m| m.borrow_mut().insert(num.clone(), seq));
+  mem_change(&INSCRIPTION_NUMBER_TO_SEQUENCE_NUMBER, checkpoint::Table::InscriptionNumberToSequenceNumber, num, |m| {
+    m.insert(num.clone(), seq)
+  });
 }
 
 pub(crate) fn mem_get_inscription_number_to_sequence_number(num: &InscriptionNumber) -> Option<u32> {
@@ -431,17 +1246,22 @@ pub(crate) fn mem_get_inscription_number_to_sequence_number(num: &InscriptionNum
 }
 
 pub(crate) fn mem_insert_home_inscriptions(seq: u32, id: InscriptionId) {
-  HOME_INSCRIPTIONS.with(|m| m.borrow_mut().insert(seq, id));
+  mem_change(&HOME_INSCRIPTIONS, checkpoint::Table::HomeInscriptions, &seq, |m| m.insert(seq, id));
 }
 
 pub(crate) fn mem_pop_first_home_inscriptions() -> Option<(u32,InscriptionId)> {
-  HOME_INSCRIPTIONS.with(|m| m.borrow_mut().pop_first())
+  let seq = HOME_INSCRIPTIONS.with(|m| m.borrow().first_key_value().map(|(seq, _)| seq))?;
+  mem_change(&HOME_INSCRIPTIONS, checkpoint::Table::HomeInscriptions, &seq, |m| m.remove(&seq)).map(|id| (seq, id))
 }
 
 pub(crate) fn mem_get_home_inscriptions_len() -> u64 {
   HOME_INSCRIPTIONS.with(|m| m.borrow().len())
 }
 
+pub(crate) fn mem_latest_home_inscriptions(limit: usize) -> Vec<InscriptionId> {
+  HOME_INSCRIPTIONS.with(|m| m.borrow().iter().rev().take(limit).map(|(_, id)| id).collect())
+}
+
 // pub(crate) fn mem_insert_height_to_events(height: u32, events: Events) {
 //   HEIGHT_TO_EVENTS.with(|m| m.borrow_mut().insert(height, events));
 // }
@@ -450,15 +1270,24 @@ pub(crate) fn mem_get_home_inscriptions_len() -> u64 {
 //   HEIGHT_TO_EVENTS.with(|m| m.borrow_mut().remove(&height));
 // }
 
+/// The transaction cache isn't part of checkpoints, so changes to its size
+/// aren't undone.
+fn mem_change_statistic(statistic: Statistic, change: impl FnOnce(&mut StableBTreeMap<u64, u64, VMemory>) -> Option<u64>) {
+  if matches!(statistic, Statistic::TransactionCacheBytes) {
+    STATISTIC_TO_COUNT.with(|m| change(&mut m.borrow_mut()));
+  } else {
+    mem_change(&STATISTIC_TO_COUNT, checkpoint::Table::StatisticToCount, &statistic.key(), change);
+  }
+}
+
 pub(crate) fn mem_insert_statistic_to_count(statistic: Statistic, count: u64) {
-  STATISTIC_TO_COUNT.with(|m| m.borrow_mut().insert(statistic.key(), count));
+  mem_change_statistic(statistic, |m| m.insert(statistic.key(), count));
 }
 
 pub(crate) fn mem_increment_statistic(statistic: Statistic, n:u64) {
-  STATISTIC_TO_COUNT.with(|m| {
-    let mut map = m.borrow_mut();
-    let count = map.get(&statistic.key()).unwrap_or_default();
-    map.insert(statistic.key(), count + n);
+  mem_change_statistic(statistic, |m| {
+    let count = m.get(&statistic.key()).unwrap_or_default();
+    m.insert(statistic.key(), count + n)
   });
 }
 
@@ -467,7 +1296,7 @@ pub(crate) fn mem_get_statistic_count(statistic: Statistic) -> u64 {
 }
 
 pub(crate) fn mem_remove_outpoint_to_utxo_entry(outpoint: OutPointValue) -> Option<UtxoEntry> {
-  OUTPOINT_TO_UTXO_ENTRY.with(|m| m.borrow_mut().remove(&outpoint))
+  mem_change(&OUTPOINT_TO_UTXO_ENTRY, checkpoint::Table::OutpointToUtxoEntry, &outpoint, |m| m.remove(&outpoint))
 }
 
 pub(crate) fn mem_get_outpoint_to_utxo_entry(outpoint: OutPointValue) -> Option<UtxoEntry> {
@@ -475,15 +1304,19 @@ pub(crate) fn mem_get_outpoint_to_utxo_entry(outpoint: OutPointValue) -> Option<
 }
 
 pub(crate) fn mem_insert_outpoint_to_utxo_entry(outpoint: OutPointValue, utxo_entry: UtxoEntry) {
-  OUTPOINT_TO_UTXO_ENTRY.with(|m| m.borrow_mut().insert(outpoint, utxo_entry));
+  mem_change(&OUTPOINT_TO_UTXO_ENTRY, checkpoint::Table::OutpointToUtxoEntry, &outpoint, |m| {
+    m.insert(outpoint, utxo_entry)
+  });
 }
 
 pub(crate) fn mem_insert_sat_to_satpoint(sat: u64, satpoint: SatPointValue) {
-  SAT_TO_SATPOINT.with(|m| m.borrow_mut().insert(sat, satpoint));
+  mem_change(&SAT_TO_SATPOINT, checkpoint::Table::SatToSatpoint, &sat, |m| m.insert(sat, satpoint));
 }
 
 pub(crate) fn mem_insert_height_to_last_sequence_number(height: u32, seq: u32) {
-  HEIGHT_TO_LAST_SEQUENCE_NUMBER.with(|m| m.borrow_mut().insert(height, seq));
+  mem_change(&HEIGHT_TO_LAST_SEQUENCE_NUMBER, checkpoint::Table::HeightToLastSequenceNumber, &height, |m| {
+    m.insert(height, seq)
+  });
 }
 
 pub(crate) fn mem_get_height_to_last_sequence_number(height: u32) -> Option<u32> {
@@ -491,13 +1324,33 @@ pub(crate) fn mem_get_height_to_last_sequence_number(height: u32) -> Option<u32>
 }
 
 pub(crate) fn mem_insert_sequence_number_to_satpoint(seq: u32, satpoint: SatPointValue) {
-  SEQUENCE_NUMBER_TO_SATPOINT.with(|m| m.borrow_mut().insert(seq, satpoint));
+  mem_change(&SEQUENCE_NUMBER_TO_SATPOINT, checkpoint::Table::SequenceNumberToSatpoint, &seq, |m| {
+    m.insert(seq, satpoint)
+  });
+  if certification::enabled() {
+    if let Some(entry) = mem_get_sequence_number_to_inscription_entry(seq) {
+      certification::update_inscription_satpoint(&entry.id, &SatPoint::load(satpoint));
+    }
+  }
 }
 
 pub(crate) fn mem_get_sequence_number_to_satpoint(seq: u32) -> Option<SatPointValue> {
   SEQUENCE_NUMBER_TO_SATPOINT.with(|m| m.borrow().get(&seq))
 }
 
+/// Records the script pubkey of the output an inscription moved to, or forgets
+/// it when the output isn't known, like the lost and unbound outpoints.
+pub(crate) fn mem_set_sequence_number_to_script_pubkey(seq: u32, script_pubkey: Option<&[u8]>) {
+  mem_change(&SEQUENCE_NUMBER_TO_SCRIPT_PUBKEY, checkpoint::Table::SequenceNumberToScriptPubkey, &seq, |m| match script_pubkey {
+    Some(script_pubkey) => m.insert(seq, script_pubkey.to_vec()),
+    None => m.remove(&seq),
+  });
+}
+
+pub(crate) fn mem_get_sequence_number_to_script_pubkey(seq: u32) -> Option<Vec<u8>> {
+  SEQUENCE_NUMBER_TO_SCRIPT_PUBKEY.with(|m| m.borrow().get(&seq))
+}
+
 pub(crate) fn mem_get_sequence_number_to_rune_id(seq: u32) -> Option<RuneIdValue> {
   SEQUENCE_NUMBER_TO_RUNE_ID.with(|m| m.borrow().get(&seq))
 }
@@ -514,15 +1367,135 @@ pub(crate) fn mem_insert_outpoint_to_rune_balances(
   outpoint_value: OutPointValue,
   rune_balances: RuneBalances,
 ) {
+  certification::update_rune_balances(outpoint_value, Some(&rune_balances));
   OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow_mut().insert(outpoint_value, rune_balances));
 }
 
 pub(crate) fn mem_remove_outpoint_to_rune_balances(
   outpoint_value: OutPointValue,
 ) -> Option<RuneBalances> {
+  certification::update_rune_balances(outpoint_value, None);
   OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow_mut().remove(&outpoint_value))
 }
 
+/// Balances of up to `limit` outpoints after `after`, for rebuilding the
+/// certified tree.
+pub(crate) fn mem_next_certified_rune_balances(
+  after: Option<OutPointValue>,
+  limit: usize,
+) -> Vec<(OutPointValue, RuneBalances)> {
+  OUTPOINT_TO_RUNE_BALANCES.with(|m| {
+    let m = m.borrow();
+    let entries = match after {
+      Some(outpoint) => m.range((Bound::Excluded(outpoint), Bound::Unbounded)),
+      None => m.range(..),
+    };
+    entries.take(limit).collect()
+  })
+}
+
+/// Locations of up to `limit` inscriptions after sequence number `after`, for
+/// rebuilding the certified tree.
+pub(crate) fn mem_next_certified_inscriptions(
+  after: Option<u32>,
+  limit: usize,
+) -> Vec<(u32, InscriptionId, SatPoint)> {
+  SEQUENCE_NUMBER_TO_SATPOINT.with(|m| {
+    let m = m.borrow();
+    let entries = match after {
+      Some(seq) => m.range((Bound::Excluded(seq), Bound::Unbounded)),
+      None => m.range(..),
+    };
+    entries
+      .take(limit)
+      .filter_map(|(seq, satpoint)| {
+        let entry = mem_get_sequence_number_to_inscription_entry(seq)?;
+        Some((seq, entry.id, SatPoint::load(satpoint)))
+      })
+      .collect()
+  })
+}
+
+fn mem_certified_tree(
+  tree: certification::Tree,
+) -> &'static LocalKey<RefCell<StableBTreeMap<CertifiedNodeId, CertifiedNode, VMemory>>> {
+  match tree {
+    certification::Tree::RuneBalances => &CERTIFIED_RUNE_BALANCES,
+    certification::Tree::InscriptionSatpoints => &CERTIFIED_INSCRIPTION_SATPOINTS,
+  }
+}
+
+pub(crate) fn mem_get_certified_node(tree: certification::Tree, id: &CertifiedNodeId) -> Option<CertifiedNode> {
+  mem_certified_tree(tree).with(|m| m.borrow().get(id))
+}
+
+pub(crate) fn mem_insert_certified_node(tree: certification::Tree, id: CertifiedNodeId, node: CertifiedNode) {
+  mem_certified_tree(tree).with(|m| m.borrow_mut().insert(id, node));
+}
+
+pub(crate) fn mem_remove_certified_node(tree: certification::Tree, id: &CertifiedNodeId) {
+  mem_certified_tree(tree).with(|m| m.borrow_mut().remove(id));
+}
+
+/// The root of `tree`, the node with the shortest prefix.
+pub(crate) fn mem_certified_root(tree: certification::Tree) -> Option<(CertifiedNodeId, CertifiedNode)> {
+  mem_certified_tree(tree).with(|m| m.borrow().first_key_value())
+}
+
+/// The leaves of `tree` right before and right after `id`.
+pub(crate) fn mem_certified_neighbours(
+  tree: certification::Tree,
+  id: &CertifiedNodeId,
+) -> (Option<CertifiedNodeId>, Option<CertifiedNodeId>) {
+  mem_certified_tree(tree).with(|m| {
+    let m = m.borrow();
+    let before = m.range(..*id).next_back().map(|(id, _)| id).filter(CertifiedNodeId::is_leaf);
+    let after = m.range((Bound::Excluded(*id), Bound::Unbounded)).next().map(|(id, _)| id);
+    (before, after)
+  })
+}
+
+/// Empties `tree` in constant time. Its dirty nodes are left behind and
+/// skipped when rehashing.
+pub(crate) fn mem_reset_certified_tree(tree: certification::Tree) {
+  match tree {
+    certification::Tree::RuneBalances => mem_reset_map(&CERTIFIED_RUNE_BALANCES, CERTIFIED_RUNE_BALANCES_MEMORY_ID),
+    certification::Tree::InscriptionSatpoints => {
+      mem_reset_map(&CERTIFIED_INSCRIPTION_SATPOINTS, CERTIFIED_INSCRIPTION_SATPOINTS_MEMORY_ID)
+    }
+  }
+}
+
+pub(crate) fn mem_mark_certified_dirty(tree: certification::Tree, id: CertifiedNodeId) {
+  CERTIFIED_DIRTY.with(|m| m.borrow_mut().insert((tree as u8, id), ()));
+}
+
+/// The dirty node with the longest prefix, which has no dirty nodes below it.
+pub(crate) fn mem_pop_certified_dirty() -> Option<(certification::Tree, CertifiedNodeId)> {
+  CERTIFIED_DIRTY.with(|m| {
+    let ((tree, id), ()) = m.borrow_mut().pop_last()?;
+    Some((certification::Tree::from(tree), id))
+  })
+}
+
+pub(crate) fn mem_certified_is_dirty() -> bool {
+  CERTIFIED_DIRTY.with(|m| !m.borrow().is_empty())
+}
+
+pub(crate) fn mem_reset_certified_dirty() {
+  mem_reset_map(&CERTIFIED_DIRTY, CERTIFIED_DIRTY_MEMORY_ID);
+}
+
+pub(crate) fn mem_get_certified_rebuild() -> CertifiedRebuild {
+  CERTIFIED_REBUILD.with(|m| m.borrow().get().clone())
+}
+
+pub(crate) fn mem_set_certified_rebuild(rebuild: CertifiedRebuild) -> Result<CertifiedRebuild> {
+  CERTIFIED_REBUILD
+    .with(|m| m.borrow_mut().set(rebuild))
+    .map_err(|e| anyhow::anyhow!("Failed to set certified rebuild: {:?}", e))
+}
+
 pub(crate) fn mem_length_outpoint_to_height() -> u64 {
   OUTPOINT_TO_HEIGHT.with(|m| m.borrow().len())
 }
@@ -685,11 +1658,16 @@ pub fn mem_prune_statistic_reserved_runes(height: u32) {
 }
 
 /// helper function
-pub(crate) async fn inscription_info(
+/// Answers from indexed state only, without fetching any transactions.
+///
+/// The address of the output holding the inscription is taken from the UTXO
+/// entry when addresses are indexed, or from the stored transaction when
+/// transactions are, and is `None` otherwise.
+pub(crate) fn inscription_info(
   index: &Index,
   query: InscriptionQuery,
   child: Option<usize>,
-) -> Result<Option<(InscriptionResp, Option<TxOut>, Inscription)>> {
+) -> Result<Option<InscriptionResp>> {
   let sequence_number = match query {
     InscriptionQuery::Id(id) => mem_get_inscription_id_to_sequence_number(&id),
     InscriptionQuery::Number(inscription_number) => mem_get_inscription_number_to_sequence_number(&InscriptionNumber::from(inscription_number)),
@@ -701,7 +1679,7 @@ pub(crate) async fn inscription_info(
   };
 
   let sequence_number = if let Some(child) = child {
-    let Some(child) = mem_get_sequence_number_to_childrens(sequence_number).iter().nth(child).map(|seqs| seqs.0[0])
+    let Some(child) = mem_get_sequence_number_to_childrens(sequence_number, child, 1).first().copied()
     else {
       return Ok(None);
     };
@@ -713,35 +1691,20 @@ pub(crate) async fn inscription_info(
 
   let entry = mem_get_sequence_number_to_inscription_entry(sequence_number).unwrap();
 
-  let Some(transaction) = get_transaction(index,entry.id.txid).await? else {
-    return Ok(None);
-  };
-
-  let Some(inscription) = ParsedEnvelope::from_transaction(&transaction)
-    .into_iter()
-    .nth(entry.id.index as usize)
-    .map(|envelope| envelope.payload)
-  else {
-    return Ok(None);
-  };
+  // inscriptions whose attributes are still being backfilled are served
+  // without them
+  let attributes = mem_get_sequence_number_to_inscription_attributes(sequence_number).unwrap_or_default();
 
   let satpoint = SatPoint::load(
     mem_get_sequence_number_to_satpoint(sequence_number)
       .unwrap()
   );
 
-  let output = if satpoint.outpoint == unbound_outpoint() || satpoint.outpoint == OutPoint::null()
+  let (address, value) = if satpoint.outpoint == unbound_outpoint() || satpoint.outpoint == OutPoint::null()
   {
-    None
+    (None, None)
   } else {
-    let Some(transaction) = get_transaction(index, satpoint.outpoint.txid).await? else {
-      return Ok(None);
-    };
-
-    transaction
-      .output
-      .into_iter()
-      .nth(satpoint.outpoint.vout.try_into().unwrap())
+    output_address_and_value(index, sequence_number, satpoint.outpoint)?
   };
 
   let previous = if let Some(n) = sequence_number.checked_sub(1) {
@@ -757,14 +1720,12 @@ pub(crate) async fn inscription_info(
   let next = mem_get_sequence_number_to_inscription_entry(sequence_number + 1)
     .map(|entry| entry.id);
 
-  let all_children = mem_get_sequence_number_to_childrens(sequence_number).unwrap_or_default();
-
-  let child_count = all_children.len() as u64;
+  let child_count = mem_count_sequence_number_to_childrens(sequence_number);
 
-  let children = all_children.0.iter()
-    .take(4)
+  let children = mem_get_sequence_number_to_childrens(sequence_number, 0, 4)
+    .into_iter()
     .map(|sequence_number| {
-      let entry = mem_get_sequence_number_to_inscription_entry(*sequence_number).unwrap();
+      let entry = mem_get_sequence_number_to_inscription_entry(sequence_number).unwrap();
       Ok(entry.id)
     })
     .collect::<Result<Vec<InscriptionId>>>()?;
@@ -796,51 +1757,69 @@ pub(crate) async fn inscription_info(
     Charm::Lost.set(&mut charms);
   }
 
-  let effective_mime_type = if let Some(delegate_id) = inscription.delegate() {
-    let delegate_result = get_inscription_by_id(index, delegate_id).await;
-    if let Ok(Some(delegate)) = delegate_result {
-      delegate.content_type().map(str::to_string)
-    } else {
-      inscription.content_type().map(str::to_string)
-    }
+  let effective_content_type = attributes
+    .delegate
+    .and_then(|delegate| mem_get_inscription_id_to_sequence_number(&delegate))
+    .and_then(mem_get_sequence_number_to_inscription_attributes)
+    .map(|delegate| delegate.content_type)
+    .unwrap_or_else(|| attributes.content_type.clone());
+
+  Ok(Some(InscriptionResp {
+    address,
+    charms: Charm::charms(charms),
+    child_count,
+    children,
+    content_length: attributes.content_length.map(|length| length as usize),
+    content_type: attributes.content_type,
+    effective_content_type,
+    fee: entry.fee,
+    height: entry.height,
+    id: entry.id,
+    next,
+    number: entry.inscription_number,
+    parents,
+    previous,
+    rune,
+    sat: entry.sat,
+    satpoint,
+    timestamp: timestamp(entry.timestamp.into()).timestamp(),
+    value,
+    metaprotocol: attributes.metaprotocol,
+  }))
+}
+
+/// The address comes from the script pubkey recorded when the inscription last
+/// moved. Inscriptions that haven't moved since it was first recorded fall back
+/// to the address or transaction index.
+fn output_address_and_value(
+  index: &Index,
+  sequence_number: u32,
+  outpoint: OutPoint,
+) -> Result<(Option<String>, Option<u64>)> {
+  let utxo_entry = mem_get_outpoint_to_utxo_entry(outpoint.store());
+  let parsed = utxo_entry.as_ref().map(|utxo_entry| utxo_entry.parse(index));
+
+  let script_pubkey = if let Some(script_pubkey) = mem_get_sequence_number_to_script_pubkey(sequence_number) {
+    Some(ScriptBuf::from_bytes(script_pubkey))
+  } else if index.index_addresses {
+    parsed
+      .as_ref()
+      .map(|parsed| ScriptBuf::from_bytes(parsed.script_pubkey().to_vec()))
+  } else if index.index_transactions {
+    mem_get_transaction_id_to_transaction(outpoint.txid.store())
+      .map(|transaction| consensus::encode::deserialize::<Transaction>(&transaction))
+      .transpose()?
+      .and_then(|transaction| transaction.output.into_iter().nth(outpoint.vout.into_usize()))
+      .map(|output| output.script_pubkey)
   } else {
-    inscription.content_type().map(str::to_string)
+    None
   };
 
-  Ok(Some((
-    InscriptionResp {
-      address: output
-        .as_ref()
-        .and_then(|o| {
-          index
-            .chain()
-            .address_from_script(&o.script_pubkey)
-            .ok()
-        })
-        .map(|address| address.to_string()),
-      charms: Charm::charms(charms),
-      child_count,
-      children,
-      content_length: inscription.content_length(),
-      content_type: inscription.content_type().map(|s| s.to_string()),
-      effective_content_type: effective_mime_type,
-      fee: entry.fee,
-      height: entry.height,
-      id: entry.id,
-      next,
-      number: entry.inscription_number,
-      parents,
-      previous,
-      rune,
-      sat: entry.sat,
-      satpoint,
-      timestamp: timestamp(entry.timestamp.into()).timestamp(),
-      value: output.as_ref().map(|o| o.value.to_sat()),
-      metaprotocol: inscription.metaprotocol().map(|s| s.to_string()),
-    },
-    output,
-    inscription,
-  )))
+  let address = script_pubkey
+    .and_then(|script_pubkey| index.chain().address_from_script(&script_pubkey).ok())
+    .map(|address| address.to_string());
+
+  Ok((address, parsed.map(|parsed| parsed.total_value())))
 }
 
 pub fn get_inscriptions_for_output(
@@ -889,61 +1868,138 @@ pub fn inscriptions_on_output(
     .map(Some)
 }
 
-pub async fn get_transaction(index: &Index, txid: Txid) -> Result<Option<Transaction>> {
-  if txid == index.genesis_block_coinbase_txid {
-    return Ok(Some(index.genesis_block_coinbase_transaction.clone()));
+pub(crate) struct OutputAssets {
+  pub(crate) inscriptions: bool,
+  pub(crate) runes: bool,
+  pub(crate) rare_sats: Option<bool>,
+}
+
+/// Reports what an output carries without resolving inscription ids or rune
+/// entries, so callers can cheaply tell cardinal from ordinal outputs.
+///
+/// Returns `None` for an outpoint neither the UTXO entries nor the rune
+/// balances know, since an unindexed or spent output can't be vouched for.
+/// `rare_sats` is `None` when the sat index is disabled. Sat ranges are split
+/// at block boundaries, so only the first sat of a range can be uncommon.
+pub(crate) fn output_assets(index: &Index, outpoint: OutPoint) -> Option<OutputAssets> {
+  let rune_balances = mem_get_outpoint_to_rune_balances(outpoint.store());
+  let utxo_entry = mem_get_outpoint_to_utxo_entry(outpoint.store());
+  if rune_balances.is_none() && utxo_entry.is_none() {
+    return None;
   }
 
-  if index.index_transactions {
-    if let Some(transaction) = mem_get_transaction_id_to_transaction(txid.store())
-    {
-      return Ok(Some(consensus::encode::deserialize(transaction.as_ref())?));
-    }
-  }
-  // if considered a ddos attack, we can return None here
-  let tx = crate::rpc::get_raw_transaction_info(&txid, None).await?.transaction()?;
-  Ok(Some(tx))
-}
+  let runes = index.index_runes
+    && rune_balances
+      .map(|rune_balances| !rune_balances.balances.is_empty())
+      .unwrap_or_default();
+
+  let parsed = utxo_entry.as_ref().map(|utxo_entry| utxo_entry.parse(index));
+
+  let inscriptions = index.index_inscriptions
+    && parsed
+      .as_ref()
+      .map(|parsed| !parsed.inscriptions().is_empty())
+      .unwrap_or_default();
+
+  let rare_sats = index.index_sats.then(|| {
+    parsed
+      .as_ref()
+      .map(|parsed| {
+        parsed
+          .sat_ranges()
+          .chunks_exact(11)
+          .any(|chunk| !Sat(SatRange::load(chunk.try_into().unwrap()).0).common())
+      })
+      .unwrap_or_default()
+  });
 
-pub fn inscription_exists(inscription_id: InscriptionId) -> Result<bool> {
-  Ok(
-    mem_get_inscription_id_to_sequence_number(&inscription_id)
-      .is_some(),
-  )
+  Some(OutputAssets {
+    inscriptions,
+    runes,
+    rare_sats,
+  })
 }
 
-pub async fn get_inscription_by_id(
-  index: &Index,
-  inscription_id: InscriptionId,
-) -> Result<Option<Inscription>> {
-  if !inscription_exists(inscription_id)? {
-    return Ok(None);
+/// Fetches a transaction and the hash of the block it was mined in, going
+/// through the LRU transaction cache before making an HTTPS outcall. Only
+/// confirmed transactions are cached.
+pub(crate) async fn fetch_transaction(txid: Txid) -> Result<(Transaction, Option<BlockHash>)> {
+  if let Some(cached) = mem_get_cached_transaction(txid) {
+    return Ok((consensus::encode::deserialize(&cached.transaction)?, cached.block_hash));
   }
 
-  let Some(transaction) = get_transaction(index, inscription_id.txid).await? else {
-    return Ok(None);
+  let raw = crate::block_source::get().raw_transaction(txid).await?;
+  let transaction = consensus::encode::deserialize(&raw.bytes)?;
+
+  if raw.block_hash.is_some() {
+    mem_insert_cached_transaction(
+      txid,
+      raw.bytes,
+      raw.block_hash,
+      mem_get_config().get_transaction_cache_bytes(),
+    );
+  }
+
+  Ok((transaction, raw.block_hash))
+}
+
+/// Walks up to `limit` inscriptions with sequence numbers in `range`, starting
+/// at `from` if given, and returns them with the sequence number to resume at.
+pub(crate) fn inscriptions_page(
+  range: Range<u32>,
+  from: Option<u32>,
+  limit: usize,
+  descending: bool,
+) -> (Vec<InscriptionId>, Option<u32>) {
+  let (start, end) = if descending {
+    let end = from
+      .map(|from| from.saturating_add(1))
+      .unwrap_or(range.end)
+      .min(range.end);
+    (range.start, end)
+  } else {
+    (from.unwrap_or(range.start).max(range.start), range.end)
   };
 
-  Ok(Some(
-    ParsedEnvelope::from_transaction(&transaction)
-      .into_iter()
-      .nth(inscription_id.index as usize)
-      .map(|envelope| envelope.payload)
-      .unwrap(),
-  ))
-}
+  if start >= end {
+    return (Vec::new(), None);
+  }
 
+  SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY.with(|m| {
+    let m = m.borrow();
 
-pub fn get_inscriptions_in_block(block_height: u32) -> Result<Vec<InscriptionId>> {
-  let Some(newest_sequence_number) = mem_get_height_to_last_sequence_number(block_height)
-  else {
-    return Ok(Vec::new());
+    let entries: Box<dyn Iterator<Item = (u32, InscriptionEntry)>> = if descending {
+      Box::new(m.range(start..end).rev())
+    } else {
+      Box::new(m.range(start..end))
+    };
+
+    let mut entries = entries.take(limit + 1).collect::<Vec<(u32, InscriptionEntry)>>();
+
+    let next = if entries.len() > limit {
+      entries.pop().map(|(sequence_number, _)| sequence_number)
+    } else {
+      None
+    };
+
+    (entries.into_iter().map(|(_, entry)| entry.id).collect(), next)
+  })
+}
+
+/// The range of sequence numbers of inscriptions created at `block_height`.
+pub(crate) fn block_sequence_numbers(block_height: u32) -> Range<u32> {
+  let Some(newest_sequence_number) = mem_get_height_to_last_sequence_number(block_height) else {
+    return 0..0;
   };
 
   let oldest_sequence_number = mem_get_height_to_last_sequence_number(block_height.saturating_sub(1))
     .unwrap_or(0);
 
-  (oldest_sequence_number..newest_sequence_number)
+  oldest_sequence_number..newest_sequence_number
+}
+
+pub fn get_inscriptions_in_block(block_height: u32) -> Result<Vec<InscriptionId>> {
+  block_sequence_numbers(block_height)
     .map(|num| match mem_get_sequence_number_to_inscription_entry(num) {
       Some(inscription_entry) => Ok(inscription_entry.id),
       None => Err(anyhow!(
@@ -963,3 +2019,258 @@ pub fn mem_get_etching(txid: Txid) -> Option<(RuneId, RuneEntry)> {
       })
   })
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    set_instruction_counter,
+    test::{address, insert_inscription, inscription_id, outpoint, tx_in, tx_out, txid},
+  };
+  use utxo_entry::UtxoEntryBuf;
+
+  fn keys<K: Storable + Ord + Clone, V: Storable>(
+    map: &'static LocalKey<RefCell<StableBTreeMap<K, V, VMemory>>>,
+  ) -> Vec<K> {
+    map.with(|m| m.borrow().iter().map(|(key, _)| key).collect())
+  }
+
+  fn insert_inscriptions(n: u32) {
+    for sequence_number in 0..n {
+      insert_inscription(sequence_number, inscription_id(sequence_number));
+    }
+  }
+
+  #[test]
+  fn legacy_multimaps_are_split() {
+    let script_pubkey = address(0).script_pubkey().into_bytes();
+    LEGACY_SAT_TO_SEQUENCE_NUMBERS.with(|m| {
+      let mut m = m.borrow_mut();
+      m.insert(1, SequenceNumbers(vec![3, 4]));
+      m.insert(2, SequenceNumbers(vec![5]));
+    });
+    LEGACY_SEQUENCE_NUMBER_TO_CHILDRENS.with(|m| m.borrow_mut().insert(3, SequenceNumbers(vec![8, 7])));
+    LEGACY_SCRIPT_PUBKEY_TO_OUTPOINTS.with(|m| {
+      m.borrow_mut().insert(
+        script_pubkey.clone(),
+        MyOutPoints {
+          outpoints: vec![outpoint(1), outpoint(2)],
+        },
+      )
+    });
+
+    set_instruction_counter(0);
+    assert!(!mem_split_legacy_multimaps());
+
+    assert_eq!(keys(&SAT_TO_SEQUENCE_NUMBER), [(1, 3), (1, 4), (2, 5)]);
+    assert_eq!(keys(&SEQUENCE_NUMBER_TO_CHILD), [(3, 7), (3, 8)]);
+    assert_eq!(
+      keys(&SCRIPT_PUBKEY_TO_OUTPOINT),
+      [outpoint(1), outpoint(2)].map(|outpoint| ScriptPubkeyOutPoint {
+        script_pubkey: script_pubkey.clone(),
+        outpoint: outpoint.store(),
+      })
+    );
+    assert_eq!(LEGACY_SAT_TO_SEQUENCE_NUMBERS.with(|m| m.borrow().len()), 0);
+    assert_eq!(LEGACY_SEQUENCE_NUMBER_TO_CHILDRENS.with(|m| m.borrow().len()), 0);
+    assert_eq!(LEGACY_SCRIPT_PUBKEY_TO_OUTPOINTS.with(|m| m.borrow().len()), 0);
+  }
+
+  #[test]
+  fn list_cut_by_the_budget_is_put_back() {
+    LEGACY_SAT_TO_SEQUENCE_NUMBERS.with(|m| m.borrow_mut().insert(1, SequenceNumbers(vec![3, 4, 5])));
+
+    // enough budget for two members
+    set_instruction_counter(migration::INSTRUCTIONS_PER_MESSAGE - 2_000_000);
+    assert!(mem_split_legacy_multimaps());
+    assert_eq!(keys(&SAT_TO_SEQUENCE_NUMBER), [(1, 3), (1, 4)]);
+    assert_eq!(
+      LEGACY_SAT_TO_SEQUENCE_NUMBERS.with(|m| m.borrow().get(&1)).map(|seqs| seqs.0),
+      Some(vec![5])
+    );
+
+    set_instruction_counter(0);
+    assert!(!mem_split_legacy_multimaps());
+    assert_eq!(keys(&SAT_TO_SEQUENCE_NUMBER), [(1, 3), (1, 4), (1, 5)]);
+    assert_eq!(LEGACY_SAT_TO_SEQUENCE_NUMBERS.with(|m| m.borrow().len()), 0);
+  }
+
+  #[test]
+  fn legacy_entries_are_reencoded() {
+    let rune_entry = RuneEntry {
+      block: 840_000,
+      mints: 7,
+      premine: 1_000,
+      ..Default::default()
+    };
+    let inscription_entry = entry::InscriptionEntry {
+      charms: 0,
+      fee: 100,
+      height: 840_001,
+      id: inscription_id(1),
+      inscription_number: -3,
+      parents: vec![2],
+      sat: None,
+      sequence_number: 1,
+      timestamp: 1_713_571_767,
+    };
+    let rune_balances = RuneBalances {
+      balances: vec![entry::RuneBalance {
+        rune_id: RuneId { block: 840_000, tx: 1 },
+        balance: u128::MAX,
+      }],
+    };
+    LEGACY_RUNE_ID_TO_RUNE_ENTRY.with(|m| {
+      let mut m = m.borrow_mut();
+      m.insert(RuneId { block: 840_000, tx: 1 }.store(), Bincode(rune_entry));
+      m.insert(RuneId { block: 840_000, tx: 2 }.store(), Bincode(rune_entry));
+    });
+    LEGACY_SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY.with(|m| m.borrow_mut().insert(1, Bincode(inscription_entry.clone())));
+    LEGACY_OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow_mut().insert(outpoint(1).store(), Bincode(rune_balances.clone())));
+
+    // enough budget for one entry
+    set_instruction_counter(migration::INSTRUCTIONS_PER_MESSAGE - 1_000_000);
+    assert!(mem_reencode_legacy_entries());
+    assert_eq!(keys(&RUNE_ID_TO_RUNE_ENTRY).len(), 1);
+    assert_eq!(LEGACY_RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow().len()), 1);
+
+    set_instruction_counter(0);
+    assert!(!mem_reencode_legacy_entries());
+    for tx in [1, 2] {
+      assert_eq!(mem_get_rune_id_to_rune_entry(RuneId { block: 840_000, tx }.store()), Some(rune_entry));
+    }
+    assert_eq!(mem_get_sequence_number_to_inscription_entry(1), Some(inscription_entry));
+    assert_eq!(
+      mem_get_outpoint_to_rune_balances(outpoint(1).store()).map(|balances| balances.to_bytes().into_owned()),
+      Some(rune_balances.to_bytes().into_owned())
+    );
+    assert_eq!(LEGACY_RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow().len()), 0);
+    assert_eq!(LEGACY_SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY.with(|m| m.borrow().len()), 0);
+    assert_eq!(LEGACY_OUTPOINT_TO_RUNE_BALANCES.with(|m| m.borrow().len()), 0);
+  }
+
+  #[test]
+  fn multimap_members_are_ranges_of_their_key() {
+    for (sat, seq) in [(1, 9), (2, 4), (2, 3), (3, 0)] {
+      assert!(mem_insert_sat_to_sequence_numbers(sat, seq));
+    }
+    assert!(!mem_insert_sat_to_sequence_numbers(2, 3));
+    assert_eq!(mem_get_first_seq_of_sat_to_sequence_numbers(2), Some(3));
+    assert_eq!(mem_get_first_seq_of_sat_to_sequence_numbers(4), None);
+
+    for child in [5, 2, 7] {
+      assert!(mem_insert_sequence_number_to_childrens(1, child));
+    }
+    mem_insert_sequence_number_to_childrens(0, 1);
+    mem_insert_sequence_number_to_childrens(2, 0);
+    assert_eq!(mem_get_sequence_number_to_childrens(1, 0, 10), [2, 5, 7]);
+    assert_eq!(mem_get_sequence_number_to_childrens(1, 1, 1), [5]);
+    assert_eq!(mem_count_sequence_number_to_childrens(1), 3);
+    assert_eq!(mem_count_sequence_number_to_childrens(3), 0);
+
+    let script_pubkey = address(0).script_pubkey().into_bytes();
+    assert!(mem_insert_script_pubkey_to_outpoints(script_pubkey.clone(), outpoint(1)));
+    assert!(mem_insert_script_pubkey_to_outpoints(script_pubkey.clone(), outpoint(2)));
+    assert!(mem_remove_script_pubkey_to_outpoints(script_pubkey.clone(), &outpoint(1)));
+    assert!(!mem_remove_script_pubkey_to_outpoints(script_pubkey.clone(), &outpoint(1)));
+    assert_eq!(
+      keys(&SCRIPT_PUBKEY_TO_OUTPOINT),
+      [ScriptPubkeyOutPoint {
+        script_pubkey,
+        outpoint: outpoint(2).store(),
+      }]
+    );
+  }
+
+  #[test]
+  fn inscriptions_page_ascending() {
+    insert_inscriptions(5);
+
+    assert_eq!(
+      inscriptions_page(0..u32::MAX, None, 2, false),
+      (vec![inscription_id(0), inscription_id(1)], Some(2))
+    );
+    assert_eq!(
+      inscriptions_page(0..u32::MAX, Some(4), 2, false),
+      (vec![inscription_id(4)], None)
+    );
+    assert_eq!(
+      inscriptions_page(1..3, None, 5, false),
+      (vec![inscription_id(1), inscription_id(2)], None)
+    );
+  }
+
+  #[test]
+  fn inscriptions_page_descending() {
+    insert_inscriptions(5);
+
+    assert_eq!(
+      inscriptions_page(0..u32::MAX, None, 2, true),
+      (vec![inscription_id(4), inscription_id(3)], Some(2))
+    );
+    assert_eq!(
+      inscriptions_page(0..u32::MAX, Some(0), 2, true),
+      (vec![inscription_id(0)], None)
+    );
+    assert_eq!(inscriptions_page(2..4, Some(1), 2, true), (Vec::new(), None));
+  }
+
+  #[test]
+  fn transaction_cache_evicts_least_recently_used() {
+    mem_insert_cached_transaction(txid(1), vec![0; 40], None, 100);
+    mem_insert_cached_transaction(txid(2), vec![0; 40], None, 100);
+    assert!(mem_get_cached_transaction(txid(1)).is_some());
+
+    mem_insert_cached_transaction(txid(3), vec![0; 40], None, 100);
+
+    assert!(mem_get_cached_transaction(txid(1)).is_some());
+    assert!(mem_get_cached_transaction(txid(2)).is_none());
+    assert!(mem_get_cached_transaction(txid(3)).is_some());
+    assert_eq!(mem_get_statistic_count(Statistic::TransactionCacheBytes), 80);
+
+    mem_insert_cached_transaction(txid(4), vec![0; 101], None, 100);
+    assert!(mem_get_cached_transaction(txid(4)).is_none());
+  }
+
+  #[test]
+  fn output_assets_of_unknown_outpoint_are_unknown() {
+    let index = Index::from_config(&Config::default());
+    assert!(output_assets(&index, outpoint(1)).is_none());
+
+    mem_insert_outpoint_to_utxo_entry(outpoint(1).store(), UtxoEntryBuf::empty(&index).as_ref().clone());
+    let assets = output_assets(&index, outpoint(1)).unwrap();
+    assert!(!assets.inscriptions && !assets.runes);
+    assert!(assets.rare_sats.is_none());
+
+    mem_insert_outpoint_to_rune_balances(
+      outpoint(2).store(),
+      RuneBalances {
+        balances: vec![entry::RuneBalance {
+          rune_id: RuneId { block: 1, tx: 0 },
+          balance: 1,
+        }],
+      },
+    );
+    assert!(output_assets(&index, outpoint(2)).unwrap().runes);
+  }
+
+  #[test]
+  fn fetched_transactions_are_cached_once_confirmed() {
+    let fake = std::rc::Rc::new(crate::block_source::Fake::default());
+    crate::block_source::set(fake.clone());
+    let transaction = Transaction {
+      version: bitcoin::transaction::Version::TWO,
+      lock_time: bitcoin::absolute::LockTime::ZERO,
+      input: vec![tx_in(outpoint(1))],
+      output: vec![tx_out(1000, address(0))],
+    };
+    let block_hash = fake.mine(vec![transaction.clone()]);
+    let txid = transaction.compute_txid();
+
+    assert_eq!(
+      futures::executor::block_on(fetch_transaction(txid)).unwrap(),
+      (transaction, Some(block_hash))
+    );
+    assert_eq!(mem_get_cached_transaction(txid).unwrap().block_hash, Some(block_hash));
+  }
+}
@@ -0,0 +1,578 @@
+use std::{cell::Cell, time::Duration};
+
+use bitcoin::hashes::Hash as _;
+use ic_canister_log::log;
+use ic_certified_map::{fork, fork_hash, labeled, labeled_hash, leaf_hash, Hash, HashTree};
+use logs::{ERROR, INFO};
+use ordinals::SatPoint;
+use sha2::{Digest, Sha256};
+
+use crate::{
+  index::{
+    entry::{CertifiedNode, CertifiedNodeId, CertifiedRebuild, OutPointValue, RuneBalances, CERTIFIED_KEY_SIZE},
+    mem_certified_is_dirty, mem_certified_neighbours, mem_certified_root, mem_get_certified_node,
+    mem_get_certified_rebuild, mem_insert_certified_node, mem_latest_block_height, mem_mark_certified_dirty,
+    mem_next_certified_inscriptions, mem_next_certified_rune_balances, mem_pop_certified_dirty,
+    mem_remove_certified_node, mem_reset_certified_dirty, mem_reset_certified_tree, mem_set_certified_rebuild,
+    migration, status,
+  },
+  inscriptions::InscriptionId,
+  Result,
+};
+
+const HEIGHT: &[u8] = b"height";
+const INSCRIPTION_SATPOINTS: &[u8] = b"inscription_satpoints";
+const RUNE_BALANCES: &[u8] = b"rune_balances";
+
+/// Entries added to the trees per rebuild batch.
+const REBUILD_BATCH: usize = 1_000;
+
+type Key = [u8; CERTIFIED_KEY_SIZE];
+
+/// The certified trees, each a binary trie over the bits of its keys kept in
+/// its own stable map. Every fork splits its keys by the first bit they differ
+/// in, so the shape only depends on the keys and an in-order walk visits them
+/// sorted, as the lookups in a hash tree expect.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Tree {
+  RuneBalances = 0,
+  InscriptionSatpoints = 1,
+}
+
+impl From<u8> for Tree {
+  fn from(tree: u8) -> Self {
+    match tree {
+      0 => Self::RuneBalances,
+      _ => Self::InscriptionSatpoints,
+    }
+  }
+}
+
+thread_local! {
+  // heap only: set from the config again after every upgrade
+  static ENABLED: Cell<bool> = const { Cell::new(false) };
+  static CERTIFIED_ROOT: Cell<Option<Hash>> = const { Cell::new(None) };
+  static CERTIFY_SCHEDULED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the trees are kept, following the `certified_queries` config.
+pub(crate) fn enabled() -> bool {
+  ENABLED.with(Cell::get)
+}
+
+fn rebuilding() -> bool {
+  mem_get_certified_rebuild() != CertifiedRebuild::default()
+}
+
+/// Leaf hash input of an outpoint's balances: the rune id block and tx and the
+/// amount of each balance, little endian, in stored order.
+pub(crate) fn rune_balances_hash(rune_balances: &RuneBalances) -> Hash {
+  let mut hasher = Sha256::new();
+  for balance in &rune_balances.balances {
+    hasher.update(balance.rune_id.block.to_le_bytes());
+    hasher.update(balance.rune_id.tx.to_le_bytes());
+    hasher.update(balance.balance.to_le_bytes());
+  }
+  hasher.finalize().into()
+}
+
+/// Leaf hash input of an inscription's location: txid, vout and offset.
+pub(crate) fn satpoint_hash(satpoint: &SatPoint) -> Hash {
+  let mut hasher = Sha256::new();
+  hasher.update(satpoint.outpoint.txid.to_byte_array());
+  hasher.update(satpoint.outpoint.vout.to_le_bytes());
+  hasher.update(satpoint.offset.to_le_bytes());
+  hasher.finalize().into()
+}
+
+pub(crate) fn inscription_key(inscription_id: &InscriptionId) -> Key {
+  let mut key = [0; CERTIFIED_KEY_SIZE];
+  key[..32].copy_from_slice(&inscription_id.txid.to_byte_array());
+  key[32..].copy_from_slice(&inscription_id.index.to_le_bytes());
+  key
+}
+
+fn bit(key: &Key, bit: u16) -> bool {
+  let bit = usize::from(bit);
+  key[bit / 8] & (0x80 >> (bit % 8)) != 0
+}
+
+/// `key` with every bit from `bits` on zeroed.
+fn prefix(key: &Key, bits: u16) -> Key {
+  let mut prefix = [0; CERTIFIED_KEY_SIZE];
+  for i in 0..bits {
+    if bit(key, i) {
+      let i = usize::from(i);
+      prefix[i / 8] |= 0x80 >> (i % 8);
+    }
+  }
+  prefix
+}
+
+/// The first of the leading `bits` bits `key` and `prefix` differ in.
+fn first_difference(key: &Key, prefix: &Key, bits: u16) -> Option<u16> {
+  (0..bits).find(|&i| bit(key, i) != bit(prefix, i))
+}
+
+/// Whether any key from `lo` to `hi` would be below `id`.
+fn overlaps(id: &CertifiedNodeId, lo: &Key, hi: &Key) -> bool {
+  let mut max = id.prefix;
+  for i in id.bits..(CERTIFIED_KEY_SIZE * 8) as u16 {
+    let i = usize::from(i);
+    max[i / 8] |= 0x80 >> (i % 8);
+  }
+  id.prefix <= *hi && max >= *lo
+}
+
+fn node_hash(id: &CertifiedNodeId, node: &CertifiedNode) -> Hash {
+  match node {
+    CertifiedNode::Leaf(value) => labeled_hash(&id.prefix, &leaf_hash(value)),
+    CertifiedNode::Fork { hash, .. } => *hash,
+  }
+}
+
+fn root_hash(tree: Tree) -> Hash {
+  match mem_certified_root(tree) {
+    Some((id, node)) => node_hash(&id, &node),
+    None => HashTree::Empty.reconstruct(),
+  }
+}
+
+fn certified_root_hash(height: u32) -> Hash {
+  fork_hash(
+    &labeled_hash(HEIGHT, &leaf_hash(&height.to_le_bytes())),
+    &fork_hash(
+      &labeled_hash(INSCRIPTION_SATPOINTS, &root_hash(Tree::InscriptionSatpoints)),
+      &labeled_hash(RUNE_BALANCES, &root_hash(Tree::RuneBalances)),
+    ),
+  )
+}
+
+fn replace_child(tree: Tree, id: CertifiedNodeId, node: CertifiedNode, old: CertifiedNodeId, new: CertifiedNodeId) {
+  if let CertifiedNode::Fork { hash, left, right } = node {
+    let (left, right) = if left == old { (new, right) } else { (left, new) };
+    mem_insert_certified_node(tree, id, CertifiedNode::Fork { hash, left, right });
+  }
+}
+
+/// Sets the value of `key`, splitting the node it leaves the path at. The forks
+/// above it are marked dirty and rehashed by [`certify`].
+fn insert(tree: Tree, key: Key, value: Hash) {
+  let leaf = CertifiedNodeId::leaf(key);
+  let Some((mut id, mut node)) = mem_certified_root(tree) else {
+    mem_insert_certified_node(tree, leaf, CertifiedNode::Leaf(value));
+    return;
+  };
+  let mut parent = None;
+  loop {
+    if let Some(bits) = first_difference(&key, &id.prefix, id.bits) {
+      let fork = CertifiedNodeId {
+        bits,
+        prefix: prefix(&key, bits),
+      };
+      let (left, right) = if bit(&key, bits) { (id, leaf) } else { (leaf, id) };
+      mem_insert_certified_node(tree, leaf, CertifiedNode::Leaf(value));
+      mem_insert_certified_node(tree, fork, CertifiedNode::Fork { hash: [0; 32], left, right });
+      mem_mark_certified_dirty(tree, fork);
+      if let Some((parent_id, parent_node)) = parent {
+        replace_child(tree, parent_id, parent_node, id, fork);
+      }
+      return;
+    }
+    let CertifiedNode::Fork { left, right, .. } = node else {
+      mem_insert_certified_node(tree, leaf, CertifiedNode::Leaf(value));
+      return;
+    };
+    mem_mark_certified_dirty(tree, id);
+    let child = if bit(&key, id.bits) { right } else { left };
+    parent = Some((id, node));
+    node = mem_get_certified_node(tree, &child).expect("certified tree child");
+    id = child;
+  }
+}
+
+/// Removes `key`, replacing its parent with its sibling.
+fn remove(tree: Tree, key: Key) {
+  let leaf = CertifiedNodeId::leaf(key);
+  let Some((mut id, mut node)) = mem_certified_root(tree) else {
+    return;
+  };
+  let mut path = Vec::new();
+  while let CertifiedNode::Fork { left, right, .. } = node {
+    if first_difference(&key, &id.prefix, id.bits).is_some() {
+      return;
+    }
+    let child = if bit(&key, id.bits) { right } else { left };
+    path.push((id, node));
+    node = mem_get_certified_node(tree, &child).expect("certified tree child");
+    id = child;
+  }
+  if id != leaf {
+    return;
+  }
+
+  mem_remove_certified_node(tree, &leaf);
+  let Some((parent_id, CertifiedNode::Fork { left, right, .. })) = path.pop() else {
+    return;
+  };
+  let sibling = if left == leaf { right } else { left };
+  mem_remove_certified_node(tree, &parent_id);
+  if let Some((grandparent_id, grandparent)) = path.last().cloned() {
+    replace_child(tree, grandparent_id, grandparent, parent_id, sibling);
+  }
+  for (id, _) in path {
+    mem_mark_certified_dirty(tree, id);
+  }
+}
+
+/// Recomputes the hashes of the dirty forks while the budget lasts, longest
+/// prefix first so children come before their parents. Returns whether none
+/// are left.
+fn rehash() -> bool {
+  while migration::within_budget() {
+    let Some((tree, id)) = mem_pop_certified_dirty() else {
+      return true;
+    };
+    // forks removed since they were marked are skipped
+    let Some(CertifiedNode::Fork { left, right, .. }) = mem_get_certified_node(tree, &id) else {
+      continue;
+    };
+    let child_hash = |child: &CertifiedNodeId| {
+      node_hash(child, &mem_get_certified_node(tree, child).expect("certified tree child"))
+    };
+    let hash = fork_hash(&child_hash(&left), &child_hash(&right));
+    mem_insert_certified_node(tree, id, CertifiedNode::Fork { hash, left, right });
+  }
+  !mem_certified_is_dirty()
+}
+
+pub(crate) fn update_rune_balances(outpoint: OutPointValue, rune_balances: Option<&RuneBalances>) {
+  if !enabled() {
+    return;
+  }
+  match rune_balances {
+    Some(rune_balances) => insert(Tree::RuneBalances, outpoint, rune_balances_hash(rune_balances)),
+    None => remove(Tree::RuneBalances, outpoint),
+  }
+}
+
+pub(crate) fn update_inscription_satpoint(inscription_id: &InscriptionId, satpoint: &SatPoint) {
+  if !enabled() {
+    return;
+  }
+  insert(
+    Tree::InscriptionSatpoints,
+    inscription_key(inscription_id),
+    satpoint_hash(satpoint),
+  );
+}
+
+/// Empties the rune balance tree along with the map it mirrors.
+pub(crate) fn clear_rune_balances() {
+  mem_reset_certified_tree(Tree::RuneBalances);
+}
+
+/// Empties the inscription tree along with the maps it mirrors.
+pub(crate) fn clear_inscription_satpoints() {
+  mem_reset_certified_tree(Tree::InscriptionSatpoints);
+}
+
+/// Starts keeping the trees, building them from the stable maps.
+pub(crate) fn enable() -> Result {
+  ENABLED.with(|enabled| enabled.set(true));
+  rebuild()
+}
+
+/// Picks up after an upgrade. The trees are kept in stable memory, so only a
+/// rebuild or rehash that was cut short has to be continued.
+pub(crate) fn resume() {
+  ENABLED.with(|enabled| enabled.set(true));
+  if rebuilding() {
+    ic_cdk_timers::set_timer(Duration::ZERO, continue_rebuild);
+  } else {
+    certify();
+  }
+}
+
+/// Rebuilds both trees from the stable maps, after the maps were written
+/// without going through the trees.
+pub(crate) fn rebuild() -> Result {
+  if !enabled() {
+    return Ok(());
+  }
+  let already_running = rebuilding();
+  clear_rune_balances();
+  clear_inscription_satpoints();
+  mem_reset_certified_dirty();
+  mem_set_certified_rebuild(CertifiedRebuild {
+    rune_balances: Some(None),
+    inscriptions: Some(None),
+  })?;
+  if !already_running {
+    log!(INFO, "rebuilding certified trees");
+    ic_cdk_timers::set_timer(Duration::ZERO, continue_rebuild);
+  }
+  Ok(())
+}
+
+/// Adds entries past the cursors while the budget lasts, returning whether the
+/// rebuild is done. Entries the updater writes meanwhile go straight into the
+/// trees, so the rebuild can run alongside indexing.
+fn rebuild_batches() -> Result<bool> {
+  let mut rebuild = mem_get_certified_rebuild();
+  while migration::within_budget() {
+    if let Some(after) = &rebuild.rune_balances {
+      let after = after.as_deref().map(|outpoint| outpoint.try_into().expect("outpoint cursor"));
+      let batch = mem_next_certified_rune_balances(after, REBUILD_BATCH);
+      rebuild.rune_balances = batch.last().map(|(outpoint, _)| Some(outpoint.to_vec()));
+      for (outpoint, rune_balances) in batch {
+        insert(Tree::RuneBalances, outpoint, rune_balances_hash(&rune_balances));
+      }
+    } else if let Some(after) = rebuild.inscriptions {
+      let batch = mem_next_certified_inscriptions(after, REBUILD_BATCH);
+      rebuild.inscriptions = batch.last().map(|(sequence_number, _, _)| Some(*sequence_number));
+      for (_, inscription_id, satpoint) in batch {
+        insert(
+          Tree::InscriptionSatpoints,
+          inscription_key(&inscription_id),
+          satpoint_hash(&satpoint),
+        );
+      }
+    } else {
+      break;
+    }
+  }
+  let done = rebuild == CertifiedRebuild::default();
+  mem_set_certified_rebuild(rebuild)?;
+  Ok(done)
+}
+
+fn continue_rebuild() {
+  if !enabled() {
+    return;
+  }
+  match rebuild_batches() {
+    Ok(true) => {
+      log!(INFO, "rebuilt certified trees");
+      certify();
+    }
+    Ok(false) => {
+      ic_cdk_timers::set_timer(Duration::ZERO, continue_rebuild);
+    }
+    Err(e) => log!(ERROR, "rebuilding certified trees failed: {}", e),
+  }
+}
+
+/// Drops the trees and the certified data when certification is switched off.
+pub(crate) fn disable() -> Result {
+  ENABLED.with(|enabled| enabled.set(false));
+  CERTIFIED_ROOT.with(|root| root.set(None));
+  clear_rune_balances();
+  clear_inscription_satpoints();
+  mem_reset_certified_dirty();
+  mem_set_certified_rebuild(CertifiedRebuild::default())?;
+  ic_cdk::api::set_certified_data(&[]);
+  Ok(())
+}
+
+/// Certifies the trees as they are now, tagged with the indexed tip. Skipped
+/// while a block is half written or the trees are being rebuilt. Forks left
+/// dirty when the budget runs out are rehashed in the next message.
+pub(crate) fn certify() {
+  if !enabled() || status::block_in_flight().is_some() || rebuilding() {
+    return;
+  }
+  if !rehash() {
+    if !CERTIFY_SCHEDULED.with(|scheduled| scheduled.replace(true)) {
+      ic_cdk_timers::set_timer(Duration::ZERO, || {
+        CERTIFY_SCHEDULED.with(|scheduled| scheduled.set(false));
+        certify();
+      });
+    }
+    return;
+  }
+  let root = certified_root_hash(mem_latest_block_height().unwrap_or_default());
+  if CERTIFIED_ROOT.with(Cell::get) != Some(root) {
+    ic_cdk::api::set_certified_data(&root);
+    CERTIFIED_ROOT.with(|certified_root| certified_root.set(Some(root)));
+  }
+}
+
+/// The part of a tree a witness reveals.
+enum Revealed {
+  Empty,
+  Pruned(Hash),
+  Fork(Box<Revealed>, Box<Revealed>),
+  /// A leaf with its value, or with the value pruned for the neighbours of an
+  /// absent key.
+  Leaf { key: Key, value: Hash, pruned: bool },
+}
+
+impl Revealed {
+  fn as_hash_tree(&self) -> HashTree<'_> {
+    match self {
+      Self::Empty => HashTree::Empty,
+      Self::Pruned(hash) => HashTree::Pruned(*hash),
+      Self::Fork(left, right) => fork(left.as_hash_tree(), right.as_hash_tree()),
+      Self::Leaf { key, value, pruned: false } => labeled(key.as_slice(), HashTree::Leaf(value.as_slice().into())),
+      Self::Leaf { key, value, pruned: true } => labeled(key.as_slice(), HashTree::Pruned(leaf_hash(value))),
+    }
+  }
+}
+
+/// Reveals the leaves below `id` from `lo` to `hi`, pruning everything else.
+fn reveal(tree: Tree, id: CertifiedNodeId, lo: &Key, hi: &Key, key: &Key) -> Revealed {
+  let node = mem_get_certified_node(tree, &id).expect("certified tree node");
+  if !overlaps(&id, lo, hi) {
+    return Revealed::Pruned(node_hash(&id, &node));
+  }
+  match node {
+    CertifiedNode::Leaf(value) => Revealed::Leaf {
+      key: id.prefix,
+      value,
+      pruned: id.prefix != *key,
+    },
+    CertifiedNode::Fork { left, right, .. } => Revealed::Fork(
+      Box::new(reveal(tree, left, lo, hi, key)),
+      Box::new(reveal(tree, right, lo, hi, key)),
+    ),
+  }
+}
+
+/// Reveals `key`, or the keys right before and after it to prove it absent.
+fn reveal_key(tree: Tree, key: &Key) -> Revealed {
+  let Some((root, _)) = mem_certified_root(tree) else {
+    return Revealed::Empty;
+  };
+  let leaf = CertifiedNodeId::leaf(*key);
+  let (lo, hi) = match mem_get_certified_node(tree, &leaf) {
+    Some(_) => (*key, *key),
+    None => {
+      let (before, after) = mem_certified_neighbours(tree, &leaf);
+      (before.map_or(*key, |id| id.prefix), after.map_or(*key, |id| id.prefix))
+    }
+  };
+  reveal(tree, root, &lo, &hi, key)
+}
+
+fn labeled_tree<'a>(label: &'a [u8], tree: Tree, revealed: &'a Option<Revealed>) -> HashTree<'a> {
+  match revealed {
+    Some(revealed) => labeled(label, revealed.as_hash_tree()),
+    None => HashTree::Pruned(labeled_hash(label, &root_hash(tree))),
+  }
+}
+
+/// A witness for `key` in one of the trees, with the rest of the tree pruned,
+/// along with the certificate and the height the witness is for.
+fn witness(rune_balances_key: Option<&Key>, inscription_key: Option<&Key>) -> Result<(u32, Vec<u8>, Vec<u8>), String> {
+  if !enabled() {
+    return Err("certified queries are not enabled".to_string());
+  }
+  let certificate = ic_cdk::api::data_certificate()
+    .ok_or_else(|| "certificates are only available in query calls".to_string())?;
+  let height = mem_latest_block_height().ok_or_else(|| "no block indexed yet".to_string())?;
+  if rebuilding()
+    || mem_certified_is_dirty()
+    || CERTIFIED_ROOT.with(Cell::get) != Some(certified_root_hash(height))
+  {
+    return Err("certified data is being updated, retry shortly".to_string());
+  }
+
+  let inscriptions = inscription_key.map(|key| reveal_key(Tree::InscriptionSatpoints, key));
+  let rune_balances = rune_balances_key.map(|key| reveal_key(Tree::RuneBalances, key));
+  let height_leaf = height.to_le_bytes();
+  let tree = fork(
+    labeled(HEIGHT, HashTree::Leaf(height_leaf.as_slice().into())),
+    fork(
+      labeled_tree(INSCRIPTION_SATPOINTS, Tree::InscriptionSatpoints, &inscriptions),
+      labeled_tree(RUNE_BALANCES, Tree::RuneBalances, &rune_balances),
+    ),
+  );
+
+  let mut witness = Vec::new();
+  ciborium::into_writer(&tree, &mut witness).map_err(|e| e.to_string())?;
+  Ok((height, certificate, witness))
+}
+
+pub(crate) fn rune_balances_witness(outpoint: &OutPointValue) -> Result<(u32, Vec<u8>, Vec<u8>), String> {
+  witness(Some(outpoint), None)
+}
+
+pub(crate) fn inscription_witness(inscription_id: &InscriptionId) -> Result<(u32, Vec<u8>, Vec<u8>), String> {
+  witness(None, Some(&inscription_key(inscription_id)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::set_instruction_counter;
+
+  fn key(n: u8) -> Key {
+    let mut key = [0; CERTIFIED_KEY_SIZE];
+    key[0] = n;
+    key[CERTIFIED_KEY_SIZE - 1] = n;
+    key
+  }
+
+  fn rehashed_root() -> Hash {
+    set_instruction_counter(0);
+    assert!(rehash());
+    root_hash(Tree::RuneBalances)
+  }
+
+  fn root_after(keys: &[u8]) -> Hash {
+    clear_rune_balances();
+    mem_reset_certified_dirty();
+    for &n in keys {
+      insert(Tree::RuneBalances, key(n), [n; 32]);
+    }
+    rehashed_root()
+  }
+
+  /// The leaves a witness reveals, and whether their values are pruned.
+  fn leaves(revealed: &Revealed) -> Vec<(Key, bool)> {
+    match revealed {
+      Revealed::Empty | Revealed::Pruned(_) => Vec::new(),
+      Revealed::Fork(left, right) => [leaves(left), leaves(right)].concat(),
+      Revealed::Leaf { key, pruned, .. } => vec![(*key, *pruned)],
+    }
+  }
+
+  #[test]
+  fn tree_only_depends_on_its_entries() {
+    let root = root_after(&[1, 2, 3, 200, 7]);
+    assert_eq!(root_after(&[7, 200, 3, 2, 1]), root);
+
+    insert(Tree::RuneBalances, key(3), [9; 32]);
+    assert_ne!(rehashed_root(), root);
+    insert(Tree::RuneBalances, key(3), [3; 32]);
+    assert_eq!(rehashed_root(), root);
+
+    let without = root_after(&[1, 2, 200, 7]);
+    root_after(&[1, 2, 3, 200, 7]);
+    remove(Tree::RuneBalances, key(3));
+    remove(Tree::RuneBalances, key(4));
+    assert_eq!(rehashed_root(), without);
+
+    for n in [1, 2, 200, 7] {
+      remove(Tree::RuneBalances, key(n));
+    }
+    assert_eq!(rehashed_root(), HashTree::Empty.reconstruct());
+    assert!(mem_certified_root(Tree::RuneBalances).is_none());
+  }
+
+  #[test]
+  fn witnesses_reveal_the_key_or_its_neighbours() {
+    let root = root_after(&[1, 2, 3, 200, 7]);
+    for (n, revealed) in [
+      (3, vec![(key(3), false)]),
+      (4, vec![(key(3), true), (key(7), true)]),
+      (0, vec![(key(1), true)]),
+      (255, vec![(key(200), true)]),
+    ] {
+      let witness = reveal_key(Tree::RuneBalances, &key(n));
+      assert_eq!(witness.as_hash_tree().reconstruct(), root);
+      assert_eq!(leaves(&witness), revealed);
+    }
+  }
+}
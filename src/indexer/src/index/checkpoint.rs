@@ -0,0 +1,394 @@
+use anyhow::anyhow;
+use bitcoin::BlockHash;
+use ic_canister_log::log;
+use logs::INFO;
+
+use crate::{
+  config::Config,
+  index::{
+    certification,
+    entry::{Checkpoint, CheckpointChunk, Entry},
+    mem_block_hash, mem_checkpoints, mem_get_checkpoint_chunk, mem_get_config,
+    mem_insert_checkpoint, mem_insert_checkpoint_chunk, mem_next_undo_epoch, mem_prune_undo_below,
+    mem_read_checkpoint_table, mem_remove_blocks_above, mem_remove_checkpoint, mem_reset_runes,
+    mem_undo_last, mem_write_checkpoint_table,
+    updater::backfill::reset_features_above,
+  },
+  Result,
+};
+
+/// Maps covered by checkpoints and snapshots, in the order they are written,
+/// followed by the per-block maps that only snapshots carry. Checkpoints copy
+/// the rune maps, but only keep an undo log of the UTXO maps: a copy of those
+/// would be as large as the index itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Table {
+  RuneIdToRuneEntry,
+  RuneToRuneId,
+  OutpointToRuneBalances,
+  OutpointToHeight,
+  SequenceNumberToRuneId,
+  TransactionIdToRune,
+  HeightToChangeRecordRune,
+  HeightToStatisticRunes,
+  HeightToStatisticReservedRunes,
+  OutpointToUtxoEntry,
+  ScriptPubkeyToOutpoint,
+  SatToSatpoint,
+  SatToSequenceNumber,
+  SequenceNumberToInscriptionEntry,
+  SequenceNumberToInscriptionAttributes,
+  SequenceNumberToSatpoint,
+  SequenceNumberToScriptPubkey,
+  SequenceNumberToChild,
+  InscriptionIdToSequenceNumber,
+  InscriptionNumberToSequenceNumber,
+  HomeInscriptions,
+  HeightToLastSequenceNumber,
+  TransactionIdToTransaction,
+  StatisticToCount,
+  HeightToBlockHeader,
+  HeightToBlockSummary,
+  BlockHashToHeight,
+  HeightToCommitment,
+}
+
+pub(crate) const TABLES: [Table; 24] = [
+  Table::RuneIdToRuneEntry,
+  Table::RuneToRuneId,
+  Table::OutpointToRuneBalances,
+  Table::OutpointToHeight,
+  Table::SequenceNumberToRuneId,
+  Table::TransactionIdToRune,
+  Table::HeightToChangeRecordRune,
+  Table::HeightToStatisticRunes,
+  Table::HeightToStatisticReservedRunes,
+  Table::OutpointToUtxoEntry,
+  Table::ScriptPubkeyToOutpoint,
+  Table::SatToSatpoint,
+  Table::SatToSequenceNumber,
+  Table::SequenceNumberToInscriptionEntry,
+  Table::SequenceNumberToInscriptionAttributes,
+  Table::SequenceNumberToSatpoint,
+  Table::SequenceNumberToScriptPubkey,
+  Table::SequenceNumberToChild,
+  Table::InscriptionIdToSequenceNumber,
+  Table::InscriptionNumberToSequenceNumber,
+  Table::HomeInscriptions,
+  Table::HeightToLastSequenceNumber,
+  Table::TransactionIdToTransaction,
+  Table::StatisticToCount,
+];
+
+impl Table {
+  fn is_rune_table(self) -> bool {
+    self as usize <= Table::HeightToStatisticReservedRunes as usize
+  }
+}
+
+/// Entries copied into a single chunk.
+const CHUNK_ENTRIES: usize = 1_000;
+
+/// Instructions a loop cycle spends on a checkpoint before yielding, well
+/// below the limit of a single message.
+const INSTRUCTIONS_PER_CYCLE: u64 = 10_000_000_000;
+
+/// How many of the newest complete checkpoints are kept.
+const CHECKPOINTS_KEPT: usize = 2;
+
+/// Undo log entries no checkpoint needs anymore dropped in a loop cycle.
+const UNDO_ENTRIES_PRUNED_PER_CYCLE: usize = 10_000;
+
+fn copies(checkpoint: &Checkpoint, table: Table) -> bool {
+  table.is_rune_table() && checkpoint.runes
+}
+
+/// Whether the rune maps and the UTXO entry maps are being indexed.
+fn live_groups(config: &Config) -> (bool, bool) {
+  let runes = config.index_runes.unwrap_or_default();
+  let utxo_entries = [
+    config.index_addresses,
+    config.index_inscriptions,
+    config.index_sats,
+    config.index_transactions,
+  ]
+  .iter()
+  .any(|flag| flag.unwrap_or_default());
+  (runes, utxo_entries)
+}
+
+/// Starts a checkpoint of the state after the block at `height` when one is due.
+pub(crate) fn begin_if_due(height: u32, block_hash: BlockHash) {
+  let config = mem_get_config();
+  let interval = config.get_checkpoint_interval();
+  if interval == 0 || height % interval != 0 {
+    return;
+  }
+  let (runes, utxo_entries) = live_groups(&config);
+  if !runes && !utxo_entries {
+    return;
+  }
+
+  log!(INFO, "writing checkpoint at height {}", height);
+  mem_insert_checkpoint(
+    height,
+    Checkpoint {
+      block_hash: block_hash.store(),
+      runes,
+      utxo_entries,
+      chunks: 0,
+      epoch: mem_next_undo_epoch(),
+      cursor: Some((0, None)),
+      restoring: None,
+    },
+  );
+}
+
+/// Continues writing or restoring a checkpoint for one loop cycle. Returns
+/// whether work is left, in which case nothing else may change the index yet.
+pub(crate) fn step() -> Result<bool> {
+  let checkpoints = mem_checkpoints();
+  for (height, checkpoint) in &checkpoints {
+    if checkpoint.restoring.is_some() {
+      return restore_chunks(*height, checkpoint.clone());
+    }
+    if !checkpoint.is_complete() {
+      return Ok(write_chunks(*height, checkpoint.clone()));
+    }
+  }
+
+  let oldest = checkpoints
+    .iter()
+    .filter(|(_, checkpoint)| checkpoint.utxo_entries)
+    .map(|(_, checkpoint)| checkpoint.epoch)
+    .min()
+    .unwrap_or(u32::MAX);
+  mem_prune_undo_below(oldest, UNDO_ENTRIES_PRUNED_PER_CYCLE);
+  Ok(false)
+}
+
+/// Whether a checkpoint is being loaded, during which the maps hold a mix of
+/// its state and the one it rolls back from.
+pub(crate) fn is_restoring() -> bool {
+  mem_checkpoints()
+    .iter()
+    .any(|(_, checkpoint)| checkpoint.restoring.is_some())
+}
+
+fn write_chunks(height: u32, mut checkpoint: Checkpoint) -> bool {
+  let Some((mut table, mut after)) = checkpoint.cursor.take() else {
+    return false;
+  };
+
+  while crate::instruction_counter() < INSTRUCTIONS_PER_CYCLE {
+    let Some(current) = TABLES.get(usize::from(table)).copied() else {
+      break;
+    };
+    if !copies(&checkpoint, current) {
+      table += 1;
+      after = None;
+      continue;
+    }
+
+    let entries = mem_read_checkpoint_table(current, after.take(), CHUNK_ENTRIES, usize::MAX);
+    let chunk = CheckpointChunk { table, entries };
+    if chunk.entries.len() == CHUNK_ENTRIES {
+      after = chunk.entries.last().map(|(key, _)| key.clone());
+    } else {
+      table += 1;
+    }
+    if !chunk.entries.is_empty() {
+      mem_insert_checkpoint_chunk(height, checkpoint.chunks, chunk);
+      checkpoint.chunks += 1;
+    }
+  }
+
+  if usize::from(table) < TABLES.len() {
+    checkpoint.cursor = Some((table, after));
+    mem_insert_checkpoint(height, checkpoint);
+    return true;
+  }
+
+  log!(
+    INFO,
+    "wrote checkpoint at height {} in {} chunks",
+    height,
+    checkpoint.chunks
+  );
+  mem_insert_checkpoint(height, checkpoint);
+  let complete: Vec<u32> = mem_checkpoints()
+    .into_iter()
+    .filter(|(_, checkpoint)| checkpoint.is_complete())
+    .map(|(height, _)| height)
+    .collect();
+  for height in complete.iter().rev().skip(CHECKPOINTS_KEPT) {
+    mem_remove_checkpoint(*height);
+  }
+  false
+}
+
+fn restore_chunks(height: u32, mut checkpoint: Checkpoint) -> Result<bool> {
+  let mut next = checkpoint.restoring.unwrap_or_default();
+  while next < checkpoint.chunks && crate::instruction_counter() < INSTRUCTIONS_PER_CYCLE {
+    let chunk = mem_get_checkpoint_chunk(height, next)
+      .ok_or_else(|| anyhow!("chunk {} of the checkpoint at height {} is missing", next, height))?;
+    let table = TABLES[usize::from(chunk.table)];
+    if copies(&checkpoint, table) {
+      mem_write_checkpoint_table(table, chunk.entries);
+    }
+    next += 1;
+  }
+  // the UTXO maps are rolled back newest change first, through every epoch
+  // since the checkpoint's own
+  let mut undo_left = checkpoint.utxo_entries;
+  while undo_left && next >= checkpoint.chunks && crate::instruction_counter() < INSTRUCTIONS_PER_CYCLE {
+    undo_left = mem_undo_last(checkpoint.epoch);
+  }
+
+  let done = next >= checkpoint.chunks && !undo_left;
+  checkpoint.restoring = (!done).then_some(next);
+  mem_insert_checkpoint(height, checkpoint);
+  if done {
+    log!(INFO, "restored checkpoint at height {}", height);
+    // the chunks were written past the certified trees
+    certification::rebuild()?;
+  }
+  Ok(!done)
+}
+
+/// Drops every checkpoint `discard` returns true for.
+pub(crate) fn discard_where(discard: impl Fn(u32, &Checkpoint) -> bool) {
+  for (height, checkpoint) in mem_checkpoints() {
+    if discard(height, &checkpoint) {
+      log!(INFO, "discarding checkpoint at height {}", height);
+      mem_remove_checkpoint(height);
+    }
+  }
+}
+
+/// Rolls the index back to the newest checkpoint at or below `height`, if
+/// there is one, and returns its height. The rune maps are loaded and the UTXO
+/// maps undone over the following loop cycles; features it doesn't cover are
+/// indexed again from their first height.
+pub(crate) fn restore(height: u32) -> Result<Option<u32>> {
+  discard_where(|h, checkpoint| h > height || !checkpoint.is_complete());
+
+  let Some((checkpoint_height, mut checkpoint)) = mem_checkpoints()
+    .into_iter()
+    .rev()
+    .find(|(h, checkpoint)| mem_block_hash(*h) == Some(BlockHash::load(checkpoint.block_hash)))
+  else {
+    return Ok(None);
+  };
+
+  log!(
+    INFO,
+    "restoring checkpoint at height {} to roll back to height {}",
+    checkpoint_height,
+    height
+  );
+  mem_remove_blocks_above(checkpoint_height);
+
+  let (runes, utxo_entries) = live_groups(&mem_get_config());
+  checkpoint.runes &= runes;
+  checkpoint.utxo_entries &= utxo_entries;
+  if checkpoint.runes {
+    mem_reset_runes();
+  }
+  checkpoint.restoring = Some(0);
+  mem_insert_checkpoint(checkpoint_height, checkpoint.clone());
+
+  reset_features_above(checkpoint_height, Some(&checkpoint))?;
+
+  Ok(Some(checkpoint_height))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    index::{
+      mem_get_height_to_last_sequence_number, mem_get_sequence_number_to_satpoint, mem_get_statistic_count,
+      mem_increment_statistic, mem_insert_block_header, mem_insert_height_to_last_sequence_number,
+      mem_insert_sequence_number_to_satpoint, mem_insert_statistic_to_count, mem_set_config, mem_table_sizes,
+      Statistic,
+    },
+    set_instruction_counter,
+    test::satpoint,
+  };
+
+  fn undo_entries() -> u64 {
+    mem_table_sizes()
+      .into_iter()
+      .find(|(name, _)| *name == "checkpoint_undo")
+      .unwrap()
+      .1
+  }
+
+  /// Runs loop cycles until the checkpoint work is done.
+  fn run() {
+    loop {
+      set_instruction_counter(0);
+      if !step().unwrap() {
+        return;
+      }
+    }
+  }
+
+  #[test]
+  fn utxo_maps_are_rolled_back_through_the_undo_log() {
+    mem_set_config(Config {
+      index_inscriptions: Some(true),
+      index_runes: Some(false),
+      checkpoint_interval: Some(10),
+      ..mem_get_config()
+    })
+    .unwrap();
+    let header = bitcoin::constants::genesis_block(bitcoin::Network::Regtest).header;
+    mem_insert_block_header(10, header.store());
+
+    mem_insert_sequence_number_to_satpoint(0, satpoint(1, 0).store());
+    mem_insert_statistic_to_count(Statistic::LostSats, 3);
+    begin_if_due(10, header.block_hash());
+    run();
+    assert_eq!(undo_entries(), 0);
+
+    mem_insert_sequence_number_to_satpoint(0, satpoint(2, 0).store());
+    mem_insert_sequence_number_to_satpoint(0, satpoint(3, 0).store());
+    mem_insert_sequence_number_to_satpoint(1, satpoint(4, 0).store());
+    mem_insert_height_to_last_sequence_number(11, 1);
+    mem_increment_statistic(Statistic::LostSats, 5);
+    mem_insert_statistic_to_count(Statistic::TransactionCacheBytes, 7);
+    assert_eq!(undo_entries(), 4);
+
+    assert_eq!(restore(10).unwrap(), Some(10));
+    assert!(is_restoring());
+    run();
+
+    assert!(!is_restoring());
+    assert_eq!(mem_get_sequence_number_to_satpoint(0), Some(satpoint(1, 0).store()));
+    assert_eq!(mem_get_sequence_number_to_satpoint(1), None);
+    assert_eq!(mem_get_height_to_last_sequence_number(11), None);
+    assert_eq!(mem_get_statistic_count(Statistic::LostSats), 3);
+    assert_eq!(mem_get_statistic_count(Statistic::TransactionCacheBytes), 7);
+    assert_eq!(undo_entries(), 0);
+
+    // changes after the restore are recorded against the same checkpoint again
+    mem_insert_height_to_last_sequence_number(11, 0);
+    assert_eq!(undo_entries(), 1);
+    discard_where(|_, _| true);
+    run();
+    assert_eq!(undo_entries(), 0);
+  }
+
+  #[test]
+  fn tables_are_grouped() {
+    assert!(TABLES
+      .iter()
+      .enumerate()
+      .all(|(position, table)| *table as usize == position));
+    assert!(Table::HeightToStatisticReservedRunes.is_rune_table());
+    assert!(!Table::OutpointToUtxoEntry.is_rune_table());
+    assert!(!Table::StatisticToCount.is_rune_table());
+  }
+}
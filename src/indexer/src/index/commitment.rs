@@ -0,0 +1,186 @@
+use bitcoin::{hashes::Hash, BlockHash, OutPoint};
+use ordinals::{RuneId, SatPoint};
+use sha2::{Digest, Sha256};
+
+use super::{
+  event::{Event, Events},
+  Index,
+};
+use crate::inscriptions::InscriptionId;
+
+/// The commitment `previous` continues from at `height`, `None` when the chain
+/// can't be continued there. It is seeded with 32 zero bytes at the first
+/// height the features index from. A canister that indexed blocks before
+/// commitments were recorded gets none until a snapshot seeds it.
+pub(crate) fn previous(index: &Index, height: u32, previous: Option<[u8; 32]>) -> Option<[u8; 32]> {
+  previous.or_else(|| (height == index.get_first_index_height()).then_some([0; 32]))
+}
+
+/// The features switched on for new blocks, one bit each. A feature being
+/// backfilled is not, so none of its events are committed to until the block
+/// after it caught up.
+pub(crate) fn features(index: &Index) -> u8 {
+  [
+    index.index_addresses,
+    index.index_inscriptions,
+    index.index_runes,
+    index.index_sats,
+    index.index_transactions,
+  ]
+  .iter()
+  .enumerate()
+  .fold(0, |bits, (bit, enabled)| bits | (u8::from(*enabled) << bit))
+}
+
+/// Rolling commitment to everything indexed up to and including a block:
+///
+/// `sha256(previous || height || block_hash || features || events)`
+///
+/// where `previous` is the commitment of the block below, or its seed, and the
+/// events are hashed in the order they were indexed. Integers are little
+/// endian, hashes and txids are in their consensus byte order, and each event
+/// starts with a tag byte. Two indexers agree on a height exactly when they
+/// indexed every block up to it with the same features and the same events.
+pub(crate) fn commit(previous: [u8; 32], height: u32, block_hash: BlockHash, features: u8, events: &Events) -> [u8; 32] {
+  let mut hasher = Sha256::new();
+  hasher.update(previous);
+  hasher.update(height.to_le_bytes());
+  hasher.update(block_hash.to_byte_array());
+  hasher.update([features]);
+  for event in events.iter() {
+    hash_event(&mut hasher, event);
+  }
+  hasher.finalize().into()
+}
+
+fn hash_event(hasher: &mut Sha256, event: &Event) {
+  match event {
+    Event::InscriptionCreated {
+      charms,
+      inscription_id,
+      location,
+      parent_inscription_ids,
+      sequence_number,
+      ..
+    } => {
+      hasher.update([0]);
+      hash_inscription_id(hasher, inscription_id);
+      hasher.update(sequence_number.to_le_bytes());
+      hasher.update(charms.to_le_bytes());
+      match location {
+        Some(location) => {
+          hasher.update([1]);
+          hash_satpoint(hasher, location);
+        }
+        None => hasher.update([0]),
+      }
+      hasher.update((parent_inscription_ids.len() as u32).to_le_bytes());
+      for parent in parent_inscription_ids {
+        hash_inscription_id(hasher, parent);
+      }
+    }
+    Event::InscriptionTransferred {
+      inscription_id,
+      new_location,
+      old_location,
+      sequence_number,
+      ..
+    } => {
+      hasher.update([1]);
+      hash_inscription_id(hasher, inscription_id);
+      hasher.update(sequence_number.to_le_bytes());
+      hash_satpoint(hasher, old_location);
+      hash_satpoint(hasher, new_location);
+    }
+    Event::RuneBurned { amount, rune_id, txid, .. } => {
+      hasher.update([2]);
+      hash_rune_id(hasher, rune_id);
+      hasher.update(txid.to_byte_array());
+      hasher.update(amount.to_le_bytes());
+    }
+    Event::RuneEtched { rune_id, txid, .. } => {
+      hasher.update([3]);
+      hash_rune_id(hasher, rune_id);
+      hasher.update(txid.to_byte_array());
+    }
+    Event::RuneMinted { amount, rune_id, txid, .. } => {
+      hasher.update([4]);
+      hash_rune_id(hasher, rune_id);
+      hasher.update(txid.to_byte_array());
+      hasher.update(amount.to_le_bytes());
+    }
+    Event::RuneTransferred {
+      amount,
+      outpoint,
+      rune_id,
+      txid,
+      ..
+    } => {
+      hasher.update([5]);
+      hash_rune_id(hasher, rune_id);
+      hasher.update(txid.to_byte_array());
+      hash_outpoint(hasher, outpoint);
+      hasher.update(amount.to_le_bytes());
+    }
+  }
+}
+
+fn hash_inscription_id(hasher: &mut Sha256, inscription_id: &InscriptionId) {
+  hasher.update(inscription_id.txid.to_byte_array());
+  hasher.update(inscription_id.index.to_le_bytes());
+}
+
+fn hash_outpoint(hasher: &mut Sha256, outpoint: &OutPoint) {
+  hasher.update(outpoint.txid.to_byte_array());
+  hasher.update(outpoint.vout.to_le_bytes());
+}
+
+fn hash_satpoint(hasher: &mut Sha256, satpoint: &SatPoint) {
+  hash_outpoint(hasher, &satpoint.outpoint);
+  hasher.update(satpoint.offset.to_le_bytes());
+}
+
+fn hash_rune_id(hasher: &mut Sha256, rune_id: &RuneId) {
+  hasher.update(rune_id.block.to_le_bytes());
+  hasher.update(rune_id.tx.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bitcoin::Txid;
+
+  fn minted(amount: u128) -> Event {
+    Event::RuneMinted {
+      amount,
+      block_height: 840_000,
+      rune_id: RuneId { block: 840_000, tx: 1 },
+      txid: Txid::all_zeros(),
+    }
+  }
+
+  #[test]
+  fn commitment_covers_events_and_history() {
+    let block_hash = BlockHash::all_zeros();
+    let events = Events(vec![minted(1)]);
+
+    let first = commit([0; 32], 840_000, block_hash, 4, &events);
+    assert_eq!(first, commit([0; 32], 840_000, block_hash, 4, &events));
+    assert_ne!(first, commit([0; 32], 840_000, block_hash, 4, &Events(vec![minted(2)])));
+    assert_ne!(first, commit([0; 32], 840_000, block_hash, 4, &Events::new()));
+    assert_ne!(first, commit([0; 32], 840_000, block_hash, 6, &events));
+
+    let second = commit(first, 840_001, block_hash, 4, &Events::new());
+    assert_ne!(second, commit([1; 32], 840_001, block_hash, 4, &Events::new()));
+  }
+
+  #[test]
+  fn chain_is_seeded_at_the_first_index_height() {
+    let index = Index::from_config(&crate::config::Config::default());
+    let first = index.get_first_index_height();
+    assert_eq!(previous(&index, first, None), Some([0; 32]));
+    assert_eq!(previous(&index, first + 1, None), None);
+    assert_eq!(previous(&index, first + 1, Some([1; 32])), Some([1; 32]));
+    assert_eq!(features(&index), 0b110);
+  }
+}
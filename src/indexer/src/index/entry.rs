@@ -1,11 +1,16 @@
 use std::{borrow::Cow, collections::HashMap};
 
-use bitcoin::{block::Header, consensus::{self, Decodable, Encodable}, hashes::Hash, OutPoint, Txid};
+use bitcoin::{block::Header, consensus::{self, Decodable, Encodable}, hashes::Hash, BlockHash, OutPoint, Txid};
+use candid::Principal;
 use ic_stable_structures::{storable::Bound, Storable};
 use ordinals::{Pile, Rune, RuneId, Sat, SatPoint, SpacedRune, Terms};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::{inscriptions::InscriptionId, runes::MintError};
+use compact::{Reader, Writer};
+
+use crate::{inscriptions::{Inscription, InscriptionId}, runes::MintError};
+
+mod compact;
 
 pub(crate) trait Entry: Sized {
   type Value;
@@ -280,19 +285,96 @@ impl Entry for RuneEntry {
   }
 }
 
+/// Width of the compact `RuneEntry` encoding, with absent options zeroed.
+const RUNE_ENTRY_SIZE: usize = 203;
+
+/// The `RuneEntryValue` fields in order, little endian, with options as a
+/// presence byte followed by the value.
 impl Storable for RuneEntry {
   fn to_bytes(&self) -> Cow<[u8]> {
-    let vec = bincode::serialize(self).unwrap();
-    Cow::Owned(vec)
+    let (
+      block,
+      burned,
+      divisibility,
+      (etching_low, etching_high),
+      mints,
+      number,
+      premine,
+      (rune, spacers),
+      symbol,
+      terms,
+      timestamp,
+      turbo,
+    ) = self.store();
+    let (cap, (height_start, height_end), amount, (offset_start, offset_end)) = terms.unwrap_or_default();
+
+    let mut writer = Writer::new(RUNE_ENTRY_SIZE);
+    writer
+      .u64(block)
+      .u128(burned)
+      .u8(divisibility)
+      .u128(etching_low)
+      .u128(etching_high)
+      .u128(mints)
+      .u64(number)
+      .u128(premine)
+      .u128(rune)
+      .u32(spacers)
+      .option(symbol.map(u32::from), Writer::u32)
+      .u8(terms.is_some().into())
+      .option(cap, Writer::u128)
+      .option(height_start, Writer::u64)
+      .option(height_end, Writer::u64)
+      .option(amount, Writer::u128)
+      .option(offset_start, Writer::u64)
+      .option(offset_end, Writer::u64)
+      .u64(timestamp)
+      .u8(turbo.into());
+    Cow::Owned(writer.finish())
   }
 
   fn from_bytes(bytes: Cow<[u8]>) -> Self {
-    bincode::deserialize(&bytes).unwrap()
+    let mut reader = Reader::new(&bytes);
+    let block = reader.u64();
+    let burned = reader.u128();
+    let divisibility = reader.u8();
+    let etching = (reader.u128(), reader.u128());
+    let mints = reader.u128();
+    let number = reader.u64();
+    let premine = reader.u128();
+    let spaced_rune = (reader.u128(), reader.u32());
+    let symbol = reader
+      .option(Reader::u32)
+      .map(|symbol| char::from_u32(symbol).unwrap());
+    let has_terms = reader.u8() != 0;
+    let terms = (
+      reader.option(Reader::u128),
+      (reader.option(Reader::u64), reader.option(Reader::u64)),
+      reader.option(Reader::u128),
+      (reader.option(Reader::u64), reader.option(Reader::u64)),
+    );
+    let timestamp = reader.u64();
+    let turbo = reader.u8() != 0;
+
+    Self::load((
+      block,
+      burned,
+      divisibility,
+      etching,
+      mints,
+      number,
+      premine,
+      spaced_rune,
+      symbol,
+      has_terms.then_some(terms),
+      timestamp,
+      turbo,
+    ))
   }
 
   const BOUND: Bound = Bound::Bounded {
-    max_size: 307,
-    is_fixed_size: false,
+    max_size: RUNE_ENTRY_SIZE as u32,
+    is_fixed_size: true,
   };
 }
 
@@ -396,7 +478,554 @@ pub struct InscriptionEntry {
 }
 
 
+/// The `InscriptionEntryValue` fields in order, little endian, except for the
+/// fee as a varint and the parents as the remaining `u32`s. Unbounded only
+/// because an inscription can have any number of parents.
 impl Storable for InscriptionEntry {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let (
+      charms,
+      fee,
+      height,
+      (txid_low, txid_high, index),
+      inscription_number,
+      parents,
+      sat,
+      sequence_number,
+      timestamp,
+    ) = self.clone().store();
+
+    let mut writer = Writer::new(74 + 4 * parents.len());
+    writer
+      .u16(charms)
+      .varint(fee.into())
+      .u32(height)
+      .u128(txid_low)
+      .u128(txid_high)
+      .u32(index)
+      .u32(inscription_number as u32)
+      .option(sat, Writer::u64)
+      .u32(sequence_number)
+      .u32(timestamp);
+    for parent in parents {
+      writer.u32(parent);
+    }
+    Cow::Owned(writer.finish())
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    let mut reader = Reader::new(&bytes);
+    let charms = reader.u16();
+    let fee = reader.varint().try_into().unwrap();
+    let height = reader.u32();
+    let id = (reader.u128(), reader.u128(), reader.u32());
+    let inscription_number = reader.u32() as i32;
+    let sat = reader.option(Reader::u64);
+    let sequence_number = reader.u32();
+    let timestamp = reader.u32();
+    let mut parents = Vec::new();
+    while !reader.is_empty() {
+      parents.push(reader.u32());
+    }
+
+    Self::load((
+      charms,
+      fee,
+      height,
+      id,
+      inscription_number,
+      parents,
+      sat,
+      sequence_number,
+      timestamp,
+    ))
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The envelope fields `get_inscription_info` needs, recorded when an
+/// inscription is indexed so reads don't have to refetch the reveal transaction.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct InscriptionAttributes {
+  pub content_encoding: Option<String>,
+  pub content_length: Option<u64>,
+  pub content_type: Option<String>,
+  pub delegate: Option<InscriptionId>,
+  pub metaprotocol: Option<String>,
+  pub rune: Option<Rune>,
+}
+
+impl From<&Inscription> for InscriptionAttributes {
+  fn from(inscription: &Inscription) -> Self {
+    Self {
+      content_encoding: inscription
+        .content_encoding
+        .as_ref()
+        .and_then(|encoding| String::from_utf8(encoding.clone()).ok()),
+      content_length: inscription.content_length().map(|length| length as u64),
+      content_type: inscription.content_type().map(str::to_string),
+      delegate: inscription.delegate(),
+      metaprotocol: inscription.metaprotocol().map(str::to_string),
+      // the tag holds a rune commitment: little-endian with trailing zeros stripped
+      rune: inscription
+        .rune
+        .as_ref()
+        .filter(|commitment| commitment.len() <= 16)
+        .map(|commitment| {
+          let mut bytes = [0; 16];
+          bytes[..commitment.len()].copy_from_slice(commitment);
+          Rune(u128::from_le_bytes(bytes))
+        }),
+    }
+  }
+}
+
+impl Storable for InscriptionAttributes {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A transaction fetched over an HTTPS outcall, kept in the LRU transaction
+/// cache. `last_used` is its key in the recency map.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CachedTransaction {
+  pub block_hash: Option<BlockHash>,
+  pub last_used: u64,
+  pub transaction: Vec<u8>,
+}
+
+impl Storable for CachedTransaction {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum IndexFeature {
+  Addresses,
+  Inscriptions,
+  Runes,
+  Sats,
+  Transactions,
+}
+
+impl Entry for IndexFeature {
+  type Value = u8;
+
+  fn load(value: Self::Value) -> Self {
+    match value {
+      0 => Self::Addresses,
+      1 => Self::Inscriptions,
+      2 => Self::Runes,
+      3 => Self::Sats,
+      4 => Self::Transactions,
+      _ => panic!("unknown index feature {value}"),
+    }
+  }
+
+  fn store(self) -> Self::Value {
+    self as u8
+  }
+}
+
+/// Progress of a feature being indexed from its first height while the rest
+/// of the index keeps serving.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Backfill {
+  pub start_height: u32,
+  pub next_height: u32,
+}
+
+impl Storable for Backfill {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Access levels, each one including everything granted to the ones before it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum Role {
+  Reader,
+  Operator,
+  Admin,
+}
+
+impl Storable for Role {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A controller-made change to the config, kept for auditing.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ConfigChange {
+  pub timestamp: u64,
+  pub caller: Principal,
+  pub change: String,
+}
+
+impl Storable for ConfigChange {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Rune and UTXO state after the block at its height. The rune maps are copied
+/// in chunks, the UTXO maps are kept as an undo log of the entries changed
+/// since. Both are written and restored a few at a time.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+  pub block_hash: BlockHashValue,
+  pub runes: bool,
+  pub utxo_entries: bool,
+  pub chunks: u32,
+  /// Undo log epoch the UTXO map changes after this checkpoint start in.
+  pub epoch: u32,
+  /// Table and last key copied so far, while the checkpoint is being written.
+  pub cursor: Option<(u8, Option<Vec<u8>>)>,
+  /// Next chunk to load, while the checkpoint is being restored.
+  pub restoring: Option<u32>,
+}
+
+impl Checkpoint {
+  pub fn is_complete(&self) -> bool {
+    self.cursor.is_none()
+  }
+}
+
+impl Storable for Checkpoint {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Raw keys and values of one table, as stored in its map.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CheckpointChunk {
+  pub table: u8,
+  pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Storable for CheckpointChunk {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Key of an undo log entry: the epoch it was recorded in, then the table and
+/// the raw key of the entry changed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CheckpointUndoKey {
+  pub epoch: u32,
+  pub table: u8,
+  pub key: Vec<u8>,
+}
+
+impl Storable for CheckpointUndoKey {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let mut vec = Vec::with_capacity(5 + self.key.len());
+    vec.extend_from_slice(&self.epoch.to_be_bytes());
+    vec.push(self.table);
+    vec.extend_from_slice(&self.key);
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    Self {
+      epoch: u32::from_be_bytes(bytes[..4].try_into().unwrap()),
+      table: bytes[4],
+      key: bytes[5..].to_vec(),
+    }
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Raw value an entry had before its first change in an undo log epoch, `None`
+/// if it didn't exist yet.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CheckpointUndo {
+  pub value: Option<Vec<u8>>,
+}
+
+impl Storable for CheckpointUndo {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The block the indexing loop started writing and hasn't committed yet, kept
+/// across upgrades so one left behind by a trapped loop isn't forgotten.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct UnfinishedBlock {
+  pub height: Option<u32>,
+}
+
+impl Storable for UnfinishedBlock {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Certified trees are keyed by outpoints and inscription ids, both 36 bytes.
+pub const CERTIFIED_KEY_SIZE: usize = 36;
+
+/// Id of a node in a certified tree: how many leading bits the keys below it
+/// share, then those bits with the rest zeroed. Leaves have all the bits of
+/// their key. Shorter prefixes sort first, so the root is the first node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CertifiedNodeId {
+  pub bits: u16,
+  pub prefix: [u8; CERTIFIED_KEY_SIZE],
+}
+
+impl CertifiedNodeId {
+  pub fn leaf(key: [u8; CERTIFIED_KEY_SIZE]) -> Self {
+    Self {
+      bits: (CERTIFIED_KEY_SIZE * 8) as u16,
+      prefix: key,
+    }
+  }
+
+  pub fn is_leaf(&self) -> bool {
+    usize::from(self.bits) == CERTIFIED_KEY_SIZE * 8
+  }
+}
+
+impl Storable for CertifiedNodeId {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let mut vec = Vec::with_capacity(2 + CERTIFIED_KEY_SIZE);
+    vec.extend_from_slice(&self.bits.to_be_bytes());
+    vec.extend_from_slice(&self.prefix);
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    Self {
+      bits: u16::from_be_bytes(bytes[..2].try_into().unwrap()),
+      prefix: bytes[2..].try_into().unwrap(),
+    }
+  }
+
+  const BOUND: Bound = Bound::Bounded {
+    max_size: 2 + CERTIFIED_KEY_SIZE as u32,
+    is_fixed_size: true,
+  };
+}
+
+/// A node of a certified tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertifiedNode {
+  /// The hash of the entry's value.
+  Leaf([u8; 32]),
+  /// The children by the bit after the prefix, and the hash of the subtree as
+  /// of the last time it was rehashed.
+  Fork {
+    hash: [u8; 32],
+    left: CertifiedNodeId,
+    right: CertifiedNodeId,
+  },
+}
+
+impl Storable for CertifiedNode {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let mut vec = Vec::with_capacity(109);
+    match self {
+      Self::Leaf(value) => {
+        vec.push(0);
+        vec.extend_from_slice(value);
+      }
+      Self::Fork { hash, left, right } => {
+        vec.push(1);
+        vec.extend_from_slice(hash);
+        vec.extend_from_slice(&left.to_bytes());
+        vec.extend_from_slice(&right.to_bytes());
+      }
+    }
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    let hash = bytes[1..33].try_into().unwrap();
+    match bytes[0] {
+      0 => Self::Leaf(hash),
+      _ => Self::Fork {
+        hash,
+        left: CertifiedNodeId::from_bytes(Cow::Borrowed(&bytes[33..71])),
+        right: CertifiedNodeId::from_bytes(Cow::Borrowed(&bytes[71..109])),
+      },
+    }
+  }
+
+  const BOUND: Bound = Bound::Bounded {
+    max_size: 109,
+    is_fixed_size: false,
+  };
+}
+
+/// How far rebuilding the certified trees has got, by the last key added to
+/// each, with `None` once that tree is done. Both `None` when not rebuilding.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CertifiedRebuild {
+  pub rune_balances: Option<Option<Vec<u8>>>,
+  pub inscriptions: Option<Option<u32>>,
+}
+
+impl Storable for CertifiedRebuild {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Layout version of the stable maps, and how far the migration to the next
+/// version has got.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SchemaVersion {
+  pub version: u32,
+  pub cursor: Option<Vec<u8>>,
+}
+
+impl Storable for SchemaVersion {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum SnapshotStage {
+  #[default]
+  None,
+  Exporting,
+  Exported,
+  Importing,
+  /// The export stopped at an entry of `table` too large for a chunk.
+  Oversized { table: u8, bytes: u64 },
+}
+
+/// A snapshot of the index at a height, being exported from this canister or
+/// imported into it.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+  pub stage: SnapshotStage,
+  pub height: u32,
+  pub block_hash: BlockHashValue,
+  pub commitment: Option<[u8; 32]>,
+  /// Entries of each snapshot table.
+  pub entries: Vec<u64>,
+  pub chunks: u32,
+  /// Chained over the hashes of all chunks, in order.
+  pub hash: [u8; 32],
+  /// Table and last key hashed so far, while the snapshot is being exported.
+  pub cursor: Option<(u8, Option<Vec<u8>>)>,
+  /// Chunks and chained hash received so far, while it is being imported.
+  pub imported: (u32, [u8; 32]),
+}
+
+impl Storable for Snapshot {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(self).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    bincode::deserialize(&bytes).unwrap()
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Per-block totals recorded while indexing, for block explorer pages.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BlockSummary {
+  pub fees: u64,
+  pub inscription_transfers: u64,
+  pub rune_burns: u64,
+  pub rune_etchings: u64,
+  pub rune_mints: u64,
+  pub rune_transfers: u64,
+  pub transaction_count: u32,
+}
+
+impl Storable for BlockSummary {
   fn to_bytes(&self) -> Cow<[u8]> {
     let vec = bincode::serialize(self).unwrap();
     Cow::Owned(vec)
@@ -585,6 +1214,32 @@ impl Storable for MyOutPoints {
 
 pub(super) type OutPointValue = [u8; 36];
 
+/// Key of the address index: a script pubkey and one of its outpoints, ordered
+/// by script pubkey first so an address's outpoints are a single range.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ScriptPubkeyOutPoint {
+  pub script_pubkey: Vec<u8>,
+  pub outpoint: OutPointValue,
+}
+
+impl Storable for ScriptPubkeyOutPoint {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let mut vec = Vec::with_capacity(36 + self.script_pubkey.len());
+    vec.extend_from_slice(&self.outpoint);
+    vec.extend_from_slice(&self.script_pubkey);
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    Self {
+      outpoint: bytes[..36].try_into().unwrap(),
+      script_pubkey: bytes[36..].to_vec(),
+    }
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
+
 impl Entry for OutPoint {
   type Value = OutPointValue;
 
@@ -642,6 +1297,34 @@ impl Entry for SatRange {
   }
 }
 
+pub(super) type BlockHashValue = [u8; 32];
+
+impl Entry for BlockHash {
+  type Value = BlockHashValue;
+
+  fn load(value: Self::Value) -> Self {
+    BlockHash::from_byte_array(value)
+  }
+
+  fn store(self) -> Self::Value {
+    BlockHash::to_byte_array(self)
+  }
+}
+
+pub(super) type PrincipalValue = Vec<u8>;
+
+impl Entry for Principal {
+  type Value = PrincipalValue;
+
+  fn load(value: Self::Value) -> Self {
+    Principal::from_slice(&value)
+  }
+
+  fn store(self) -> Self::Value {
+    self.as_slice().to_vec()
+  }
+}
+
 pub(super) type TxidValue = [u8; 32];
 
 impl Entry for Txid {
@@ -667,19 +1350,55 @@ pub struct RuneBalances {
   pub balances: Vec<RuneBalance>,
 }
 
+/// Each balance as varints of its rune id block and tx and its amount, in
+/// order, until the end of the value.
 impl Storable for RuneBalances {
   fn to_bytes(&self) -> Cow<[u8]> {
-    let vec = bincode::serialize(self).unwrap();
-    Cow::Owned(vec)
+    let mut writer = Writer::new(1 + 12 * self.balances.len());
+    for balance in &self.balances {
+      let (block, tx) = balance.rune_id.store();
+      writer
+        .varint(block.into())
+        .varint(tx.into())
+        .varint(balance.balance);
+    }
+    Cow::Owned(writer.finish())
   }
 
   fn from_bytes(bytes: Cow<[u8]>) -> Self {
-    bincode::deserialize(&bytes).unwrap()
+    let mut reader = Reader::new(&bytes);
+    let mut balances = Vec::new();
+    while !reader.is_empty() {
+      let block = reader.varint().try_into().unwrap();
+      let tx = reader.varint().try_into().unwrap();
+      balances.push(RuneBalance {
+        rune_id: RuneId::load((block, tx)),
+        balance: reader.varint(),
+      });
+    }
+    Self { balances }
   }
 
   const BOUND: Bound = Bound::Unbounded;
 }
 
+/// Bincode encoding of a value, which the hot entry types used before their
+/// compact encodings. Only read by the migration to schema version 3.
+#[derive(Debug, Clone)]
+pub struct Bincode<T>(pub T);
+
+impl<T: Serialize + DeserializeOwned> Storable for Bincode<T> {
+  fn to_bytes(&self) -> Cow<[u8]> {
+    let vec = bincode::serialize(&self.0).unwrap();
+    Cow::Owned(vec)
+  }
+
+  fn from_bytes(bytes: Cow<[u8]>) -> Self {
+    Self(bincode::deserialize(&bytes).unwrap())
+  }
+
+  const BOUND: Bound = Bound::Unbounded;
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChangeRecordRune {
@@ -745,6 +1464,189 @@ use super::*;
     assert_eq!(InscriptionEntry::load(value), entry);
   }
 
+  #[test]
+  fn inscription_entry_encoding() {
+    let mut entry = InscriptionEntry {
+      charms: 1,
+      fee: 300,
+      height: 840_000,
+      id: "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdefi2"
+        .parse()
+        .unwrap(),
+      inscription_number: -5,
+      parents: vec![4, 5],
+      sat: Some(Sat(7)),
+      sequence_number: 8,
+      timestamp: 9,
+    };
+    assert_eq!(InscriptionEntry::from_bytes(entry.to_bytes()), entry);
+    assert!(entry.to_bytes().len() < bincode::serialize(&entry).unwrap().len());
+
+    entry.parents.clear();
+    entry.sat = None;
+    assert_eq!(InscriptionEntry::from_bytes(entry.to_bytes()), entry);
+  }
+
+  #[test]
+  fn rune_entry_encoding() {
+    let entry = RuneEntry {
+      block: 840_000,
+      burned: 1,
+      divisibility: 2,
+      etching: Txid::from_byte_array([3; 32]),
+      mints: 4,
+      number: 5,
+      premine: 6,
+      spaced_rune: SpacedRune {
+        rune: Rune(7),
+        spacers: 8,
+      },
+      symbol: Some('ᚠ'),
+      terms: Some(Terms {
+        cap: Some(9),
+        height: (None, Some(10)),
+        amount: Some(11),
+        offset: (Some(12), None),
+      }),
+      timestamp: 13,
+      turbo: true,
+    };
+    assert_eq!(entry.to_bytes().len(), RUNE_ENTRY_SIZE);
+    assert_eq!(RuneEntry::from_bytes(entry.to_bytes()), entry);
+
+    let entry = RuneEntry::default();
+    assert_eq!(entry.to_bytes().len(), RUNE_ENTRY_SIZE);
+    assert_eq!(RuneEntry::from_bytes(entry.to_bytes()), entry);
+  }
+
+  #[test]
+  fn rune_balances_encoding() {
+    let balances = RuneBalances {
+      balances: vec![
+        RuneBalance {
+          rune_id: RuneId { block: 840_000, tx: 1 },
+          balance: u128::MAX,
+        },
+        RuneBalance {
+          rune_id: RuneId { block: 2, tx: 0 },
+          balance: 100,
+        },
+      ],
+    };
+    let decoded = RuneBalances::from_bytes(balances.to_bytes());
+    assert_eq!(
+      decoded.balances.iter().map(|b| (b.rune_id, b.balance)).collect::<Vec<_>>(),
+      balances.balances.iter().map(|b| (b.rune_id, b.balance)).collect::<Vec<_>>(),
+    );
+    assert!(balances.to_bytes().len() < bincode::serialize(&balances).unwrap().len());
+    assert!(RuneBalances::from_bytes(RuneBalances { balances: vec![] }.to_bytes())
+      .balances
+      .is_empty());
+  }
+
+  #[test]
+  fn index_feature() {
+    for feature in [
+      IndexFeature::Addresses,
+      IndexFeature::Inscriptions,
+      IndexFeature::Runes,
+      IndexFeature::Sats,
+      IndexFeature::Transactions,
+    ] {
+      assert_eq!(IndexFeature::load(feature.store()), feature);
+    }
+  }
+
+  #[test]
+  fn role_includes_lower_roles() {
+    assert!(Role::Admin > Role::Operator);
+    assert!(Role::Operator > Role::Reader);
+    assert_eq!(Role::from_bytes(Role::Operator.to_bytes()), Role::Operator);
+
+    let principal = Principal::from_slice(&[1, 2, 3]);
+    assert_eq!(Principal::load(principal.store()), principal);
+  }
+
+  #[test]
+  fn script_pubkey_outpoint() {
+    let key = ScriptPubkeyOutPoint {
+      script_pubkey: vec![0x51, 0x20, 7],
+      outpoint: [3; 36],
+    };
+    assert_eq!(ScriptPubkeyOutPoint::from_bytes(key.to_bytes()), key);
+  }
+
+  #[test]
+  fn checkpoint_undo_key() {
+    let key = CheckpointUndoKey {
+      epoch: 258,
+      table: 9,
+      key: vec![1, 2, 3],
+    };
+    assert_eq!(CheckpointUndoKey::from_bytes(key.to_bytes()), key);
+    assert!(
+      CheckpointUndoKey { epoch: 1, table: 10, key: vec![] }.to_bytes()
+        < CheckpointUndoKey { epoch: 256, table: 0, key: vec![] }.to_bytes()
+    );
+  }
+
+  #[test]
+  fn certified_node() {
+    let left = CertifiedNodeId::leaf([1; CERTIFIED_KEY_SIZE]);
+    let mut prefix = [0; CERTIFIED_KEY_SIZE];
+    prefix[0] = 0x80;
+    let right = CertifiedNodeId { bits: 7, prefix };
+    for node in [
+      CertifiedNode::Leaf([2; 32]),
+      CertifiedNode::Fork {
+        hash: [3; 32],
+        left,
+        right,
+      },
+    ] {
+      assert_eq!(CertifiedNode::from_bytes(node.to_bytes()), node);
+    }
+    assert!(right.to_bytes() < left.to_bytes());
+  }
+
+  #[test]
+  fn inscription_attributes() {
+    let delegate = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdefi0"
+      .parse::<InscriptionId>()
+      .unwrap();
+
+    let inscription = Inscription {
+      body: Some(b"hello".to_vec()),
+      content_encoding: Some(b"br".to_vec()),
+      content_type: Some(b"text/plain".to_vec()),
+      delegate: Some(delegate.value()),
+      metaprotocol: Some(b"brc-20".to_vec()),
+      rune: Some(Rune(300).commitment()),
+      ..default()
+    };
+
+    assert_eq!(
+      InscriptionAttributes::from(&inscription),
+      InscriptionAttributes {
+        content_encoding: Some("br".into()),
+        content_length: Some(5),
+        content_type: Some("text/plain".into()),
+        delegate: Some(delegate),
+        metaprotocol: Some("brc-20".into()),
+        rune: Some(Rune(300)),
+      }
+    );
+
+    assert_eq!(
+      InscriptionAttributes::from(&Inscription {
+        rune: Some(vec![1; 17]),
+        ..default()
+      })
+      .rune,
+      None
+    );
+  }
+
   #[test]
   fn inscription_id_entry() {
     let inscription_id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdefi0"
@@ -0,0 +1,111 @@
+use ordinals::varint;
+
+/// Leads every compact encoding, so a later layout can be told apart.
+pub(super) const VERSION: u8 = 1;
+
+/// Little endian integers, fixed-width options and varints, appended to a
+/// buffer that starts with the encoding version.
+pub(super) struct Writer(Vec<u8>);
+
+impl Writer {
+  pub(super) fn new(capacity: usize) -> Self {
+    let mut vec = Vec::with_capacity(capacity);
+    vec.push(VERSION);
+    Self(vec)
+  }
+
+  pub(super) fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+    self.0.extend_from_slice(bytes);
+    self
+  }
+
+  pub(super) fn u8(&mut self, n: u8) -> &mut Self {
+    self.bytes(&[n])
+  }
+
+  pub(super) fn u16(&mut self, n: u16) -> &mut Self {
+    self.bytes(&n.to_le_bytes())
+  }
+
+  pub(super) fn u32(&mut self, n: u32) -> &mut Self {
+    self.bytes(&n.to_le_bytes())
+  }
+
+  pub(super) fn u64(&mut self, n: u64) -> &mut Self {
+    self.bytes(&n.to_le_bytes())
+  }
+
+  pub(super) fn u128(&mut self, n: u128) -> &mut Self {
+    self.bytes(&n.to_le_bytes())
+  }
+
+  /// A presence byte followed by the value, or by its default when absent, so
+  /// the width doesn't depend on it.
+  pub(super) fn option<T: Default>(&mut self, value: Option<T>, write: impl FnOnce(&mut Self, T) -> &mut Self) -> &mut Self {
+    self.u8(value.is_some().into());
+    write(self, value.unwrap_or_default())
+  }
+
+  pub(super) fn varint(&mut self, n: u128) -> &mut Self {
+    varint::encode_to_vec(n, &mut self.0);
+    self
+  }
+
+  pub(super) fn finish(&mut self) -> Vec<u8> {
+    std::mem::take(&mut self.0)
+  }
+}
+
+/// Reads back what a [`Writer`] wrote, panicking like the bincode decoders on
+/// bytes it didn't.
+pub(super) struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+  pub(super) fn new(bytes: &'a [u8]) -> Self {
+    let (version, rest) = bytes.split_first().expect("empty encoding");
+    assert_eq!(*version, VERSION, "unknown encoding version {version}");
+    Self(rest)
+  }
+
+  pub(super) fn array<const N: usize>(&mut self) -> [u8; N] {
+    let (head, rest) = self.0.split_at(N);
+    self.0 = rest;
+    head.try_into().unwrap()
+  }
+
+  pub(super) fn u8(&mut self) -> u8 {
+    self.array::<1>()[0]
+  }
+
+  pub(super) fn u16(&mut self) -> u16 {
+    u16::from_le_bytes(self.array())
+  }
+
+  pub(super) fn u32(&mut self) -> u32 {
+    u32::from_le_bytes(self.array())
+  }
+
+  pub(super) fn u64(&mut self) -> u64 {
+    u64::from_le_bytes(self.array())
+  }
+
+  pub(super) fn u128(&mut self) -> u128 {
+    u128::from_le_bytes(self.array())
+  }
+
+  pub(super) fn option<T>(&mut self, read: impl FnOnce(&mut Self) -> T) -> Option<T> {
+    let present = self.u8() != 0;
+    let value = read(self);
+    present.then_some(value)
+  }
+
+  pub(super) fn varint(&mut self) -> u128 {
+    let (n, len) = varint::decode(self.0).unwrap();
+    self.0 = &self.0[len..];
+    n
+  }
+
+  pub(super) fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+}
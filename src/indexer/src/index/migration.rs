@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use ic_canister_log::log;
+use logs::{ERROR, INFO};
+
+use crate::{
+  index::{
+    checkpoint,
+    entry::{CertifiedRebuild, SchemaVersion, Snapshot},
+    mem_get_config, mem_get_schema_version, mem_set_certified_rebuild, mem_set_config, mem_set_schema_version,
+    mem_set_snapshot, mem_reencode_legacy_entries, mem_split_legacy_multimaps, status,
+  },
+  Result,
+};
+
+/// A change to the layout of the stable maps.
+struct Migration {
+  description: &'static str,
+  /// Migrates the entries after `cursor` while `within_budget` allows, and
+  /// returns the cursor to continue from, or `None` once everything is migrated.
+  migrate: fn(Option<Vec<u8>>) -> Result<Option<Vec<u8>>>,
+}
+
+/// Migration `i` moves the stable maps from schema version `i` to `i + 1`.
+/// New migrations are only ever appended.
+const MIGRATIONS: &[Migration] = &[
+  Migration {
+    description: "store the config as candid",
+    migrate: reencode_config,
+  },
+  Migration {
+    description: "store multimaps with one entry per member",
+    migrate: split_multimaps,
+  },
+  Migration {
+    description: "store hot entries in compact encodings",
+    migrate: reencode_entries,
+  },
+  Migration {
+    description: "record the output script of each inscription",
+    migrate: shift_tables,
+  },
+  Migration {
+    description: "keep the certified trees in stable memory",
+    migrate: build_certified_trees,
+  },
+];
+
+/// The schema version this build reads and writes.
+pub(crate) const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Instructions a message spends on migrations before continuing in the next
+/// one, leaving headroom below the limits of upgrades and timers.
+pub(crate) const INSTRUCTIONS_PER_MESSAGE: u64 = 20_000_000_000;
+
+pub(crate) fn within_budget() -> bool {
+  crate::instruction_counter() < INSTRUCTIONS_PER_MESSAGE
+}
+
+/// Whether the stable maps still have to be migrated before indexing.
+pub(crate) fn is_pending() -> bool {
+  mem_get_schema_version().version < SCHEMA_VERSION
+}
+
+/// Records a freshly installed canister as being on the current schema.
+pub(crate) fn set_current() -> Result {
+  mem_set_schema_version(SchemaVersion {
+    version: SCHEMA_VERSION,
+    cursor: None,
+  })?;
+  Ok(())
+}
+
+fn reencode_config(_: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+  mem_set_config(mem_get_config())?;
+  Ok(None)
+}
+
+/// Checkpoints and snapshot exports or imports hold raw entries in the old
+/// layout, so migrations changing it drop them before their first batch.
+fn discard_raw_entries(cursor: &Option<Vec<u8>>) -> Result {
+  if cursor.is_none() {
+    checkpoint::discard_where(|_, _| true);
+    mem_set_snapshot(Snapshot::default())?;
+  }
+  Ok(())
+}
+
+fn split_multimaps(cursor: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+  discard_raw_entries(&cursor)?;
+  Ok(mem_split_legacy_multimaps().then(Vec::new))
+}
+
+fn reencode_entries(cursor: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+  discard_raw_entries(&cursor)?;
+  Ok(mem_reencode_legacy_entries().then(Vec::new))
+}
+
+/// A new map moved the positions checkpoint and snapshot chunks refer to
+/// tables by. It starts out empty, so only the raw entries need to go.
+fn shift_tables(cursor: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+  discard_raw_entries(&cursor)?;
+  Ok(None)
+}
+
+/// The certified trees used to be heap only and rebuilt after every upgrade.
+/// When certified queries are on, they are now built once in stable memory.
+fn build_certified_trees(_: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+  if mem_get_config().certified_queries.unwrap_or_default() {
+    mem_set_certified_rebuild(CertifiedRebuild {
+      rune_balances: Some(None),
+      inscriptions: Some(None),
+    })?;
+  }
+  Ok(None)
+}
+
+/// Runs pending migrations for as long as the budget of the message allows.
+/// Returns whether any are left.
+fn step() -> Result<bool> {
+  let mut schema = mem_get_schema_version();
+  if schema.version > SCHEMA_VERSION {
+    return Err(anyhow!(
+      "stable memory has schema version {}, but this build only supports up to {}",
+      schema.version,
+      SCHEMA_VERSION
+    ));
+  }
+
+  while schema.version < SCHEMA_VERSION && within_budget() {
+    let migration = &MIGRATIONS[schema.version as usize];
+    if schema.cursor.is_none() {
+      log!(
+        INFO,
+        "migrating schema to version {}: {}",
+        schema.version + 1,
+        migration.description
+      );
+    }
+    schema.cursor = (migration.migrate)(schema.cursor.take())?;
+    if schema.cursor.is_none() {
+      schema.version += 1;
+      log!(INFO, "migrated schema to version {}", schema.version);
+    }
+    mem_set_schema_version(schema.clone())?;
+  }
+
+  Ok(schema.version < SCHEMA_VERSION)
+}
+
+/// Migrates the stable maps to the current schema, continuing in further
+/// messages when one isn't enough, and calls `done` once they are migrated.
+///
+/// The first batch runs right away, so an upgrade from a newer schema or with a
+/// failing migration is rejected before it goes through.
+pub(crate) fn migrate(done: fn()) -> Result {
+  if step()? {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || resume(done));
+  } else {
+    done();
+  }
+  Ok(())
+}
+
+fn resume(done: fn()) {
+  match step() {
+    Ok(true) => {
+      ic_cdk_timers::set_timer(Duration::ZERO, move || resume(done));
+    }
+    Ok(false) => done(),
+    Err(e) => {
+      // shown by get_status, since indexing never resumes
+      let error = format!("schema migration failed: {:?}", e);
+      log!(ERROR, "{}", error);
+      status::record_failure(error);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+
+  use super::*;
+  use crate::{
+    index::{
+      entry::{Bincode, Checkpoint, Entry, RuneEntry, SequenceNumbers},
+      mem_checkpoints, mem_get_first_seq_of_sat_to_sequence_numbers, mem_get_rune_id_to_rune_entry,
+      mem_insert_checkpoint, LEGACY_RUNE_ID_TO_RUNE_ENTRY, LEGACY_SAT_TO_SEQUENCE_NUMBERS,
+    },
+    set_instruction_counter,
+  };
+
+  thread_local! {
+    static DONE: Cell<bool> = const { Cell::new(false) };
+  }
+
+  fn done() {
+    DONE.with(|done| done.set(true));
+  }
+
+  fn set_version(version: u32) {
+    mem_set_schema_version(SchemaVersion { version, cursor: None }).unwrap();
+  }
+
+  #[test]
+  fn migrations_continue_where_the_budget_ran_out() {
+    set_version(1);
+    LEGACY_SAT_TO_SEQUENCE_NUMBERS.with(|m| m.borrow_mut().insert(1, SequenceNumbers(vec![3, 4, 5])));
+    let rune_id = ordinals::RuneId { block: 1, tx: 0 }.store();
+    LEGACY_RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow_mut().insert(rune_id, Bincode(RuneEntry::default())));
+    mem_insert_checkpoint(
+      10,
+      Checkpoint {
+        block_hash: [0; 32],
+        runes: true,
+        utxo_entries: false,
+        chunks: 0,
+        epoch: 0,
+        cursor: None,
+        restoring: None,
+      },
+    );
+    assert!(is_pending());
+
+    // enough budget for the first two members of the list
+    set_instruction_counter(INSTRUCTIONS_PER_MESSAGE - 3_000_000);
+    assert!(step().unwrap());
+    assert_eq!(
+      mem_get_schema_version(),
+      SchemaVersion {
+        version: 1,
+        cursor: Some(Vec::new()),
+      }
+    );
+    assert!(mem_checkpoints().is_empty());
+    assert_eq!(mem_get_first_seq_of_sat_to_sequence_numbers(1), Some(3));
+    assert!(is_pending());
+
+    set_instruction_counter(0);
+    resume(done);
+    assert!(DONE.with(Cell::get));
+    assert_eq!(
+      mem_get_schema_version(),
+      SchemaVersion {
+        version: SCHEMA_VERSION,
+        cursor: None,
+      }
+    );
+    assert!(!is_pending());
+    assert_eq!(LEGACY_SAT_TO_SEQUENCE_NUMBERS.with(|m| m.borrow().len()), 0);
+    assert_eq!(mem_get_rune_id_to_rune_entry(rune_id), Some(RuneEntry::default()));
+    assert_eq!(LEGACY_RUNE_ID_TO_RUNE_ENTRY.with(|m| m.borrow().len()), 0);
+  }
+
+  #[test]
+  fn newer_schema_is_refused() {
+    set_version(SCHEMA_VERSION + 1);
+    set_instruction_counter(0);
+    assert!(step().is_err());
+    assert!(migrate(done).is_err());
+    assert!(!DONE.with(Cell::get));
+  }
+}
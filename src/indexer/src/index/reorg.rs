@@ -1,18 +1,20 @@
 use std::fmt::{self, Display, Formatter};
 
-use bitcoin::BlockHash;
+use anyhow::anyhow;
+use bitcoin::{block::Header, BlockHash};
 use ic_canister_log::log;
 use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
-use logs::INFO;
+use logs::{ERROR, INFO};
 
-use super::{mem_block_hash, updater::BlockData, Index};
+use super::{checkpoint, mem_block_hash, mem_get_config, mem_latest_block_height, mem_remove_blocks_above, updater::{backfill::{features_above, reset_features_above}, BlockData}, Index};
 
-use crate::{bitcoin_api::get_block_hash, index::{entry::Entry, mem_prune_block_header, mem_prune_change_record_rune, mem_prune_statistic_reserved_runes, mem_prune_statistic_runes}};
+use crate::{block_source, index::{entry::Entry, mem_prune_change_record_rune, mem_prune_statistic_reserved_runes, mem_prune_statistic_runes}};
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum Error {
   Recoverable { height: u32, depth: u32 },
   Unrecoverable,
+  Unavailable { height: u32 },
 }
 
 impl Display for Error {
@@ -22,13 +24,20 @@ impl Display for Error {
         write!(f, "{depth} block deep reorg detected at height {height}")
       }
       Self::Unrecoverable => write!(f, "unrecoverable reorg detected"),
+      Self::Unavailable { height } => write!(f, "block hash at height {height} is unavailable"),
     }
   }
 }
 
 impl std::error::Error for Error {}
 
-const MAX_RECOVERABLE_REORG_DEPTH: u32 = 6;
+/// How many blocks back reorgs are rolled back with change records.
+pub(crate) fn reorg_window() -> u32 {
+  mem_get_config().get_reorg_window()
+}
+
+/// How far below the reorg window the fork of a deeper reorg is searched for.
+const MAX_FORK_SEARCH_DEPTH: u32 = 1_000;
 
 pub(crate) struct Reorg {}
 
@@ -38,15 +47,17 @@ impl Reorg {
     match index_prev_blockhash {
       Some(index_prev_blockhash) if index_prev_blockhash == bitcoind_prev_blockhash => Ok(()),
       Some(index_prev_blockhash) if index_prev_blockhash != bitcoind_prev_blockhash => {
-        for depth in 1..MAX_RECOVERABLE_REORG_DEPTH {
+        for depth in 1..reorg_window() {
           let index_block_hash = mem_block_hash(height.checked_sub(depth).expect("height overflow"))
             .ok_or(Error::Unrecoverable)?;
           let bitcoin_height = height.checked_sub(depth).expect("height overflow");
-          let block_hash = get_block_hash(network, bitcoin_height)
+          // rpc failures show up as missing hashes and must not be mistaken for a reorg
+          let bitcoind_block_hash = block_source::get()
+            .block_hash(network, bitcoin_height)
             .await
-            .map_err(|_| Error::Unrecoverable)?;
-
-          let bitcoind_block_hash =  block_hash.ok_or(Error::Unrecoverable)?;
+            .ok()
+            .flatten()
+            .ok_or(Error::Unavailable { height: bitcoin_height })?;
 
           if index_block_hash == bitcoind_block_hash {
             return Err(Error::Recoverable { height, depth });
@@ -65,6 +76,7 @@ impl Reorg {
       "rolling back state after reorg of depth {depth} at height {height}"
     );
 
+    let mut orphaned = Vec::new();
     for h in (height - depth + 1..height).rev() {
       log!(INFO, "rolling back change record at height {h}");
       if index.index_runes && h >= index.first_rune_height() {
@@ -118,12 +130,16 @@ impl Reorg {
         crate::index::mem_remove_change_record_rune(h);
         crate::index::mem_remove_statistic_runes(h);
         crate::index::mem_remove_statistic_reserved_runes(h);
-        crate::index::mem_remove_block_header(h);
       }
+      orphaned.extend(crate::index::mem_remove_block_header(h).map(|header| Header::load(header).block_hash()));
+      crate::index::mem_remove_block_summary(h);
+      crate::index::mem_remove_commitment(h);
       if index.index_inscriptions || index.index_addresses || index.index_sats {
         // TODO: implement
       }
     }
+    crate::index::mem_evict_cached_transactions_in(&orphaned);
+    checkpoint::discard_where(|h, _| h > height - depth);
 
     log!(
       INFO,
@@ -132,14 +148,181 @@ impl Reorg {
     );
   }
 
+  /// Recovers from a reorg deeper than the reorg window, detected while indexing
+  /// `height`. Finds the fork and restores the newest checkpoint below it, and
+  /// returns the height indexing continues after.
+  ///
+  /// Without a checkpoint, the features holding blocks above the fork have to
+  /// be indexed again from their first height. That is left to an admin with
+  /// `reset_index`, and `None` returned, unless `reset_on_deep_reorg` is on.
+  /// Once none of them holds such blocks, the headers above the fork are
+  /// dropped and indexing continues after it.
+  pub(crate) async fn recover(network: BitcoinNetwork, height: u32) -> crate::Result<Option<u32>> {
+    let tip = height - 1;
+    let lowest = tip.saturating_sub(reorg_window() + MAX_FORK_SEARCH_DEPTH);
+
+    let mut fork = None;
+    for h in (lowest..tip).rev() {
+      let Some(index_block_hash) = mem_block_hash(h) else {
+        break;
+      };
+      let bitcoind_block_hash = block_source::get()
+        .block_hash(network, h)
+        .await?
+        .ok_or_else(|| anyhow!("block hash at height {h} is unavailable"))?;
+      if index_block_hash == bitcoind_block_hash {
+        fork = Some(h);
+        break;
+      }
+    }
+    let fork = fork.ok_or_else(|| anyhow!("no common block with the chain since height {lowest}"))?;
+
+    log!(
+      INFO,
+      "recovering from reorg of depth {} at height {height}",
+      tip - fork
+    );
+
+    if let Some(restored) = checkpoint::restore(fork)? {
+      return Ok(Some(restored));
+    }
+    let above = features_above(fork);
+    if !above.is_empty() && !mem_get_config().reset_on_deep_reorg.unwrap_or_default() {
+      log!(
+        ERROR,
+        "reorg at height {height} forks at height {fork}, below every checkpoint; reset_index {:?} to index them again",
+        above
+      );
+      return Ok(None);
+    }
+    mem_remove_blocks_above(fork);
+    reset_features_above(fork, None)?;
+
+    Ok(Some(fork))
+  }
+
+  /// Rolls the index back so that `height` becomes the latest indexed block,
+  /// using the change records kept for the last few blocks. Further back, it
+  /// restores the newest checkpoint at or below `height` instead. Returns the
+  /// height the index was rolled back to.
+  pub(crate) fn rewind(height: u32, index: &Index) -> crate::Result<u32> {
+    let tip = mem_latest_block_height().ok_or_else(|| anyhow!("no block indexed yet"))?;
+    if height >= tip {
+      return Err(anyhow!("height {height} is not below the indexed tip {tip}"));
+    }
+    let window = reorg_window();
+    let utxo_entries = index.index_inscriptions || index.index_addresses || index.index_sats;
+    if tip - height <= window && !utxo_entries && Self::is_recorded(tip + 1, tip + 1 - height, index) {
+      Self::handle_reorg(tip + 1, tip + 1 - height, index);
+      return Ok(height);
+    }
+
+    checkpoint::restore(height)?.ok_or_else(|| {
+      if utxo_entries {
+        anyhow!("inscription, sat and address indexes have no change records, and there is no checkpoint at or below height {height} to roll them back to (see set_checkpoint_interval)")
+      } else {
+        anyhow!("change records only cover the last {window} blocks below the tip {tip} and there is no checkpoint at or below height {height}")
+      }
+    })
+  }
+
+  /// Whether change records are kept for every block a reorg at `height` of
+  /// `depth` rolls back. After the window is raised, the blocks that were
+  /// outside it have none.
+  pub(crate) fn is_recorded(height: u32, depth: u32, index: &Index) -> bool {
+    !index.index_runes
+      || (height - depth + 1..height)
+        .filter(|h| *h >= index.first_rune_height())
+        .all(|h| crate::index::mem_get_change_record_rune(h).is_some())
+  }
+
   pub(crate) fn prune_change_record(height: u32) {
-    if height >= MAX_RECOVERABLE_REORG_DEPTH {
-      let h = height - MAX_RECOVERABLE_REORG_DEPTH;
+    let window = reorg_window();
+    if height >= window {
+      let h = height - window;
       log!(INFO, "clearing change record at height {h}");
       mem_prune_change_record_rune(h);
       mem_prune_statistic_runes(h);
       mem_prune_statistic_reserved_runes(h);
-      mem_prune_block_header(h);
     }
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use std::rc::Rc;
+
+  use futures::executor::block_on;
+
+  use super::*;
+  use crate::{
+    block_source::{BlockSource, Fake},
+    config::Config,
+    index::{fetch_transaction, mem_get_cached_transaction, mem_insert_block_header},
+    test::{address, outpoint, tx_in, tx_out},
+  };
+
+  fn block(fake: &Fake, height: u32) -> BlockData {
+    let hash = block_on(fake.block_hash(BitcoinNetwork::Regtest, height)).unwrap().unwrap();
+    block_on(fake.block(hash)).unwrap()
+  }
+
+  #[test]
+  fn reorg_is_detected_against_block_source() {
+    let fake = Rc::new(Fake::default());
+    block_source::set(fake.clone());
+    for height in 0..5 {
+      fake.mine(Vec::new());
+      mem_insert_block_header(height, block(&fake, height).header.store());
+    }
+
+    fake.invalidate_above(2);
+    for _ in 0..3 {
+      fake.mine(Vec::new());
+    }
+
+    assert_eq!(
+      block_on(Reorg::detect_reorg(BitcoinNetwork::Regtest, mem_block_hash(4), &block(&fake, 5), 5)),
+      Err(Error::Recoverable { height: 5, depth: 3 })
+    );
+    assert_eq!(
+      block_on(Reorg::detect_reorg(BitcoinNetwork::Regtest, Some(block(&fake, 4).header.block_hash()), &block(&fake, 5), 5)),
+      Ok(())
+    );
+  }
+
+  #[test]
+  fn rollback_evicts_transactions_cached_from_orphaned_blocks() {
+    let fake = Rc::new(Fake::default());
+    block_source::set(fake.clone());
+    let transaction = bitcoin::Transaction {
+      version: bitcoin::transaction::Version::TWO,
+      lock_time: bitcoin::absolute::LockTime::ZERO,
+      input: vec![tx_in(outpoint(1))],
+      output: vec![tx_out(1000, address(0))],
+    };
+    fake.mine(Vec::new());
+    fake.mine(vec![transaction.clone()]);
+    for height in 0..2 {
+      mem_insert_block_header(height, block(&fake, height).header.store());
+    }
+    let txid = transaction.compute_txid();
+    block_on(fetch_transaction(txid)).unwrap();
+    assert!(mem_get_cached_transaction(txid).is_some());
+
+    Reorg::handle_reorg(2, 2, &Index::from_config(&Config::default()));
+
+    assert_eq!(mem_latest_block_height(), Some(0));
+    assert!(mem_get_cached_transaction(txid).is_none());
+  }
+
+  #[test]
+  fn reorgs_past_the_change_records_are_not_recorded() {
+    let index = Index::from_config(&Config::default());
+    crate::index::mem_insert_change_record_rune(4, crate::index::entry::ChangeRecordRune::new());
+    assert!(!Reorg::is_recorded(5, 3, &index));
+
+    crate::index::mem_insert_change_record_rune(3, crate::index::entry::ChangeRecordRune::new());
+    assert!(Reorg::is_recorded(5, 3, &index));
+  }
+}
@@ -0,0 +1,416 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bitcoin::BlockHash;
+use ic_canister_log::log;
+use logs::{ERROR, INFO};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  config::Config,
+  index::{
+    certification,
+    checkpoint::{self, Table},
+    entry::{Entry, IndexFeature, Snapshot, SnapshotStage},
+    mem_backfills, mem_checkpoint_table_len, mem_get_commitment, mem_get_snapshot, mem_insert_commitment,
+    mem_latest_block, mem_latest_block_height, mem_read_checkpoint_table, mem_set_snapshot,
+    mem_write_checkpoint_table, migration,
+    updater::backfill::enabled_features,
+  },
+  Result,
+};
+
+/// Every map a snapshot carries, in the order its chunks come in. Roles, the
+/// config, the transaction cache and checkpoints belong to the canister rather
+/// than to the index and are left out.
+pub(crate) const TABLES: [Table; 28] = [
+  checkpoint::TABLES[0],
+  checkpoint::TABLES[1],
+  checkpoint::TABLES[2],
+  checkpoint::TABLES[3],
+  checkpoint::TABLES[4],
+  checkpoint::TABLES[5],
+  checkpoint::TABLES[6],
+  checkpoint::TABLES[7],
+  checkpoint::TABLES[8],
+  checkpoint::TABLES[9],
+  checkpoint::TABLES[10],
+  checkpoint::TABLES[11],
+  checkpoint::TABLES[12],
+  checkpoint::TABLES[13],
+  checkpoint::TABLES[14],
+  checkpoint::TABLES[15],
+  checkpoint::TABLES[16],
+  checkpoint::TABLES[17],
+  checkpoint::TABLES[18],
+  checkpoint::TABLES[19],
+  checkpoint::TABLES[20],
+  checkpoint::TABLES[21],
+  checkpoint::TABLES[22],
+  checkpoint::TABLES[23],
+  Table::HeightToBlockHeader,
+  Table::HeightToBlockSummary,
+  Table::BlockHashToHeight,
+  Table::HeightToCommitment,
+];
+
+/// Entries in a single chunk.
+const CHUNK_ENTRIES: usize = 1_000;
+
+/// Bytes of keys and values after which a chunk is closed. The entry that
+/// crosses it is still included.
+const CHUNK_BYTES: usize = 1_000_000;
+
+/// Largest entry a chunk can carry, so that a chunk stays below
+/// `CHUNK_BYTES + MAX_ENTRY_BYTES`, within the 2MiB of an ingress message to
+/// `import_snapshot_chunk`. Only stored transactions get this large.
+const MAX_ENTRY_BYTES: usize = 800_000;
+
+/// Raw entries of one table, and where the chunk after it starts.
+pub(crate) struct Chunk {
+  pub(crate) table: u8,
+  pub(crate) entries: Vec<(Vec<u8>, Vec<u8>)>,
+  pub(crate) next: Option<(u8, Option<Vec<u8>>)>,
+}
+
+/// `sha256(table || (key length || key || value length || value)*)`, with
+/// lengths as little endian `u32`s.
+pub(crate) fn chunk_hash(table: u8, entries: &[(Vec<u8>, Vec<u8>)]) -> [u8; 32] {
+  let mut hasher = Sha256::new();
+  hasher.update([table]);
+  for (key, value) in entries {
+    hasher.update((key.len() as u32).to_le_bytes());
+    hasher.update(key);
+    hasher.update((value.len() as u32).to_le_bytes());
+    hasher.update(value);
+  }
+  hasher.finalize().into()
+}
+
+/// The snapshot hash after one more chunk: `sha256(hash || chunk_hash)`,
+/// starting from 32 zero bytes.
+fn chain(hash: [u8; 32], chunk_hash: [u8; 32]) -> [u8; 32] {
+  let mut hasher = Sha256::new();
+  hasher.update(hash);
+  hasher.update(chunk_hash);
+  hasher.finalize().into()
+}
+
+pub(crate) fn network_name(config: &Config) -> String {
+  format!("{:?}", config.network).to_lowercase()
+}
+
+/// Whether a snapshot is being exported or imported, during which the index
+/// must not change.
+pub(crate) fn in_progress() -> bool {
+  matches!(
+    mem_get_snapshot().stage,
+    SnapshotStage::Exporting | SnapshotStage::Importing
+  )
+}
+
+/// Drops an exported snapshot once the index moves on from its height. The
+/// download is over by then, so the commitment chain can be seeded.
+pub(crate) fn discard_export() -> Result {
+  let snapshot = mem_get_snapshot();
+  if matches!(
+    snapshot.stage,
+    SnapshotStage::Exported | SnapshotStage::Oversized { .. }
+  ) {
+    if snapshot.stage == SnapshotStage::Exported {
+      seed_commitments(&snapshot);
+    }
+    log!(INFO, "discarding exported snapshot");
+    mem_set_snapshot(Snapshot::default())?;
+  }
+  Ok(())
+}
+
+/// Seeds the commitment chain of a canister that indexed blocks before
+/// commitments were recorded with the snapshot hash at the snapshot height. The
+/// exporting and the importing canister then continue the same chain, as does
+/// any other canister exporting a snapshot of the same state there. Only done
+/// once the chunks were read, as it changes a table they carry.
+fn seed_commitments(snapshot: &Snapshot) {
+  if snapshot.commitment.is_none() && mem_latest_block_height() == Some(snapshot.height) {
+    log!(INFO, "seeding state commitments at height {} with the snapshot hash", snapshot.height);
+    mem_insert_commitment(snapshot.height, snapshot.hash);
+  }
+}
+
+/// The chunk at the cursor, skipping empty tables, or `None` past the last one.
+pub(crate) fn read_chunk(mut table: u8, mut after: Option<Vec<u8>>) -> Option<Chunk> {
+  loop {
+    let current = TABLES.get(usize::from(table)).copied()?;
+    let entries = mem_read_checkpoint_table(current, after.take(), CHUNK_ENTRIES, CHUNK_BYTES);
+    if entries.is_empty() {
+      table += 1;
+      continue;
+    }
+
+    let bytes: usize = entries.iter().map(|(key, value)| key.len() + value.len()).sum();
+    let next = if entries.len() == CHUNK_ENTRIES || bytes >= CHUNK_BYTES {
+      Some((table, entries.last().map(|(key, _)| key.clone())))
+    } else {
+      (usize::from(table) + 1 < TABLES.len()).then_some((table + 1, None))
+    };
+    return Some(Chunk { table, entries, next });
+  }
+}
+
+/// Starts hashing every table at the indexed tip, over as many messages as it
+/// takes. The indexer has to stay stopped until the snapshot is downloaded.
+pub(crate) fn begin_export() -> Result {
+  if in_progress() {
+    return Err(anyhow!("a snapshot is already being exported or imported"));
+  }
+  if !mem_backfills().is_empty() {
+    return Err(anyhow!("features are still being backfilled"));
+  }
+  if checkpoint::is_restoring() {
+    return Err(anyhow!("a checkpoint is still being restored"));
+  }
+  let (height, block_hash) = mem_latest_block().ok_or_else(|| anyhow!("no block indexed yet"))?;
+
+  log!(INFO, "exporting snapshot at height {}", height);
+  mem_set_snapshot(Snapshot {
+    stage: SnapshotStage::Exporting,
+    height,
+    block_hash: block_hash.store(),
+    commitment: mem_get_commitment(height),
+    entries: vec![0; TABLES.len()],
+    cursor: Some((0, None)),
+    ..Default::default()
+  })?;
+  ic_cdk_timers::set_timer(Duration::ZERO, continue_export);
+  Ok(())
+}
+
+fn continue_export() {
+  let mut snapshot = mem_get_snapshot();
+  if snapshot.stage != SnapshotStage::Exporting {
+    return;
+  }
+
+  while migration::within_budget() {
+    let Some((table, after)) = snapshot.cursor.take() else {
+      break;
+    };
+    if let Some(chunk) = read_chunk(table, after) {
+      let largest = chunk
+        .entries
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .max()
+        .unwrap_or_default();
+      if largest > MAX_ENTRY_BYTES {
+        snapshot.stage = SnapshotStage::Oversized {
+          table: chunk.table,
+          bytes: largest as u64,
+        };
+        break;
+      }
+      snapshot.entries[usize::from(chunk.table)] += chunk.entries.len() as u64;
+      snapshot.hash = chain(snapshot.hash, chunk_hash(chunk.table, &chunk.entries));
+      snapshot.chunks += 1;
+      snapshot.cursor = chunk.next;
+    }
+  }
+
+  let done = snapshot.stage != SnapshotStage::Exporting || snapshot.cursor.is_none();
+  if let SnapshotStage::Oversized { table, bytes } = snapshot.stage {
+    log!(ERROR, "{}", oversized(table, bytes));
+  } else if done {
+    snapshot.stage = SnapshotStage::Exported;
+    log!(
+      INFO,
+      "exported snapshot at height {} in {} chunks",
+      snapshot.height,
+      snapshot.chunks
+    );
+  }
+  if let Err(e) = mem_set_snapshot(snapshot) {
+    log!(ERROR, "failed to record snapshot export: {:?}", e);
+    return;
+  }
+  if !done {
+    ic_cdk_timers::set_timer(Duration::ZERO, continue_export);
+  }
+}
+
+fn oversized(table: u8, bytes: u64) -> String {
+  format!(
+    "the snapshot can't be exported: {:?} has an entry of {} bytes, more than the {} a chunk can carry",
+    TABLES[usize::from(table)],
+    bytes,
+    MAX_ENTRY_BYTES
+  )
+}
+
+/// Picks an export back up after an upgrade dropped its timer.
+pub(crate) fn resume() {
+  if mem_get_snapshot().stage == SnapshotStage::Exporting {
+    ic_cdk_timers::set_timer(Duration::ZERO, continue_export);
+  }
+}
+
+/// The snapshot whose chunks can be downloaded.
+pub(crate) fn exported() -> Result<Snapshot> {
+  let snapshot = mem_get_snapshot();
+  match snapshot.stage {
+    SnapshotStage::Exported => Ok(snapshot),
+    SnapshotStage::Exporting => Err(anyhow!("the snapshot is still being exported")),
+    SnapshotStage::Oversized { table, bytes } => Err(anyhow!(oversized(table, bytes))),
+    _ => Err(anyhow!("no snapshot has been exported")),
+  }
+}
+
+/// Prepares a freshly installed canister to receive the chunks of `snapshot`,
+/// exported by a canister with the same schema, network and features.
+pub(crate) fn begin_import(
+  snapshot: Snapshot,
+  schema_version: u32,
+  network: &str,
+  features: &[IndexFeature],
+  config: &Config,
+) -> Result {
+  if mem_get_snapshot().stage != SnapshotStage::None {
+    return Err(anyhow!("a snapshot is already being exported or imported"));
+  }
+  if mem_latest_block_height().is_some() {
+    return Err(anyhow!("snapshots can only be imported before any block is indexed"));
+  }
+  if schema_version != migration::SCHEMA_VERSION {
+    return Err(anyhow!(
+      "the snapshot has schema version {}, but this build is on {}",
+      schema_version,
+      migration::SCHEMA_VERSION
+    ));
+  }
+  if network != network_name(config) {
+    return Err(anyhow!("the snapshot is of {}, but this indexer is on {}", network, network_name(config)));
+  }
+  if features != enabled_features(config) {
+    return Err(anyhow!(
+      "the snapshot was indexed with {:?}, but this indexer has {:?} enabled",
+      features,
+      enabled_features(config)
+    ));
+  }
+  if snapshot.entries.len() != TABLES.len() {
+    return Err(anyhow!("the snapshot has {} tables instead of {}", snapshot.entries.len(), TABLES.len()));
+  }
+
+  log!(INFO, "importing snapshot at height {}", snapshot.height);
+  mem_set_snapshot(Snapshot {
+    stage: SnapshotStage::Importing,
+    cursor: None,
+    imported: (0, [0; 32]),
+    ..snapshot
+  })?;
+  Ok(())
+}
+
+/// Writes the next chunk, in the order they were exported, after checking it
+/// against its hash.
+pub(crate) fn import_chunk(table: u8, entries: Vec<(Vec<u8>, Vec<u8>)>, hash: [u8; 32]) -> Result {
+  let mut snapshot = mem_get_snapshot();
+  if snapshot.stage != SnapshotStage::Importing {
+    return Err(anyhow!("no snapshot is being imported"));
+  }
+  let current = TABLES
+    .get(usize::from(table))
+    .copied()
+    .ok_or_else(|| anyhow!("unknown table {}", table))?;
+  if chunk_hash(table, &entries) != hash {
+    return Err(anyhow!("chunk {} doesn't match its hash", snapshot.imported.0));
+  }
+
+  mem_write_checkpoint_table(current, entries);
+  snapshot.imported = (snapshot.imported.0 + 1, chain(snapshot.imported.1, hash));
+  mem_set_snapshot(snapshot)?;
+  Ok(())
+}
+
+/// Checks the imported maps against the manifest and hands them over to the
+/// indexer. Returns the height indexing continues from.
+pub(crate) fn finish_import() -> Result<u32> {
+  let snapshot = mem_get_snapshot();
+  if snapshot.stage != SnapshotStage::Importing {
+    return Err(anyhow!("no snapshot is being imported"));
+  }
+  if snapshot.imported != (snapshot.chunks, snapshot.hash) {
+    return Err(anyhow!(
+      "{} of {} chunks imported, or they don't match the snapshot hash",
+      snapshot.imported.0,
+      snapshot.chunks
+    ));
+  }
+  for (table, entries) in TABLES.iter().zip(&snapshot.entries) {
+    let len = mem_checkpoint_table_len(*table);
+    if len != *entries {
+      return Err(anyhow!("{:?} has {} entries instead of {}", table, len, entries));
+    }
+  }
+  if mem_latest_block() != Some((snapshot.height, BlockHash::load(snapshot.block_hash))) {
+    return Err(anyhow!("the imported tip doesn't match the snapshot"));
+  }
+  if mem_get_commitment(snapshot.height) != snapshot.commitment {
+    return Err(anyhow!("the imported state commitment doesn't match the snapshot"));
+  }
+
+  log!(INFO, "imported snapshot at height {}", snapshot.height);
+  seed_commitments(&snapshot);
+  mem_set_snapshot(Snapshot::default())?;
+  // the chunks were written past the certified trees
+  certification::rebuild()?;
+  Ok(snapshot.height)
+}
+
+#[cfg(test)]
+mod tests {
+  use bitcoin::{constants::genesis_block, Network};
+
+  use super::*;
+  use crate::index::{mem_insert_block_header, mem_remove_commitment};
+
+  #[test]
+  fn tables_extend_checkpoint_tables() {
+    assert!(TABLES
+      .iter()
+      .enumerate()
+      .all(|(position, table)| *table as usize == position));
+    assert_eq!(TABLES[..checkpoint::TABLES.len()], checkpoint::TABLES);
+  }
+
+  #[test]
+  fn chunk_hash_covers_table_and_entry_boundaries() {
+    let entries = vec![(vec![1, 2], vec![3])];
+    assert_eq!(chunk_hash(0, &entries), chunk_hash(0, &entries));
+    assert_ne!(chunk_hash(0, &entries), chunk_hash(1, &entries));
+    assert_ne!(chunk_hash(0, &entries), chunk_hash(0, &[(vec![1], vec![2, 3])]));
+    assert_ne!(chain([0; 32], chunk_hash(0, &entries)), chunk_hash(0, &entries));
+  }
+
+  #[test]
+  fn exported_snapshot_seeds_missing_commitments() {
+    mem_insert_block_header(5, genesis_block(Network::Regtest).header.store());
+    for (commitment, seeded) in [(Some([1; 32]), [1; 32]), (None, [7; 32])] {
+      mem_remove_commitment(5);
+      if let Some(commitment) = commitment {
+        mem_insert_commitment(5, commitment);
+      }
+      mem_set_snapshot(Snapshot {
+        stage: SnapshotStage::Exported,
+        height: 5,
+        commitment,
+        hash: [7; 32],
+        ..Default::default()
+      })
+      .unwrap();
+      discard_export().unwrap();
+      assert_eq!(mem_get_commitment(5), Some(seeded));
+      assert_eq!(mem_get_snapshot(), Snapshot::default());
+    }
+  }
+}
@@ -0,0 +1,218 @@
+use std::{cell::RefCell, time::Duration};
+
+use crate::{
+  index::{mem_get_unfinished_block, mem_set_unfinished_block},
+  Result,
+};
+
+/// A loop that hasn't reported progress for this long is considered stalled.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+pub(crate) const WATCHDOG_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum State {
+  Running,
+  Stopped,
+  Stalled,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Status {
+  running: bool,
+  generation: u64,
+  heartbeat: u64,
+  block_in_flight: Option<u32>,
+  pub(crate) last_indexed: Option<(u32, u64)>,
+  pub(crate) last_error: Option<(String, u64)>,
+  pub(crate) consecutive_failures: u32,
+}
+
+impl Status {
+  /// Starts a new loop generation, superseding whichever loop was scheduled before.
+  fn begin(&mut self, now: u64) -> u64 {
+    self.generation += 1;
+    self.running = true;
+    self.heartbeat = now;
+    self.generation
+  }
+
+  fn abandon(&mut self) {
+    self.generation += 1;
+    self.running = false;
+  }
+
+  fn exited(&mut self, generation: u64) {
+    if self.generation == generation {
+      self.running = false;
+    }
+  }
+
+  fn success(&mut self, height: u32, now: u64) {
+    self.heartbeat = now;
+    self.last_indexed = Some((height, now));
+    self.consecutive_failures = 0;
+  }
+
+  fn failure(&mut self, error: String, now: u64) {
+    self.heartbeat = now;
+    self.last_error = Some((error, now));
+    self.consecutive_failures += 1;
+  }
+
+  pub(crate) fn state(&self, now: u64) -> State {
+    if !self.running {
+      State::Stopped
+    } else if now.saturating_sub(self.heartbeat) > STALL_TIMEOUT.as_nanos() as u64 {
+      State::Stalled
+    } else {
+      State::Running
+    }
+  }
+}
+
+thread_local! {
+  static STATUS: RefCell<Status> = RefCell::new(Status::default());
+}
+
+fn now() -> u64 {
+  crate::time()
+}
+
+pub(crate) fn begin() -> u64 {
+  STATUS.with(|status| status.borrow_mut().begin(now()))
+}
+
+/// The generation of the newest loop, which is 0 until one is started.
+pub(crate) fn generation() -> u64 {
+  STATUS.with(|status| status.borrow().generation)
+}
+
+pub(crate) fn is_current(generation: u64) -> bool {
+  STATUS.with(|status| status.borrow().generation == generation)
+}
+
+pub(crate) fn exited(generation: u64) {
+  STATUS.with(|status| status.borrow_mut().exited(generation))
+}
+
+/// Gives up on a loop that trapped or hangs, which never reports that it
+/// exited. It stops at its next await, if it ever resumes. A block it left
+/// unfinished is still recorded.
+pub(crate) fn abandon() {
+  STATUS.with(|status| status.borrow_mut().abandon())
+}
+
+pub(crate) fn heartbeat() {
+  STATUS.with(|status| status.borrow_mut().heartbeat = now())
+}
+
+pub(crate) fn record_success(height: u32) {
+  STATUS.with(|status| status.borrow_mut().success(height, now()))
+}
+
+pub(crate) fn record_failure(error: String) {
+  STATUS.with(|status| status.borrow_mut().failure(error, now()))
+}
+
+/// Marks `height` as partially written until the matching `end_block`, in
+/// stable memory too, so it outlives a trap or an upgrade.
+pub(crate) fn begin_block(height: u32) -> Result {
+  STATUS.with(|status| status.borrow_mut().block_in_flight = Some(height));
+  mem_set_unfinished_block(Some(height))?;
+  Ok(())
+}
+
+pub(crate) fn end_block() -> Result {
+  STATUS.with(|status| status.borrow_mut().block_in_flight = None);
+  mem_set_unfinished_block(None)?;
+  Ok(())
+}
+
+/// The block a running loop is in the middle of indexing, if any.
+///
+/// A block left behind by a loop that trapped or stalled is not reported here,
+/// but it isn't re-indexed cleanly either: its writes so far stay in the maps.
+/// It shows in `unfinished_block`, and is rolled back before the next loop
+/// starts.
+pub(crate) fn block_in_flight() -> Option<u32> {
+  STATUS.with(|status| {
+    let status = status.borrow();
+    if status.state(now()) == State::Running {
+      status.block_in_flight
+    } else {
+      None
+    }
+  })
+}
+
+/// The block some loop started indexing and hasn't committed yet, whatever
+/// state that loop is in now, including loops from before an upgrade.
+pub(crate) fn unfinished_block() -> Option<u32> {
+  mem_get_unfinished_block()
+}
+
+pub(crate) fn status() -> Status {
+  STATUS.with(|status| status.borrow().clone())
+}
+
+pub(crate) fn state() -> State {
+  STATUS.with(|status| status.borrow().state(now()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MINUTE: u64 = 60_000_000_000;
+
+  #[test]
+  fn loop_state() {
+    let mut status = Status::default();
+    assert_eq!(status.state(0), State::Stopped);
+
+    let generation = status.begin(0);
+    assert_eq!(status.state(MINUTE), State::Running);
+    assert_eq!(status.state(31 * MINUTE), State::Stalled);
+
+    status.failure("boom".into(), 31 * MINUTE);
+    status.failure("boom".into(), 32 * MINUTE);
+    assert_eq!(status.consecutive_failures, 2);
+    assert_eq!(status.state(33 * MINUTE), State::Running);
+
+    status.success(100, 33 * MINUTE);
+    assert_eq!(status.consecutive_failures, 0);
+    assert_eq!(status.last_indexed, Some((100, 33 * MINUTE)));
+    assert_eq!(status.last_error, Some(("boom".into(), 32 * MINUTE)));
+
+    status.exited(generation);
+    assert_eq!(status.state(34 * MINUTE), State::Stopped);
+  }
+
+  #[test]
+  fn superseded_loop_does_not_stop_its_successor() {
+    let mut status = Status::default();
+    let old = status.begin(0);
+    let new = status.begin(MINUTE);
+    assert_ne!(old, new);
+
+    status.exited(old);
+    assert_eq!(status.state(MINUTE), State::Running);
+
+    status.exited(new);
+    assert_eq!(status.state(MINUTE), State::Stopped);
+  }
+
+  #[test]
+  fn abandoned_loop_is_stopped() {
+    let mut status = Status::default();
+    let generation = status.begin(0);
+    status.block_in_flight = Some(100);
+    assert_eq!(status.state(31 * MINUTE), State::Stalled);
+
+    status.abandon();
+    assert_ne!(status.generation, generation);
+    assert_eq!(status.block_in_flight, Some(100));
+    assert_eq!(status.state(31 * MINUTE), State::Stopped);
+  }
+}
@@ -1,20 +1,20 @@
 use std::collections::HashMap;
 
 use bitcoin::{block::Header, Block, OutPoint, Transaction, Txid};
-use bitcoincore_rpc_json::GetRawTransactionResult;
-use candid::Principal;
 use ic_canister_log::log;
 use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 use inscription_updater::InscriptionUpdater;
 use logs::{ERROR, INFO};
 use ordinals::{Height, Rune, Sat, SatPoint};
 use rune_updater::RuneUpdater;
-use crate::{index::{entry::{ChangeRecordRune, Entry, SatRange}, event::Events, mem_get_home_inscriptions_len, mem_get_next_sequence_of_sequence_number_to_inscription_entry, mem_get_outpoint_to_utxo_entry, mem_get_statistic_count, mem_increment_statistic, mem_insert_height_to_last_sequence_number, mem_insert_outpoint_to_utxo_entry, mem_insert_script_pubkey_to_outpoints, mem_insert_sequence_number_to_satpoint, mem_insert_statistic_to_count, mem_latest_block, mem_length_outpoint_to_height, mem_length_outpoint_to_rune_balances, mem_length_rune_id_to_rune_entry, mem_length_rune_to_rune_id, mem_length_transaction_id_to_rune, mem_remove_outpoint_to_utxo_entry, mem_remove_script_pubkey_to_outpoints, mem_statistic_reserved_runes, mem_statistic_runes, utxo_entry::{ParsedUtxoEntry, UtxoEntryBuf}, Statistic}, timestamp, Result};
+use crate::{index::{entry::{BlockSummary, ChangeRecordRune, Entry, SatRange}, event::{Event, Events}, fetch_transaction, mem_get_home_inscriptions_len, mem_get_next_sequence_of_sequence_number_to_inscription_entry, mem_get_outpoint_to_utxo_entry, mem_get_statistic_count, mem_increment_statistic, mem_insert_height_to_last_sequence_number, mem_insert_outpoint_to_utxo_entry, mem_insert_script_pubkey_to_outpoints, mem_insert_sequence_number_to_satpoint, mem_insert_statistic_to_count, mem_set_sequence_number_to_script_pubkey, mem_latest_block, mem_length_outpoint_to_height, mem_length_outpoint_to_rune_balances, mem_length_rune_id_to_rune_entry, mem_length_rune_to_rune_id, mem_length_transaction_id_to_rune, mem_remove_outpoint_to_utxo_entry, mem_remove_script_pubkey_to_outpoints, mem_statistic_reserved_runes, mem_statistic_runes, utxo_entry::{ParsedUtxoEntry, UtxoEntryBuf}, Statistic}, block_source::{self, BlockSource}, timestamp, Result};
 
-use super::{is_shutting_down, mem_insert_block_header, mem_insert_sat_to_satpoint, next_block, reorg::{self, Reorg}, Index};
+use super::{certification, checkpoint, commitment, is_shutting_down, mem_get_commitment, mem_insert_commitment, mem_get_config, migration, mem_get_indexer_running, mem_set_indexer_running, mem_insert_block_header, mem_remove_blocks_above, rpc_tip_is_stale, set_rpc_tip_height, mem_insert_block_summary, mem_insert_sat_to_satpoint, next_block, reorg::{self, Reorg}, snapshot, status, Index};
 
+pub(crate) mod backfill;
 mod inscription_updater;
 mod rune_updater;
+pub(crate) mod simulation;
 
 pub(crate) struct BlockData {
     pub(crate) header: Header,
@@ -46,112 +46,265 @@ pub(crate) struct Updater {
     pub(super) outputs_https_outcall: u64,
 }
 
-pub fn update_index(network: BitcoinNetwork, index: Index, subscribers: Vec<Principal>) -> Result {
+/// Starts a fresh indexing loop from the stored config, superseding any loop
+/// that is already scheduled.
+pub fn start_index_loop() -> Result {
+    if migration::is_pending() {
+      return Err(anyhow::anyhow!("the stable memory schema is still being migrated, indexing starts once it is done"));
+    }
+    if snapshot::in_progress() {
+      return Err(anyhow::anyhow!("a snapshot is being exported or imported, indexing starts once it is done"));
+    }
+    if let Some(height) = status::block_in_flight() {
+      return Err(anyhow::anyhow!("block {} is still being indexed by the previous loop, start again once it is committed", height));
+    }
+    if let Some(height) = status::unfinished_block() {
+      roll_back_unfinished_block(height)?;
+    }
+    snapshot::discard_export()?;
+    update_index(mem_get_config().network, status::begin())
+}
+
+/// Rolls back a block a trapped or abandoned loop left partly indexed, which
+/// would otherwise be indexed again on top of its own writes. Those can't be
+/// told apart from the rest, so the newest checkpoint below it is restored,
+/// or without one every feature that may hold them is indexed again from its
+/// first height.
+fn roll_back_unfinished_block(height: u32) -> Result {
+    log!(ERROR, "block {} was left partly indexed, rolling it back", height);
+    let below = height.saturating_sub(1);
+    if checkpoint::restore(below)?.is_none() {
+      mem_remove_blocks_above(below);
+      backfill::reset_features_above(below, None)?;
+    }
+    status::end_block()?;
+    certification::certify();
+    Ok(())
+}
+
+/// Starts the indexing loop if it was running before the canister was
+/// installed or upgraded.
+pub fn resume_index_loop() {
+    if mem_get_indexer_running() {
+      log!(INFO, "resuming index loop");
+      if let Err(e) = start_index_loop() {
+        log!(ERROR, "failed to resume index loop: {:?}", e);
+      }
+    }
+}
+
+/// Restarts the indexing loop whenever it has exited or stalled while it should
+/// be running.
+pub fn start_watchdog() {
+    ic_cdk_timers::set_timer_interval(status::WATCHDOG_INTERVAL, || {
+      if !mem_get_indexer_running() || is_shutting_down() || migration::is_pending() || snapshot::in_progress() {
+        return;
+      }
+      let state = status::state();
+      if state != status::State::Running {
+        log!(INFO, "watchdog restarting {:?} index loop", state);
+        if let Err(e) = start_index_loop() {
+          log!(ERROR, "watchdog failed to restart index loop: {:?}", e);
+        }
+      }
+    });
+}
+
+fn record_failure(error: String) {
+    log!(ERROR, "{}", error);
+    status::record_failure(error);
+}
+
+fn update_index(network: BitcoinNetwork, generation: u64) -> Result {
     ic_cdk_timers::set_timer(std::time::Duration::from_secs(10), move || {
       ic_cdk::spawn(async move {
-        let (height, index_prev_blockhash) = next_block(&index);
-        match crate::bitcoin_api::get_block_hash(network, height).await {
-          Ok(Some(block_hash)) => match crate::rpc::get_block(block_hash).await {
-            Ok(block) => {
-              match Reorg::detect_reorg(
-                network,
-                index_prev_blockhash,
-                &block,
-                height,
-              )
-              .await
-              {
-                Ok(()) => {
-                  let txids: Vec<String> = block
-                    .txdata
-                    .iter()
-                    .map(|(_, txid)| txid.to_string())
-                    .collect();
-                  if let Err(e) = index_block(height, &block, &index).await {
-                    log!(
-                      ERROR,
-                      "failed to index_block at height {}: {:?}",
-                      height,
-                      e
-                    );
-                  } else {
-                    Reorg::prune_change_record(height);
-                    mem_insert_block_header(height, block.header.store());
-                    log!(
-                      INFO,
-                      "indexed block_height: {} block_hash: {}",
-                      height,
-                      block_hash.to_string()
-                    );
-                    for subscriber in subscribers.iter() {
-                      let _ = crate::notifier::notify_new_block(
-                        *subscriber,
-                        height,
-                        block_hash.to_string(),
-                        txids.clone(),
-                      )
-                      .await;
-                      log!(
-                        INFO,
-                        "notified subscriber: {:?} with block_height: {:?} block_hash: {:?}",
-                        subscriber,
-                        height,
-                        block_hash
-                      );
-                    }
-                  }
+        if let Some(height) = index_round(block_source::get().as_ref(), network, generation).await {
+          reschedule(network, generation, height);
+        }
+      });
+    });
+  
+    Ok(())
+  }
+
+/// Indexes the next block of `source` if there is one and runs the background
+/// work, returning the height to go on from or `None` once the loop is over.
+async fn index_round(source: &dyn BlockSource, network: BitcoinNetwork, generation: u64) -> Option<u32> {
+    if !status::is_current(generation) {
+      return None;
+    }
+    status::heartbeat();
+    // features may be switched on by a finished backfill
+    let index = Index::from_config(&mem_get_config());
+    let (height, index_prev_blockhash) = next_block(&index);
+    // the index has to stay put while a checkpoint is written or restored
+    match checkpoint::step() {
+      Ok(false) => {}
+      Ok(true) => return Some(height),
+      Err(e) => {
+        record_failure(format!("failed to restore checkpoint: {:?}", e));
+        status::exited(generation);
+        return None;
+      }
+    }
+    if rpc_tip_is_stale(height) {
+      match source.block_count().await {
+        Ok(tip) => set_rpc_tip_height(tip),
+        Err(e) => log!(ERROR, "failed to get_block_count: {:?}", e),
+      }
+    }
+    match source.block_hash(network, height).await {
+      Ok(Some(block_hash)) => match source.block(block_hash).await {
+        Ok(block) => {
+          // the watchdog may have replaced this loop while it was waiting
+          if !status::is_current(generation) {
+            return None;
+          }
+          match Reorg::detect_reorg(
+            network,
+            index_prev_blockhash,
+            &block,
+            height,
+          )
+          .await
+          {
+            Ok(()) => {
+              let txids: Vec<String> = block
+                .txdata
+                .iter()
+                .map(|(_, txid)| txid.to_string())
+                .collect();
+              if let Err(e) = status::begin_block(height) {
+                record_failure(format!("failed to record block {} as in flight: {:?}", height, e));
+                status::exited(generation);
+                return None;
+              }
+              let result = index_block(height, &block, &index, generation).await;
+              // a superseded loop must not write anything more
+              if !status::is_current(generation) {
+                log!(ERROR, "index loop superseded while indexing block {}", height);
+                return None;
+              }
+              if let Err(e) = result {
+                if let Err(e) = status::end_block() {
+                  log!(ERROR, "failed to record block {} as given up: {:?}", height, e);
+                }
+                record_failure(format!("failed to index_block at height {}: {:?}", height, e));
+              } else {
+                Reorg::prune_change_record(height);
+                mem_insert_block_header(height, block.header.store());
+                checkpoint::begin_if_due(height, block_hash);
+                if let Err(e) = status::end_block() {
+                  log!(ERROR, "failed to record block {} as committed: {:?}", height, e);
+                }
+                status::record_success(height);
+                log!(
+                  INFO,
+                  "indexed block_height: {} block_hash: {}",
+                  height,
+                  block_hash.to_string()
+                );
+                // subscribers can change while the loop is running
+                for subscriber in mem_get_config().subscribers.iter() {
+                  let _ = crate::notifier::notify_new_block(
+                    *subscriber,
+                    height,
+                    block_hash.to_string(),
+                    txids.clone(),
+                  )
+                  .await;
+                  log!(
+                    INFO,
+                    "notified subscriber: {:?} with block_height: {:?} block_hash: {:?}",
+                    subscriber,
+                    height,
+                    block_hash
+                  );
                 }
-                Err(e) => match e {
-                  reorg::Error::Recoverable { height, depth } => {
-                    Reorg::handle_reorg(height, depth, &index);
+              }
+            }
+            Err(e) => match e {
+              reorg::Error::Recoverable { height, depth } if Reorg::is_recorded(height, depth, &index) => {
+                Reorg::handle_reorg(height, depth, &index);
+              }
+              reorg::Error::Recoverable { .. } | reorg::Error::Unrecoverable => {
+                log!(
+                  ERROR,
+                  "reorg detected at height {} is not covered by change records",
+                  height
+                );
+                match Reorg::recover(network, height).await {
+                  Ok(Some(_)) => {}
+                  // the watchdog would only run into the same reorg again
+                  Ok(None) => {
+                    record_failure(format!("reorg at height {} reaches below every checkpoint, indexing stopped until an admin resets the affected features", height));
+                    if let Err(e) = mem_set_indexer_running(false) {
+                      log!(ERROR, "failed to record the index loop as stopped: {:?}", e);
+                    }
+                    status::exited(generation);
+                    return None;
                   }
-                  reorg::Error::Unrecoverable => {
-                    log!(
-                      ERROR,
-                      "unrecoverable reorg detected at height {}",
-                      height
-                    );
-                    return;
+                  Err(e) => {
+                    record_failure(format!("failed to recover from reorg at height {}: {:?}", height, e));
+                    status::exited(generation);
+                    return None;
                   }
-                },
+                }
               }
-            }
-            Err(e) => {
-              log!(
-                ERROR,
-                "failed to get_block: {:?} error: {:?}",
-                block_hash,
-                e
-              );
-            }
-          },
-          Ok(None) => {}
-          Err(e) => {
-            log!(
-              ERROR,
-              "failed to get_block_hash at height {}: {:?}",
-              height,
-              e
-            );
-            return;
+              error @ reorg::Error::Unavailable { .. } => {
+                record_failure(format!("failed to detect reorg at height {}: {}", height, error));
+              }
+            },
           }
         }
-        if is_shutting_down() {
-          log!(
-            INFO,
-            "shutting down index thread, skipping update at height {}",
-            height
-          );
-        } else {
-          let _ = update_index(network, index, subscribers);
+        Err(e) => {
+          record_failure(format!("failed to get_block: {:?} error: {:?}", block_hash, e));
         }
-      });
-    });
-  
-    Ok(())
+      },
+      Ok(None) => {}
+      Err(e) => {
+        record_failure(format!("failed to get_block_hash at height {}: {:?}", height, e));
+        status::exited(generation);
+        return None;
+      }
+    }
+    if let Err(e) = backfill::backfill(network).await {
+      record_failure(format!("failed to backfill: {:?}", e));
+    }
+    if let Err(e) = backfill::backfill_inscription_attributes(network).await {
+      record_failure(format!("failed to backfill inscription attributes: {:?}", e));
+    }
+    if let Err(e) = backfill::backfill_headers(index.get_first_index_height()).await {
+      record_failure(format!("failed to backfill block headers: {:?}", e));
+    }
+    certification::certify();
+    Some(height)
+}
+
+fn reschedule(network: BitcoinNetwork, generation: u64, height: u32) {
+    if is_shutting_down() {
+      log!(
+        INFO,
+        "shutting down index thread, skipping update at height {}",
+        height
+      );
+      status::exited(generation);
+    } else if status::is_current(generation) {
+      let _ = update_index(network, generation);
+    }
+}
+
+  /// Fails once `generation` is no longer the current loop, so a superseded
+  /// loop stops at its next await instead of writing on.
+  fn ensure_current(generation: u64) -> Result {
+    if status::is_current(generation) {
+      Ok(())
+    } else {
+      Err(anyhow::anyhow!("index loop {} was superseded", generation))
+    }
   }
 
-  async fn index_block(height: u32, block: &BlockData, index: &Index) -> Result {
+  pub(crate) async fn index_block(height: u32, block: &BlockData, index: &Index, generation: u64) -> Result {
     log!(
       INFO,
       "Block {} at {} with {} transactions…",
@@ -159,16 +312,58 @@ pub fn update_index(network: BitcoinNetwork, index: Index, subscribers: Vec<Prin
       timestamp(block.header.time.into()),
       block.txdata.len()
     );
+    let mut events = Events::new();
     if index.index_inscriptions ||index.index_addresses || index.index_sats {
-      index_utxo_entries(height, block, index).await?;
+      crate::bench_scope!("index_utxo_entries");
+      events.extend(index_utxo_entries(height, block, index).await?);
+      ensure_current(generation)?;
     }
     if index.index_runes && height >= index.first_rune_height() {
-      index_rune(height, block).await?;
+      crate::bench_scope!("index_rune");
+      events.extend(index_rune(height, block, index).await?);
+      ensure_current(generation)?;
+    }
+    {
+      crate::bench_scope!("block_summary");
+      mem_insert_block_summary(height, summarize_block(height, block, &events));
+    }
+    crate::bench_scope!("commitment");
+    let previous = height.checked_sub(1).and_then(mem_get_commitment);
+    if let Some(previous) = commitment::previous(index, height, previous) {
+      let features = commitment::features(index);
+      mem_insert_commitment(height, commitment::commit(previous, height, block.header.block_hash(), features, &events));
     }
     Ok(())
   }
 
-  async fn index_rune(height: u32, block: &BlockData) -> Result {
+  fn summarize_block(height: u32, block: &BlockData, events: &Events) -> BlockSummary {
+    let coinbase_value = block
+      .txdata
+      .first()
+      .map(|(tx, _)| tx.output.iter().map(|txout| txout.value.to_sat()).sum::<u64>())
+      .unwrap_or_default();
+
+    let mut summary = BlockSummary {
+      fees: coinbase_value.saturating_sub(Height(height).subsidy()),
+      transaction_count: block.txdata.len().try_into().unwrap(),
+      ..Default::default()
+    };
+
+    for event in events.iter() {
+      match event {
+        Event::InscriptionCreated { .. } => {}
+        Event::InscriptionTransferred { .. } => summary.inscription_transfers += 1,
+        Event::RuneBurned { .. } => summary.rune_burns += 1,
+        Event::RuneEtched { .. } => summary.rune_etchings += 1,
+        Event::RuneMinted { .. } => summary.rune_mints += 1,
+        Event::RuneTransferred { .. } => summary.rune_transfers += 1,
+      }
+    }
+
+    summary
+  }
+
+  async fn index_rune(height: u32, block: &BlockData, index: &Index) -> Result<Events> {
     let runes = mem_statistic_runes();
     let reserved_runes = mem_statistic_reserved_runes();
   
@@ -196,7 +391,7 @@ pub fn update_index(network: BitcoinNetwork, index: Index, subscribers: Vec<Prin
       block_time: block.header.time,
       burned: HashMap::new(),
       height,
-      minimum: Rune::minimum_at_height(bitcoin::Network::Bitcoin, Height(height)),
+      minimum: Rune::minimum_at_height(index.chain().network(), Height(height)),
       runes,
       change_record: ChangeRecordRune::new(),
       events: Events::new(),
@@ -208,13 +403,11 @@ pub fn update_index(network: BitcoinNetwork, index: Index, subscribers: Vec<Prin
         .await?;
     }
   
-    rune_updater.update()?;
-  
-    Ok(())
+    rune_updater.update()
   }
 
 
-  async fn index_utxo_entries(height: u32, block: &BlockData, index: &Index) -> Result {
+  async fn index_utxo_entries(height: u32, block: &BlockData, index: &Index) -> Result<Events> {
     let mut sat_ranges_written = 0;
     let mut outputs_in_block = 0;
     let index_inscriptions = height >= index.first_inscription_height() && index.index_inscriptions;
@@ -289,8 +482,8 @@ pub fn update_index(network: BitcoinNetwork, index: Index, subscribers: Vec<Prin
                   entry.to_buf()
               } else {
                   assert!(!index.have_full_utxo_index());
-                  let tx_info = get_raw_transaction_info_forever(outpoint.txid).await?;
-                  let txout = tx_info.transaction()?.tx_out(outpoint.vout as usize)?.clone();
+                  let transaction = get_transaction_forever(outpoint.txid).await;
+                  let txout = transaction.tx_out(outpoint.vout as usize)?.clone();
 
                   let mut entry = UtxoEntryBuf::new();
                   entry.push_value(txout.value.to_sat(), index);
@@ -404,13 +597,21 @@ pub fn update_index(network: BitcoinNetwork, index: Index, subscribers: Vec<Prin
       }
 
       *utxo_entry = UtxoEntryBuf::merged(utxo_entry, &new_utxo_entry, index);
-      mem_insert_statistic_to_count(crate::index::Statistic::LostSats, inscription_updater.lost_sats);
-      mem_insert_statistic_to_count(crate::index::Statistic::CursedInscriptions, inscription_updater.cursed_inscription_count);
-      mem_insert_statistic_to_count(crate::index::Statistic::BlessedInscriptions, inscription_updater.blessed_inscription_count);
-      mem_insert_statistic_to_count(crate::index::Statistic::UnboundInscriptions, inscription_updater.unbound_inscriptions);
     }
-    commit(&updater, index, utxo_cache)?;
-    Ok(())
+
+    mem_insert_statistic_to_count(
+      crate::index::Statistic::LostSats,
+      if index.index_sats {
+        lost_sats
+      } else {
+        inscription_updater.lost_sats
+      },
+    );
+    mem_insert_statistic_to_count(crate::index::Statistic::CursedInscriptions, inscription_updater.cursed_inscription_count);
+    mem_insert_statistic_to_count(crate::index::Statistic::BlessedInscriptions, inscription_updater.blessed_inscription_count);
+    mem_insert_statistic_to_count(crate::index::Statistic::UnboundInscriptions, inscription_updater.unbound_inscriptions);
+    commit(&updater, index, block, utxo_cache)?;
+    Ok(inscription_updater.events)
   }
 
   fn index_transaction_output_script_pubkeys(
@@ -511,8 +712,10 @@ pub fn update_index(network: BitcoinNetwork, index: Index, subscribers: Vec<Prin
   fn commit(
     updater: &Updater,
     index: &Index,
+    block: &BlockData,
     utxo_cache: HashMap<OutPoint, UtxoEntryBuf>,
   ) -> Result {
+    crate::bench_scope!("commit");
     log!(INFO,
       "Committing at block height {}, {} outputs traversed, {} in map, {} cached, {} in stable memory, {} HTTPS outcalls",
       updater.height,
@@ -524,6 +727,8 @@ pub fn update_index(network: BitcoinNetwork, index: Index, subscribers: Vec<Prin
     );
 
     {
+      // outputs left in the cache are all from this block
+      let transactions: HashMap<Txid, &Transaction> = block.txdata.iter().map(|(tx, txid)| (*txid, tx)).collect();
       for (outpoint, mut utxo_entry) in utxo_cache {
         if Index::is_special_outpoint(outpoint) {
           if let Some(old_entry) = mem_get_outpoint_to_utxo_entry(outpoint.store()) {
@@ -540,9 +745,14 @@ pub fn update_index(network: BitcoinNetwork, index: Index, subscribers: Vec<Prin
         }
 
         if index.index_inscriptions {
+          let script_pubkey = transactions
+            .get(&outpoint.txid)
+            .and_then(|tx| tx.output.get(outpoint.vout.into_usize()))
+            .map(|txout| txout.script_pubkey.as_bytes());
           for (sequence_number, offset) in utxo_entry.parse_inscriptions() {
             let satpoint = SatPoint { outpoint, offset };
             mem_insert_sequence_number_to_satpoint(sequence_number, satpoint.store());
+            mem_set_sequence_number_to_script_pubkey(sequence_number, script_pubkey);
           }
         }
       }
@@ -554,16 +764,116 @@ pub fn update_index(network: BitcoinNetwork, index: Index, subscribers: Vec<Prin
     Ok(())
   }
 
-  async fn get_raw_transaction_info_forever(txid: Txid) -> Result<GetRawTransactionResult> {
+  async fn get_transaction_forever(txid: Txid) -> Transaction {
     let mut retry_count = 1;
     loop {
-      match crate::rpc::get_raw_transaction_info(&txid, None).await {
-        Ok(info) => return Ok(info),
+      match fetch_transaction(txid).await {
+        Ok((transaction, _)) => return transaction,
         Err(e) => {
-          log!(ERROR, "failed to get_raw_transaction_info: {:?} error: {:?}", txid, e);
+          record_failure(format!("failed to fetch transaction: {:?} error: {:?}", txid, e));
         }
       }
-      log!(INFO, "retrying +{} get_raw_transaction_info: {:?}", retry_count, txid,);
+      log!(INFO, "retrying +{} fetch transaction: {:?}", retry_count, txid,);
       retry_count = retry_count + 1;
     }
-  }
\ No newline at end of file
+  }
+
+#[cfg(test)]
+mod tests {
+  use std::rc::Rc;
+
+  use futures::executor::block_on;
+
+  use super::*;
+  use crate::{
+    block_source::Fake,
+    index::{
+      entry::IndexFeature, inscription_info, mem_backfills, mem_get_block_summary, mem_get_inscription_id_to_sequence_number,
+      mem_get_outpoint_to_rune_balances, mem_get_rune_id_to_rune_entry,
+    },
+    inscriptions::{InscriptionId, InscriptionQuery},
+    test::{address, blocks::{Chain, INSCRIPTIONS, MINTS, RUNE_ID}},
+  };
+
+  /// Runs rounds of the update loop against `source` until one of them no
+  /// longer moves the tip.
+  fn sync(source: &dyn BlockSource) {
+    loop {
+      let tip = mem_latest_block();
+      block_on(index_round(source, BitcoinNetwork::Regtest, status::generation())).unwrap();
+      if mem_latest_block() == tip {
+        return;
+      }
+    }
+  }
+
+  fn mine(fake: &Fake, block: BlockData) -> Vec<Txid> {
+    let txids = block.txdata.iter().map(|(_, txid)| *txid).collect();
+    fake.mine(block.txdata.into_iter().map(|(tx, _)| tx).collect());
+    txids
+  }
+
+  #[test]
+  fn synthetic_blocks_are_indexed_from_the_block_source() {
+    let fake = Rc::new(Fake::default());
+    block_source::set(fake.clone());
+    let mut chain = Chain::new();
+    mine(&fake, chain.funding_block());
+    mine(&fake, chain.etching_block());
+    let transfers = mine(&fake, chain.transfer_block());
+
+    sync(fake.as_ref());
+
+    assert_eq!(mem_latest_block().map(|(height, _)| height), Some(2));
+    assert!((0..=2).all(|height| mem_get_commitment(height).is_some()));
+
+    let etching = mem_get_block_summary(1).unwrap();
+    assert_eq!(etching.rune_etchings, 1);
+    assert!(chain.inscriptions().iter().all(|txid| {
+      mem_get_inscription_id_to_sequence_number(&InscriptionId { txid: *txid, index: 0 }).is_some()
+    }));
+    assert_eq!(chain.inscriptions().len(), INSCRIPTIONS as usize);
+
+    assert_eq!(mem_get_block_summary(2).unwrap().rune_mints, u64::from(MINTS));
+    assert_eq!(mem_get_rune_id_to_rune_entry(RUNE_ID.store()).unwrap().mints, u128::from(MINTS));
+    assert!(mem_get_outpoint_to_rune_balances(OutPoint { txid: chain.etching(), vout: 1 }.store()).is_none());
+    let transferred = mem_get_outpoint_to_rune_balances(OutPoint { txid: transfers[1], vout: 0 }.store()).unwrap();
+    assert_eq!(transferred.balances[0].rune_id, RUNE_ID);
+    assert_eq!(transferred.balances[0].balance, 1_000);
+  }
+
+  #[test]
+  fn inscription_address_is_known_without_the_address_index() {
+    let mut chain = Chain::new();
+    let funding = chain.funding_block();
+    chain.index_block(funding);
+    let etching = chain.etching_block();
+    chain.index_block(etching);
+
+    let mut config = mem_get_config();
+    config.index_addresses = Some(false);
+    let index = Index::from_config(&config);
+    let id = InscriptionId { txid: chain.inscriptions()[1], index: 0 };
+    let info = inscription_info(&index, InscriptionQuery::Id(id), None).unwrap().unwrap();
+    let expected = index.chain().address_from_script(&address(1).script_pubkey()).unwrap();
+    assert_eq!(info.address, Some(expected.to_string()));
+  }
+
+  #[test]
+  fn unfinished_block_is_rolled_back_by_indexing_everything_again() {
+    let mut chain = Chain::new();
+    let funding = chain.funding_block();
+    chain.index_block(funding);
+    let etching = chain.etching_block();
+    status::begin_block(1).unwrap();
+    block_on(index_block(1, &etching, chain.index(), status::generation())).unwrap();
+
+    roll_back_unfinished_block(1).unwrap();
+
+    assert_eq!(status::unfinished_block(), None);
+    assert_eq!(mem_latest_block().map(|(height, _)| height), Some(0));
+    let reset: Vec<IndexFeature> = mem_backfills().into_iter().map(|(feature, _)| feature).collect();
+    assert!(reset.contains(&IndexFeature::Runes));
+    assert!(reset.contains(&IndexFeature::Inscriptions));
+  }
+}
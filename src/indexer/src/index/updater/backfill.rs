@@ -0,0 +1,644 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use bitcoin::Transaction;
+use ic_canister_log::log;
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use logs::INFO;
+
+use crate::{
+  block_source,
+  config::Config,
+  index::{
+    checkpoint,
+    entry::{Backfill, Checkpoint, Entry, IndexFeature, InscriptionAttributes, InscriptionEntry},
+    mem_backfills, mem_first_block_header, mem_get_block_header, mem_insert_block_header, mem_get_config, mem_get_sequence_number_to_inscription_entry,
+    mem_get_transaction_id_to_transaction, mem_insert_backfill, mem_insert_transaction_id_to_transaction,
+    mem_insert_sequence_number_to_inscription_attributes, mem_inscriptions_without_attributes,
+    mem_latest_block_height,
+    mem_length_outpoint_to_rune_balances, mem_length_outpoint_to_utxo_entry,
+    mem_length_rune_id_to_rune_entry, mem_remove_backfill, mem_remove_commitments_from, mem_reset_runes,
+    mem_reset_transactions, mem_reset_utxo_entries, mem_set_config,
+    reorg::{reorg_window, Reorg},
+    Index,
+  },
+  inscriptions::envelope::ParsedEnvelope,
+  Result,
+};
+
+use super::{index_rune, index_utxo_entries, BlockData};
+
+/// Blocks whose inscriptions get their attributes back per round of the update
+/// loop, each fetched once.
+const ATTRIBUTE_BLOCKS_PER_ROUND: u32 = 5;
+
+/// Headers fetched per round of the update loop, one outcall each.
+const HEADERS_PER_ROUND: u32 = 10;
+
+fn is_enabled(config: &Config, feature: IndexFeature) -> bool {
+  match feature {
+    IndexFeature::Addresses => config.index_addresses,
+    IndexFeature::Inscriptions => config.index_inscriptions,
+    IndexFeature::Runes => config.index_runes,
+    IndexFeature::Sats => config.index_sats,
+    IndexFeature::Transactions => config.index_transactions,
+  }
+  .unwrap_or_default()
+}
+
+/// Features currently switched on for new blocks.
+pub(crate) fn enabled_features(config: &Config) -> Vec<IndexFeature> {
+  [
+    IndexFeature::Addresses,
+    IndexFeature::Inscriptions,
+    IndexFeature::Runes,
+    IndexFeature::Sats,
+    IndexFeature::Transactions,
+  ]
+  .into_iter()
+  .filter(|feature| is_enabled(config, *feature))
+  .collect()
+}
+
+fn set_enabled(config: &mut Config, feature: IndexFeature, enabled: bool) {
+  let flag = match feature {
+    IndexFeature::Addresses => &mut config.index_addresses,
+    IndexFeature::Inscriptions => &mut config.index_inscriptions,
+    IndexFeature::Runes => &mut config.index_runes,
+    IndexFeature::Sats => &mut config.index_sats,
+    IndexFeature::Transactions => &mut config.index_transactions,
+  };
+  *flag = Some(enabled);
+}
+
+/// Every feature except runes is built from the same UTXO entries, whose layout
+/// depends on which of them are enabled.
+fn uses_utxo_entries(feature: IndexFeature) -> bool {
+  feature != IndexFeature::Runes
+}
+
+/// Features that change the layout of UTXO entries.
+const UTXO_LAYOUT_FEATURES: [IndexFeature; 3] = [
+  IndexFeature::Addresses,
+  IndexFeature::Inscriptions,
+  IndexFeature::Sats,
+];
+
+/// Starts indexing `features` from their first height. They are only switched
+/// on for new blocks once the backfill has caught up with the indexed tip.
+pub(crate) fn enable_features(features: &[IndexFeature]) -> Result {
+  if features.is_empty() {
+    return Err(anyhow!("no features given"));
+  }
+
+  let config = mem_get_config();
+  let backfills = mem_backfills();
+  for feature in features {
+    if is_enabled(&config, *feature) || backfills.iter().any(|(f, _)| f == feature) {
+      return Err(anyhow!("{:?} is already enabled", feature));
+    }
+  }
+
+  let mut with_features = config.clone();
+  features
+    .iter()
+    .for_each(|feature| set_enabled(&mut with_features, *feature, true));
+  let index = Index::from_config(&with_features);
+
+  let mut starts = Vec::new();
+  if features.contains(&IndexFeature::Runes) {
+    if mem_length_rune_id_to_rune_entry() > 0 || mem_length_outpoint_to_rune_balances() > 0 {
+      return Err(anyhow!("rune data from an earlier run has to be reset first"));
+    }
+    starts.push((vec![IndexFeature::Runes], index.first_rune_height()));
+  }
+
+  let utxo_features: Vec<IndexFeature> = features
+    .iter()
+    .copied()
+    .filter(|feature| uses_utxo_entries(*feature))
+    .collect();
+  if utxo_features == [IndexFeature::Transactions] {
+    let inscriptions = is_enabled(&config, IndexFeature::Inscriptions);
+    if backfills.iter().any(|(feature, _)| uses_utxo_entries(*feature)) {
+      return Err(anyhow!("Transactions can be enabled once the running UTXO backfill has caught up"));
+    }
+    let start_height = if inscriptions {
+      index.first_inscription_height()
+    } else {
+      // only reveal transactions of indexed inscriptions are stored, so there
+      // is nothing to backfill
+      mem_latest_block_height().map_or(0, |tip| tip + 1)
+    };
+    starts.push((utxo_features, start_height));
+  } else if !utxo_features.is_empty() {
+    if UTXO_LAYOUT_FEATURES.iter().any(|feature| is_enabled(&config, *feature))
+      || backfills.iter().any(|(feature, _)| uses_utxo_entries(*feature))
+    {
+      return Err(anyhow!(
+        "{:?} change the layout of the UTXO entries of features that are already indexed, \
+         so they can only be enabled on a fresh canister or rebuilt together with them by reset_index",
+        utxo_features
+      ));
+    }
+    if mem_length_outpoint_to_utxo_entry() > 0 {
+      return Err(anyhow!("UTXO entries from an earlier run have to be reset first"));
+    }
+    let start_height = if with_features.index_sats.unwrap_or_default()
+      || with_features.index_addresses.unwrap_or_default()
+    {
+      0
+    } else {
+      index.first_inscription_height()
+    };
+    starts.push((utxo_features, start_height));
+  }
+
+  let mut config = config;
+  for (features, start_height) in starts {
+    match mem_latest_block_height() {
+      Some(tip) if start_height <= tip => {
+        for feature in features {
+          log!(INFO, "backfilling {:?} from height {}", feature, start_height);
+          mem_insert_backfill(
+            feature,
+            Backfill {
+              start_height,
+              next_height: start_height,
+            },
+          );
+        }
+      }
+      // nothing indexed yet that the features would have covered
+      _ => features
+        .into_iter()
+        .for_each(|feature| set_enabled(&mut config, feature, true)),
+    }
+  }
+  mem_set_config(config)?;
+
+  Ok(())
+}
+
+/// Stops indexing `features` and cancels their backfills. Their data is left in
+/// place and has to be reset before they can be enabled again.
+pub(crate) fn disable_features(features: &[IndexFeature]) -> Result {
+  let mut config = mem_get_config();
+  let backfills = mem_backfills();
+
+  for feature in UTXO_LAYOUT_FEATURES {
+    let active = is_enabled(&config, feature) || backfills.iter().any(|(f, _)| *f == feature);
+    let others_remain = UTXO_LAYOUT_FEATURES
+      .iter()
+      .filter(|other| !features.contains(other))
+      .any(|other| is_enabled(&config, *other) || backfills.iter().any(|(f, _)| f == other));
+    if active && features.contains(&feature) && others_remain {
+      return Err(anyhow!(
+        "{:?} can only be disabled together with {:?}",
+        feature,
+        UTXO_LAYOUT_FEATURES
+      ));
+    }
+  }
+
+  for feature in features {
+    if mem_remove_backfill(*feature).is_some() {
+      log!(INFO, "cancelled backfill of {:?}", feature);
+    }
+    set_enabled(&mut config, *feature, false);
+  }
+  mem_set_config(config)?;
+
+  Ok(())
+}
+
+/// Stores the transactions of `block` that reveal inscriptions, like the
+/// inscription updater does for new blocks.
+fn index_reveal_transactions(block: &BlockData) {
+  for (tx, txid) in &block.txdata {
+    if !ParsedEnvelope::from_transaction(tx).is_empty() {
+      mem_insert_transaction_id_to_transaction(txid.store(), bitcoin::consensus::serialize(tx));
+    }
+  }
+}
+
+/// Clears the data of `feature` and of every feature built from the same maps,
+/// along with the state commitments from their first height, then backfills
+/// them again from there.
+pub(crate) fn reset_feature(feature: IndexFeature) -> Result {
+  let mut config = mem_get_config();
+  let backfills = mem_backfills();
+
+  if feature == IndexFeature::Transactions {
+    mem_reset_transactions();
+    mem_remove_backfill(feature);
+    set_enabled(&mut config, feature, false);
+    mem_set_config(config)?;
+    log!(INFO, "reset {:?}", feature);
+    return enable_features(&[feature]);
+  }
+
+  let features: Vec<IndexFeature> = if uses_utxo_entries(feature) {
+    [
+      IndexFeature::Addresses,
+      IndexFeature::Inscriptions,
+      IndexFeature::Sats,
+      IndexFeature::Transactions,
+    ]
+    .into_iter()
+    .filter(|f| *f == feature || is_enabled(&config, *f) || backfills.iter().any(|(b, _)| b == f))
+    .collect()
+  } else {
+    vec![IndexFeature::Runes]
+  };
+
+  let index = Index::from_config(&config);
+  let first_height = if uses_utxo_entries(feature) {
+    mem_reset_utxo_entries();
+    checkpoint::discard_where(|_, checkpoint| checkpoint.utxo_entries);
+    if features.contains(&IndexFeature::Sats) || features.contains(&IndexFeature::Addresses) {
+      0
+    } else {
+      index.first_inscription_height()
+    }
+  } else {
+    mem_reset_runes();
+    checkpoint::discard_where(|_, checkpoint| checkpoint.runes);
+    index.first_rune_height()
+  };
+  // the commitments from there on were to the state just cleared
+  mem_remove_commitments_from(first_height);
+  for feature in &features {
+    mem_remove_backfill(*feature);
+    set_enabled(&mut config, *feature, false);
+  }
+  mem_set_config(config)?;
+  log!(INFO, "reset {:?}", features);
+
+  enable_features(&features)
+}
+
+/// The features whose state may include blocks above `height`: those switched
+/// on, and those whose backfill went past it.
+pub(crate) fn features_above(height: u32) -> Vec<IndexFeature> {
+  let config = mem_get_config();
+  let backfills = mem_backfills();
+  [
+    IndexFeature::Addresses,
+    IndexFeature::Inscriptions,
+    IndexFeature::Runes,
+    IndexFeature::Sats,
+    IndexFeature::Transactions,
+  ]
+  .into_iter()
+  .filter(|feature| {
+    is_enabled(&config, *feature)
+      || backfills
+        .iter()
+        .any(|(f, backfill)| f == feature && backfill.next_height > height + 1)
+  })
+  .collect()
+}
+
+/// Resets every feature whose state may include blocks above `height`, which
+/// are no longer part of the chain, except those `restored` from a checkpoint.
+pub(crate) fn reset_features_above(height: u32, restored: Option<&Checkpoint>) -> Result {
+  let above = features_above(height);
+  let affected = |feature: &IndexFeature| above.contains(feature);
+
+  if affected(&IndexFeature::Runes) && !restored.is_some_and(|checkpoint| checkpoint.runes) {
+    reset_feature(IndexFeature::Runes)?;
+  }
+  if restored.is_some_and(|checkpoint| checkpoint.utxo_entries) {
+    return Ok(());
+  }
+  if let Some(feature) = [
+    IndexFeature::Addresses,
+    IndexFeature::Inscriptions,
+    IndexFeature::Sats,
+    IndexFeature::Transactions,
+  ]
+  .iter()
+  .find(|feature| affected(feature))
+  {
+    reset_feature(*feature)?;
+  }
+
+  Ok(())
+}
+
+/// Indexes the next backfill block of each feature group. Once a group is
+/// within reorg range of the tip it is caught up in one go, so the live index
+/// never has to roll back a feature that isn't switched on yet.
+pub(crate) async fn backfill(network: BitcoinNetwork) -> Result {
+  let Some(tip) = mem_latest_block_height() else {
+    return Ok(());
+  };
+
+  let backfills = mem_backfills();
+  let layout = backfills
+    .iter()
+    .any(|(feature, _)| UTXO_LAYOUT_FEATURES.contains(feature));
+  // transactions backfilled on their own come from blocks alone, with the UTXO
+  // entries already indexed
+  let (runes, rest): (Vec<_>, Vec<_>) = backfills
+    .into_iter()
+    .partition(|(feature, _)| !uses_utxo_entries(*feature));
+  let (transactions, utxo): (Vec<_>, Vec<_>) = rest
+    .into_iter()
+    .partition(|(feature, _)| *feature == IndexFeature::Transactions && !layout);
+
+  for group in [runes, utxo, transactions] {
+    let Some((_, Backfill { next_height, .. })) = group.first().cloned() else {
+      continue;
+    };
+    let features: Vec<IndexFeature> = group.iter().map(|(feature, _)| *feature).collect();
+
+    let mut config = mem_get_config();
+    features
+      .iter()
+      .for_each(|feature| set_enabled(&mut config, *feature, true));
+    let index = Index::from_config(&config);
+
+    let last_height = if tip.saturating_sub(next_height) < reorg_window() {
+      tip
+    } else {
+      next_height
+    };
+
+    for height in next_height..=last_height {
+      let source = block_source::get();
+      let block_hash = source
+        .block_hash(network, height)
+        .await?
+        .ok_or_else(|| anyhow!("no block hash at height {}", height))?;
+      let block = source.block(block_hash).await?;
+
+      if features == [IndexFeature::Transactions] {
+        index_reveal_transactions(&block);
+      } else if uses_utxo_entries(features[0]) {
+        index_utxo_entries(height, &block, &index).await?;
+      } else {
+        index_rune(height, &block, &index).await?;
+        Reorg::prune_change_record(height);
+      }
+
+      // the backfill may have been cancelled while the block was fetched
+      if !mem_backfills().iter().any(|(feature, _)| features.contains(feature)) {
+        return Ok(());
+      }
+      for (feature, backfill) in &group {
+        mem_insert_backfill(
+          *feature,
+          Backfill {
+            start_height: backfill.start_height,
+            next_height: height + 1,
+          },
+        );
+      }
+      log!(INFO, "backfilled {:?} at height {}", features, height);
+    }
+
+    if last_height >= tip {
+      let mut config = mem_get_config();
+      for feature in &features {
+        mem_remove_backfill(*feature);
+        set_enabled(&mut config, *feature, true);
+      }
+      mem_set_config(config)?;
+      log!(INFO, "backfill of {:?} caught up at height {}", features, tip);
+    }
+  }
+
+  Ok(())
+}
+
+/// Records the attributes of inscriptions indexed before they were stored,
+/// newest first, by parsing the blocks that revealed them again.
+pub(crate) async fn backfill_inscription_attributes(network: BitcoinNetwork) -> Result {
+  let index_transactions = mem_get_config().index_transactions.unwrap_or_default();
+  for _ in 0..ATTRIBUTE_BLOCKS_PER_ROUND {
+    let end = mem_inscriptions_without_attributes();
+    let Some(newest) = end.checked_sub(1).and_then(mem_get_sequence_number_to_inscription_entry) else {
+      break;
+    };
+
+    // sequence numbers follow the order inscriptions were indexed in, so those
+    // of a block are consecutive
+    let height = newest.height;
+    let entries: Vec<(u32, InscriptionEntry)> = (0..end)
+      .rev()
+      .map_while(|sequence_number| {
+        mem_get_sequence_number_to_inscription_entry(sequence_number)
+          .filter(|entry| entry.height == height)
+          .map(|entry| (sequence_number, entry))
+      })
+      .collect();
+
+    let mut transactions = HashMap::new();
+    if index_transactions {
+      for (_, entry) in &entries {
+        if let Some(bytes) = mem_get_transaction_id_to_transaction(entry.id.txid.store()) {
+          transactions.insert(entry.id.txid, bitcoin::consensus::encode::deserialize::<Transaction>(&bytes)?);
+        }
+      }
+    }
+    if entries.iter().any(|(_, entry)| !transactions.contains_key(&entry.id.txid)) {
+      let source = block_source::get();
+      let block_hash = source
+        .block_hash(network, height)
+        .await?
+        .ok_or_else(|| anyhow!("no block hash at height {}", height))?;
+      let block = source.block(block_hash).await?;
+      transactions.extend(block.txdata.into_iter().map(|(transaction, txid)| (txid, transaction)));
+    }
+
+    for (sequence_number, entry) in entries {
+      // an envelope that can't be found again still gets empty attributes, so
+      // the backfill moves on
+      let attributes = transactions
+        .get(&entry.id.txid)
+        .and_then(|transaction| {
+          ParsedEnvelope::from_transaction(transaction)
+            .into_iter()
+            .nth(entry.id.index as usize)
+        })
+        .map(|envelope| InscriptionAttributes::from(&envelope.payload))
+        .unwrap_or_default();
+
+      // the inscription may have been rolled back while the block was fetched
+      if mem_get_sequence_number_to_inscription_entry(sequence_number)
+        .is_some_and(|current| current.id == entry.id)
+      {
+        mem_insert_sequence_number_to_inscription_attributes(sequence_number, attributes);
+      }
+    }
+  }
+
+  log!(
+    INFO,
+    "backfilled inscription attributes, {} left",
+    mem_inscriptions_without_attributes()
+  );
+
+  Ok(())
+}
+
+/// Fetches the headers missing below the oldest one kept, down to
+/// `first_height`. Canisters that used to prune headers to the reorg window
+/// get the full chain back this way. Each header is looked up by the previous
+/// block hash of the one above it, so it is on the indexed chain.
+pub(crate) async fn backfill_headers(first_height: u32) -> Result {
+  for _ in 0..HEADERS_PER_ROUND {
+    let Some((height, header)) = mem_first_block_header() else {
+      return Ok(());
+    };
+    if height <= first_height {
+      return Ok(());
+    }
+
+    let previous = block_source::get().header(header.prev_blockhash).await?;
+    if previous.height + 1 != height {
+      return Err(anyhow!(
+        "header {} is at height {}, not {}",
+        header.prev_blockhash,
+        previous.height,
+        height - 1
+      ));
+    }
+
+    // the headers may have been dropped by a reorg while this one was fetched
+    if mem_get_block_header(height) != Some(header) {
+      return Ok(());
+    }
+    mem_insert_block_header(previous.height, previous.header.store());
+  }
+
+  log!(
+    INFO,
+    "backfilled block headers down to height {}",
+    mem_first_block_header().map_or(first_height, |(height, _)| height)
+  );
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::rc::Rc;
+
+  use bitcoin::{absolute::LockTime, block::Header, constants::genesis_block, transaction::Version, Block, Network};
+  use futures::executor::block_on;
+
+  use super::*;
+  use crate::{
+    block_source::{self, Fake},
+    index::{mem_get_commitment, mem_get_sequence_number_to_inscription_attributes, mem_insert_commitment},
+    inscriptions::InscriptionId,
+    test::{address, envelope, insert_inscription, inscription_id, outpoint, tx_in, tx_out},
+  };
+
+  #[test]
+  fn reset_drops_the_commitments_to_the_cleared_state() {
+    mem_set_config(Config::default()).unwrap();
+    let first_rune_height = Index::from_config(&Config::default()).first_rune_height();
+    for height in first_rune_height - 1..=first_rune_height + 1 {
+      mem_insert_commitment(height, [1; 32]);
+    }
+
+    reset_feature(IndexFeature::Runes).unwrap();
+    assert_eq!(mem_get_commitment(first_rune_height - 1), Some([1; 32]));
+    assert_eq!(mem_get_commitment(first_rune_height), None);
+    assert_eq!(mem_get_commitment(first_rune_height + 1), None);
+  }
+
+  #[test]
+  fn transactions_are_backfilled_apart_from_utxo_entries() {
+    mem_set_config(Config::default()).unwrap();
+    mem_insert_block_header(0, genesis_block(Network::Regtest).header.store());
+
+    let error = enable_features(&[IndexFeature::Sats]).unwrap_err().to_string();
+    assert!(error.contains("reset_index"), "{}", error);
+
+    enable_features(&[IndexFeature::Transactions]).unwrap();
+    let start_height = Index::from_config(&Config::default()).first_inscription_height();
+    assert_eq!(
+      mem_backfills(),
+      vec![(IndexFeature::Transactions, Backfill { start_height, next_height: start_height })]
+    );
+
+    let mut input = tx_in(outpoint(1));
+    input.witness = envelope(&[b"ord", &[1], b"text/plain", &[], b"hello"]);
+    let reveal = Transaction {
+      version: Version::TWO,
+      lock_time: LockTime::ZERO,
+      input: vec![input],
+      output: vec![tx_out(1000, address(0))],
+    };
+    let transfer = Transaction {
+      input: vec![tx_in(outpoint(2))],
+      ..reveal.clone()
+    };
+    let block = BlockData::from(Block {
+      header: genesis_block(Network::Regtest).header,
+      txdata: vec![reveal.clone(), transfer.clone()],
+    });
+    index_reveal_transactions(&block);
+    assert!(mem_get_transaction_id_to_transaction(reveal.compute_txid().store()).is_some());
+    assert!(mem_get_transaction_id_to_transaction(transfer.compute_txid().store()).is_none());
+  }
+
+  #[test]
+  fn attributes_are_recovered_from_the_reveal_block() {
+    let fake = Rc::new(Fake::default());
+    block_source::set(fake.clone());
+    let reveals: Vec<Transaction> = (0..2u8)
+      .map(|n| {
+        let mut input = tx_in(outpoint(n.into()));
+        input.witness = envelope(&[b"ord", &[1], b"text/plain", &[], &[b'a'; 5][..usize::from(n) + 4]]);
+        Transaction {
+          version: Version::TWO,
+          lock_time: LockTime::ZERO,
+          input: vec![input],
+          output: vec![tx_out(1000, address(0))],
+        }
+      })
+      .collect();
+    fake.mine(reveals.clone());
+
+    for (sequence_number, reveal) in (0..).zip(&reveals) {
+      insert_inscription(sequence_number, InscriptionId { txid: reveal.compute_txid(), index: 0 });
+    }
+    insert_inscription(2, inscription_id(2));
+    mem_insert_sequence_number_to_inscription_attributes(2, InscriptionAttributes::default());
+    assert_eq!(mem_inscriptions_without_attributes(), 2);
+
+    block_on(backfill_inscription_attributes(BitcoinNetwork::Regtest)).unwrap();
+
+    assert_eq!(mem_inscriptions_without_attributes(), 0);
+    for (sequence_number, length) in [(0, 4), (1, 5)] {
+      let attributes = mem_get_sequence_number_to_inscription_attributes(sequence_number).unwrap();
+      assert_eq!(attributes.content_type.as_deref(), Some("text/plain"));
+      assert_eq!(attributes.content_length, Some(length));
+    }
+  }
+
+  #[test]
+  fn headers_are_backfilled_down_to_the_first_height() {
+    let fake = Rc::new(Fake::default());
+    block_source::set(fake.clone());
+    let headers: Vec<Header> = (0..6)
+      .map(|_| {
+        let hash = fake.mine(Vec::new());
+        block_on(fake.block(hash)).unwrap().header
+      })
+      .collect();
+    mem_insert_block_header(4, headers[4].store());
+    mem_insert_block_header(5, headers[5].store());
+
+    block_on(backfill_headers(1)).unwrap();
+
+    assert_eq!(mem_first_block_header(), Some((1, headers[1])));
+    assert!((1..=5).all(|height| mem_get_block_header(height) == Some(headers[height as usize])));
+  }
+}
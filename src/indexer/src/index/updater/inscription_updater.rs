@@ -3,7 +3,7 @@ use bitcoin::{consensus::Encodable, OutPoint, Transaction, Txid};
 use ordinals::{Charm, Height, Sat, SatPoint};
 
 use crate::{
-  index::{entry::{Entry, InscriptionEntry, InscriptionNumber, SatRange}, event::{Event, Events}, mem_get_inscription_id_to_sequence_number, mem_get_sequence_number_to_inscription_entry, mem_insert_home_inscriptions, mem_insert_inscription_id_to_sequence_number, mem_insert_inscription_number_to_sequence_number, mem_insert_sat_to_sequence_numbers, mem_insert_sequence_number_to_childrens, mem_insert_sequence_number_to_inscription_entry, mem_insert_transaction_id_to_transaction, mem_pop_first_home_inscriptions, utxo_entry::{ParsedUtxoEntry, UtxoEntryBuf}, Index}, inscriptions::{envelope::ParsedEnvelope, InscriptionId}, unbound_outpoint, Result
+  index::{entry::{Entry, InscriptionAttributes, InscriptionEntry, InscriptionNumber, SatRange}, event::{Event, Events}, mem_get_inscription_id_to_sequence_number, mem_get_sequence_number_to_inscription_entry, mem_insert_home_inscriptions, mem_insert_inscription_id_to_sequence_number, mem_insert_inscription_number_to_sequence_number, mem_insert_sat_to_sequence_numbers, mem_insert_sequence_number_to_childrens, mem_insert_sequence_number_to_inscription_attributes, mem_insert_sequence_number_to_inscription_entry, mem_insert_transaction_id_to_transaction, mem_pop_first_home_inscriptions, utxo_entry::{ParsedUtxoEntry, UtxoEntryBuf}, Index}, inscriptions::{envelope::ParsedEnvelope, InscriptionId}, unbound_outpoint, Result
 };
 
 
@@ -22,14 +22,15 @@ enum Curse {
 
 #[derive(Debug, Clone)]
 pub(super) struct Flotsam {
-  inscription_id: InscriptionId,
-  offset: u64,
-  origin: Origin,
+  pub(super) inscription_id: InscriptionId,
+  pub(super) offset: u64,
+  pub(super) origin: Origin,
 }
 
 #[derive(Debug, Clone)]
-enum Origin {
+pub(super) enum Origin {
   New {
+    attributes: InscriptionAttributes,
     cursed: bool,
     fee: u64,
     hidden: bool,
@@ -45,6 +46,18 @@ enum Origin {
 }
 
 
+/// Where the inscriptions in a transaction end up, before anything is written.
+pub(super) struct InscriptionFlow {
+  pub(super) has_new_inscriptions: bool,
+  pub(super) is_coinbase: bool,
+  /// Inscriptions landing in an output, with whether that output is OP_RETURN.
+  pub(super) located: Vec<(SatPoint, Flotsam, bool)>,
+  /// Inscriptions past the last output, which are paid to the miner as fees.
+  pub(super) unlocated: Vec<Flotsam>,
+  pub(super) output_value: u64,
+  pub(super) total_input_value: u64,
+}
+
 pub(super) struct InscriptionUpdater {
     pub(super) blessed_inscription_count: u64,
     pub(super) cursed_inscription_count: u64,
@@ -71,6 +84,71 @@ impl InscriptionUpdater {
       index: &Index,
       input_sat_ranges: Option<&Vec<&[u8]>>,
     ) -> Result {
+    let flow = self.flow(tx, txid, input_utxo_entries, index)?;
+
+    if index.index_transactions && flow.has_new_inscriptions {
+      tx.consensus_encode(&mut self.transaction_buffer)
+        .expect("in-memory writers don't error");
+
+      mem_insert_transaction_id_to_transaction(txid.store(), self.transaction_buffer.clone());
+
+      self.transaction_buffer.clear();
+    }
+
+    for (new_satpoint, flotsam, op_return) in flow.located.into_iter() {
+      let output_utxo_entry =
+        &mut output_utxo_entries[usize::try_from(new_satpoint.outpoint.vout).unwrap()];
+
+      self.update_inscription_location(
+        input_sat_ranges,
+        flotsam,
+        new_satpoint,
+        op_return,
+        Some(output_utxo_entry),
+        utxo_cache,
+        index,
+      )?;
+    }
+
+    let output_value = flow.output_value;
+
+    if flow.is_coinbase {
+      for flotsam in flow.unlocated {
+        let new_satpoint = SatPoint {
+          outpoint: OutPoint::null(),
+          offset: self.lost_sats + flotsam.offset - output_value,
+        };
+        self.update_inscription_location(
+          input_sat_ranges,
+          flotsam,
+          new_satpoint,
+          false,
+          None,
+          utxo_cache,
+          index,
+        )?;
+      }
+      self.lost_sats += self.reward - output_value;
+      Ok(())
+    } else {
+      self.flotsam.extend(flow.unlocated.into_iter().map(|flotsam| Flotsam {
+        offset: self.reward + flotsam.offset - output_value,
+        ..flotsam
+      }));
+      self.reward += flow.total_input_value - output_value;
+      Ok(())
+    }
+  }
+
+  /// Follows old and new inscriptions through `tx` without touching stable
+  /// memory, so it can also be used to simulate unconfirmed transactions.
+  pub(super) fn flow(
+    &mut self,
+    tx: &Transaction,
+    txid: Txid,
+    input_utxo_entries: &[ParsedUtxoEntry],
+    index: &Index,
+  ) -> Result<InscriptionFlow> {
       let mut floating_inscriptions = Vec::new();
     let mut id_counter = 0;
     let mut inscribed_offsets = BTreeMap::new();
@@ -185,6 +263,7 @@ impl InscriptionUpdater {
           inscription_id,
           offset,
           origin: Origin::New {
+            attributes: InscriptionAttributes::from(&inscription.payload),
             cursed: curse.is_some() && !jubilant,
             fee: 0,
             hidden: inscription.payload.hidden(),
@@ -207,15 +286,6 @@ impl InscriptionUpdater {
       }
     }
 
-    if index.index_transactions && has_new_inscriptions {
-      tx.consensus_encode(&mut self.transaction_buffer)
-        .expect("in-memory writers don't error");
-
-      mem_insert_transaction_id_to_transaction(txid.store(), self.transaction_buffer.clone());
-
-      self.transaction_buffer.clear();
-    }
-
     let potential_parents = floating_inscriptions
       .iter()
       .map(|flotsam| flotsam.inscription_id)
@@ -288,47 +358,14 @@ impl InscriptionUpdater {
       output_value = end;
     }
 
-    for (new_satpoint, flotsam, op_return) in new_locations.into_iter() {
-      let output_utxo_entry =
-        &mut output_utxo_entries[usize::try_from(new_satpoint.outpoint.vout).unwrap()];
-
-      self.update_inscription_location(
-        input_sat_ranges,
-        flotsam,
-        new_satpoint,
-        op_return,
-        Some(output_utxo_entry),
-        utxo_cache,
-        index,
-      )?;
-    }
-
-    if is_coinbase {
-      for flotsam in inscriptions {
-        let new_satpoint = SatPoint {
-          outpoint: OutPoint::null(),
-          offset: self.lost_sats + flotsam.offset - output_value,
-        };
-        self.update_inscription_location(
-          input_sat_ranges,
-          flotsam,
-          new_satpoint,
-          false,
-          None,
-          utxo_cache,
-          index,
-        )?;
-      }
-      self.lost_sats += self.reward - output_value;
-      Ok(())
-    } else {
-      self.flotsam.extend(inscriptions.map(|flotsam| Flotsam {
-        offset: self.reward + flotsam.offset - output_value,
-        ..flotsam
-      }));
-      self.reward += total_input_value - output_value;
-      Ok(())
-    }
+    Ok(InscriptionFlow {
+      has_new_inscriptions,
+      is_coinbase,
+      located: new_locations,
+      unlocated: inscriptions.collect(),
+      output_value,
+      total_input_value,
+    })
   }
 
   fn calculate_sat(input_sat_ranges: Option<&Vec<&[u8]>>, input_offset: u64) -> Option<Sat> {
@@ -387,6 +424,7 @@ impl InscriptionUpdater {
         (false, sequence_number)
       }
       Origin::New {
+        attributes,
         cursed,
         fee,
         hidden,
@@ -471,6 +509,8 @@ impl InscriptionUpdater {
           sequence_number,
         });
 
+        mem_insert_sequence_number_to_inscription_attributes(sequence_number, attributes);
+
         mem_insert_sequence_number_to_inscription_entry(
           sequence_number,
           InscriptionEntry {
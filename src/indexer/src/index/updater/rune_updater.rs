@@ -31,13 +31,20 @@ impl RuneUpdater {
 
     let mut unallocated = self.unallocated(tx)?;
 
-    let mut allocated: Vec<HashMap<RuneId, Lot>> = vec![HashMap::new(); tx.output.len()];
+    let mut etched = None;
 
     if let Some(artifact) = &artifact {
       if let Some(id) = artifact.mint() {
         if let Some(amount) = self.mint(id)? {
           *unallocated.entry(id).or_default() += amount;
 
+          self.events.push(Event::RuneMinted {
+            amount: amount.n(),
+            block_height: self.height,
+            rune_id: id,
+            txid,
+          });
+
           // log!(
           //   INFO,
           //   "Rune minted: block_height: {}, txid: {:?}, rune_id: {:?}, amount: {:?}",
@@ -49,147 +56,27 @@ impl RuneUpdater {
         }
       }
 
-      let etched = self.etched(tx_index, tx, artifact).await?;
-
-      if let Artifact::Runestone(runestone) = artifact {
-        if let Some((id, ..)) = etched {
-          *unallocated.entry(id).or_default() +=
-            runestone.etching.unwrap().premine.unwrap_or_default();
-        }
-
-        for Edict { id, amount, output } in runestone.edicts.iter().copied() {
-          let amount = Lot(amount);
-
-          // edicts with output values greater than the number of outputs
-          // should never be produced by the edict parser
-          let output = usize::try_from(output).unwrap();
-          assert!(output <= tx.output.len());
-
-          let id = if id == RuneId::default() {
-            let Some((id, ..)) = etched else {
-              continue;
-            };
-
-            id
-          } else {
-            id
-          };
-
-          let Some(balance) = unallocated.get_mut(&id) else {
-            continue;
-          };
-
-          let mut allocate = |balance: &mut Lot, amount: Lot, output: usize| {
-            if amount > 0 {
-              *balance -= amount;
-              *allocated[output].entry(id).or_default() += amount;
-            }
-          };
-
-          if output == tx.output.len() {
-            // find non-OP_RETURN outputs
-            let destinations = tx
-              .output
-              .iter()
-              .enumerate()
-              .filter_map(|(output, tx_out)| {
-                (!tx_out.script_pubkey.is_op_return()).then_some(output)
-              })
-              .collect::<Vec<usize>>();
-
-            if !destinations.is_empty() {
-              if amount == 0 {
-                // if amount is zero, divide balance between eligible outputs
-                let amount = *balance / destinations.len() as u128;
-                let remainder = usize::try_from(*balance % destinations.len() as u128).unwrap();
-
-                for (i, output) in destinations.iter().enumerate() {
-                  allocate(
-                    balance,
-                    if i < remainder { amount + 1 } else { amount },
-                    *output,
-                  );
-                }
-              } else {
-                // if amount is non-zero, distribute amount to eligible outputs
-                for output in destinations {
-                  allocate(balance, amount.min(*balance), output);
-                }
-              }
-            }
-          } else {
-            // Get the allocatable amount
-            let amount = if amount == 0 {
-              *balance
-            } else {
-              amount.min(*balance)
-            };
-
-            allocate(balance, amount, output);
-          }
-        }
-      }
+      etched = self.etched(tx_index, tx, artifact).await?;
 
       if let Some((id, rune)) = etched {
         self.create_rune_entry(txid, artifact, id, rune)?;
-      }
-    }
-
-    let mut burned: HashMap<RuneId, Lot> = HashMap::new();
 
-    if let Some(Artifact::Cenotaph(_)) = artifact {
-      for (id, balance) in unallocated {
-        *burned.entry(id).or_default() += balance;
-      }
-    } else {
-      let pointer = artifact
-        .map(|artifact| match artifact {
-          Artifact::Runestone(runestone) => runestone.pointer,
-          Artifact::Cenotaph(_) => unreachable!(),
-        })
-        .unwrap_or_default();
-
-      // assign all un-allocated runes to the default output, or the first non
-      // OP_RETURN output if there is no default
-      if let Some(vout) = pointer
-        .map(|pointer| pointer.into_usize())
-        .inspect(|&pointer| assert!(pointer < allocated.len()))
-        .or_else(|| {
-          tx.output
-            .iter()
-            .enumerate()
-            .find(|(_vout, tx_out)| !tx_out.script_pubkey.is_op_return())
-            .map(|(vout, _tx_out)| vout)
-        })
-      {
-        for (id, balance) in unallocated {
-          if balance > 0 {
-            *allocated[vout].entry(id).or_default() += balance;
-          }
-        }
-      } else {
-        for (id, balance) in unallocated {
-          if balance > 0 {
-            *burned.entry(id).or_default() += balance;
-          }
-        }
+        self.events.push(Event::RuneEtched {
+          block_height: self.height,
+          rune_id: id,
+          txid,
+        });
       }
     }
 
+    let (allocated, burned) = allocate(tx, artifact.as_ref(), etched, unallocated);
+
     // update outpoint balances
     for (vout, balances) in allocated.into_iter().enumerate() {
       if balances.is_empty() {
         continue;
       }
 
-      // increment burned balances
-      if tx.output[vout].script_pubkey.is_op_return() {
-        for (id, balance) in &balances {
-          *burned.entry(*id).or_default() += *balance;
-        }
-        continue;
-      }
-
       // let mut balances = balances.into_iter().collect::<Vec<(RuneId, Lot)>>();
 
       // Sort balances by id so tests can assert balances in a fixed order
@@ -246,7 +133,7 @@ impl RuneUpdater {
     Ok(())
   }
 
-  pub(super) fn update(mut self) -> Result {
+  pub(super) fn update(mut self) -> Result<Events> {
     for (rune_id, burned) in self.burned {
       let mut entry = crate::index::mem_get_rune_id_to_rune_entry(rune_id.store()).unwrap();
 
@@ -260,7 +147,7 @@ impl RuneUpdater {
 
     crate::index::mem_insert_change_record_rune(self.height, self.change_record);
 
-    Ok(())
+    Ok(self.events)
   }
 
   fn create_rune_entry(
@@ -427,19 +314,19 @@ impl RuneUpdater {
           continue;
         }
 
-        let tx_info =
-          crate::rpc::get_raw_transaction_info(&input.previous_output.txid, None).await?;
+        let (commit_tx, block_hash) =
+          crate::index::fetch_transaction(input.previous_output.txid).await?;
 
-        let taproot = tx_info.vout[input.previous_output.vout.into_usize()]
-          .script_pub_key
-          .script()?
+        let taproot = commit_tx.output[input.previous_output.vout.into_usize()]
+          .script_pubkey
           .is_p2tr();
 
         if !taproot {
           continue;
         }
 
-        let commit_tx_height = crate::rpc::get_block_header_info(&tx_info.blockhash.unwrap())
+        let commit_tx_height = crate::block_source::get()
+          .header(block_hash.unwrap())
           .await?
           .height;
 
@@ -488,3 +375,214 @@ impl RuneUpdater {
     Ok(unallocated)
   }
 }
+
+/// Distributes the unallocated balances of a transaction to its outputs
+/// according to `artifact`, returning the balances per output and the amounts
+/// burned, either by a cenotaph or by landing in an OP_RETURN output.
+///
+/// This only reads `tx` and the balances passed in, so the same allocation can
+/// be replayed against unconfirmed transactions.
+pub(super) fn allocate(
+  tx: &Transaction,
+  artifact: Option<&Artifact>,
+  etched: Option<(RuneId, Rune)>,
+  mut unallocated: HashMap<RuneId, Lot>,
+) -> (Vec<HashMap<RuneId, Lot>>, HashMap<RuneId, Lot>) {
+  let mut allocated: Vec<HashMap<RuneId, Lot>> = vec![HashMap::new(); tx.output.len()];
+
+  if let Some(Artifact::Runestone(runestone)) = artifact {
+    if let Some((id, ..)) = etched {
+      *unallocated.entry(id).or_default() +=
+        runestone.etching.unwrap().premine.unwrap_or_default();
+    }
+
+    for Edict { id, amount, output } in runestone.edicts.iter().copied() {
+      let amount = Lot(amount);
+
+      // edicts with output values greater than the number of outputs
+      // should never be produced by the edict parser
+      let output = usize::try_from(output).unwrap();
+      assert!(output <= tx.output.len());
+
+      let id = if id == RuneId::default() {
+        let Some((id, ..)) = etched else {
+          continue;
+        };
+
+        id
+      } else {
+        id
+      };
+
+      let Some(balance) = unallocated.get_mut(&id) else {
+        continue;
+      };
+
+      let mut allocate = |balance: &mut Lot, amount: Lot, output: usize| {
+        if amount > 0 {
+          *balance -= amount;
+          *allocated[output].entry(id).or_default() += amount;
+        }
+      };
+
+      if output == tx.output.len() {
+        // find non-OP_RETURN outputs
+        let destinations = tx
+          .output
+          .iter()
+          .enumerate()
+          .filter_map(|(output, tx_out)| {
+            (!tx_out.script_pubkey.is_op_return()).then_some(output)
+          })
+          .collect::<Vec<usize>>();
+
+        if !destinations.is_empty() {
+          if amount == 0 {
+            // if amount is zero, divide balance between eligible outputs
+            let amount = *balance / destinations.len() as u128;
+            let remainder = usize::try_from(*balance % destinations.len() as u128).unwrap();
+
+            for (i, output) in destinations.iter().enumerate() {
+              allocate(
+                balance,
+                if i < remainder { amount + 1 } else { amount },
+                *output,
+              );
+            }
+          } else {
+            // if amount is non-zero, distribute amount to eligible outputs
+            for output in destinations {
+              allocate(balance, amount.min(*balance), output);
+            }
+          }
+        }
+      } else {
+        // Get the allocatable amount
+        let amount = if amount == 0 {
+          *balance
+        } else {
+          amount.min(*balance)
+        };
+
+        allocate(balance, amount, output);
+      }
+    }
+  }
+
+  let mut burned: HashMap<RuneId, Lot> = HashMap::new();
+
+  if let Some(Artifact::Cenotaph(_)) = artifact {
+    for (id, balance) in unallocated {
+      *burned.entry(id).or_default() += balance;
+    }
+  } else {
+    let pointer = artifact
+      .map(|artifact| match artifact {
+        Artifact::Runestone(runestone) => runestone.pointer,
+        Artifact::Cenotaph(_) => unreachable!(),
+      })
+      .unwrap_or_default();
+
+    // assign all un-allocated runes to the default output, or the first non
+    // OP_RETURN output if there is no default
+    if let Some(vout) = pointer
+      .map(|pointer| pointer.into_usize())
+      .inspect(|&pointer| assert!(pointer < allocated.len()))
+      .or_else(|| {
+        tx.output
+          .iter()
+          .enumerate()
+          .find(|(_vout, tx_out)| !tx_out.script_pubkey.is_op_return())
+          .map(|(vout, _tx_out)| vout)
+      })
+    {
+      for (id, balance) in unallocated {
+        if balance > 0 {
+          *allocated[vout].entry(id).or_default() += balance;
+        }
+      }
+    } else {
+      for (id, balance) in unallocated {
+        if balance > 0 {
+          *burned.entry(id).or_default() += balance;
+        }
+      }
+    }
+  }
+
+  // increment burned balances
+  for (vout, balances) in allocated.iter_mut().enumerate() {
+    if tx.output[vout].script_pubkey.is_op_return() {
+      for (id, balance) in balances.drain() {
+        *burned.entry(id).or_default() += balance;
+      }
+    }
+  }
+
+  (allocated, burned)
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::test::{address, outpoint, rune_id, tx_in, tx_out},
+    bitcoin::{absolute::LockTime, transaction::Version, Amount, TxOut},
+  };
+
+  fn transaction(runestone: Runestone) -> Transaction {
+    Transaction {
+      version: Version::TWO,
+      lock_time: LockTime::ZERO,
+      input: vec![tx_in(outpoint(1))],
+      output: vec![
+        TxOut {
+          value: Amount::ZERO,
+          script_pubkey: runestone.encipher(),
+        },
+        tx_out(1000, address(0)),
+      ],
+    }
+  }
+
+  #[test]
+  fn unallocated_runes_go_to_first_non_op_return_output() {
+    let tx = transaction(Runestone::default());
+    let artifact = Runestone::decipher(&tx);
+
+    let (allocated, burned) = allocate(
+      &tx,
+      artifact.as_ref(),
+      None,
+      [(rune_id(1), Lot(100))].into(),
+    );
+
+    assert!(allocated[0].is_empty());
+    assert_eq!(allocated[1][&rune_id(1)], Lot(100));
+    assert!(burned.is_empty());
+  }
+
+  #[test]
+  fn edict_to_op_return_output_is_burned() {
+    let tx = transaction(Runestone {
+      edicts: vec![Edict {
+        id: rune_id(1),
+        amount: 40,
+        output: 0,
+      }],
+      ..Default::default()
+    });
+    let artifact = Runestone::decipher(&tx);
+
+    let (allocated, burned) = allocate(
+      &tx,
+      artifact.as_ref(),
+      None,
+      [(rune_id(1), Lot(100))].into(),
+    );
+
+    assert!(allocated[0].is_empty());
+    assert_eq!(allocated[1][&rune_id(1)], Lot(60));
+    assert_eq!(burned[&rune_id(1)], Lot(40));
+  }
+}
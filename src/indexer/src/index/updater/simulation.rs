@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail};
+use bitcoin::{consensus, Psbt, Transaction};
+use ordinals::{Artifact, Height, Rune, RuneId, Runestone, Sat, SpacedRune};
+
+use crate::{
+  index::{
+    entry::{Entry, SatRange},
+    event::Events,
+    lot::Lot,
+    mem_get_outpoint_to_rune_balances, mem_get_outpoint_to_utxo_entry,
+    mem_get_rune_id_to_rune_entry, mem_get_rune_to_rune_id, mem_latest_block_height,
+    utxo_entry::{ParsedUtxoEntry, UtxoEntryBuf},
+    Index,
+  },
+  inscriptions::InscriptionId,
+  Result,
+};
+
+use super::{
+  inscription_updater::{InscriptionUpdater, Origin},
+  rune_updater::allocate,
+};
+
+pub(crate) struct SimulatedOutput {
+  pub(crate) inscriptions: Vec<InscriptionId>,
+  pub(crate) runes: Vec<(RuneId, u128)>,
+  pub(crate) rare_sats: Option<Vec<Sat>>,
+  pub(crate) op_return: bool,
+}
+
+pub(crate) struct Simulation {
+  pub(crate) outputs: Vec<SimulatedOutput>,
+  pub(crate) inscriptions_to_fee: Vec<InscriptionId>,
+  pub(crate) unbound_inscriptions: Vec<InscriptionId>,
+  pub(crate) rare_sats_to_fee: Option<Vec<Sat>>,
+  pub(crate) runes_burned: Vec<(RuneId, u128)>,
+  pub(crate) cenotaph: bool,
+  pub(crate) etched_rune: Option<SpacedRune>,
+}
+
+/// Decodes a hex encoded PSBT or raw transaction. For a PSBT, the values of
+/// the inputs are taken from `witness_utxo` or `non_witness_utxo` when present,
+/// so inputs the index doesn't know about can still be simulated.
+pub(crate) fn decode_transaction(hex: &str) -> Result<(Transaction, Vec<Option<u64>>)> {
+  let bytes = hex::decode(hex)?;
+
+  if bytes.starts_with(b"psbt\xff") {
+    let psbt = Psbt::deserialize(&bytes)?;
+    let input_values = psbt
+      .inputs
+      .iter()
+      .zip(&psbt.unsigned_tx.input)
+      .map(|(input, txin)| {
+        input
+          .witness_utxo
+          .as_ref()
+          .or_else(|| {
+            input
+              .non_witness_utxo
+              .as_ref()
+              .and_then(|tx| tx.output.get(usize::try_from(txin.previous_output.vout).ok()?))
+          })
+          .map(|txout| txout.value.to_sat())
+      })
+      .collect();
+    Ok((psbt.unsigned_tx, input_values))
+  } else {
+    let tx: Transaction = consensus::encode::deserialize(&bytes)?;
+    let input_values = vec![None; tx.input.len()];
+    Ok((tx, input_values))
+  }
+}
+
+/// Runs `tx` through the same inscription and rune logic as the updater, as if
+/// it were mined in the next block, without writing anything.
+///
+/// An etching is assumed to have a valid commitment. Runes it etches are
+/// reported under the `0:0` rune id, since the real id depends on where the
+/// transaction is mined.
+pub(crate) fn simulate_transaction(
+  index: &Index,
+  tx: &Transaction,
+  input_values: &[Option<u64>],
+) -> Result<Simulation> {
+  if tx.is_coinbase() {
+    bail!("cannot simulate a coinbase transaction");
+  }
+
+  let height = mem_latest_block_height()
+    .map(|height| height + 1)
+    .unwrap_or(index.get_first_index_height());
+
+  let mut simulation = Simulation {
+    outputs: tx
+      .output
+      .iter()
+      .map(|txout| SimulatedOutput {
+        inscriptions: Vec::new(),
+        runes: Vec::new(),
+        rare_sats: None,
+        op_return: txout.script_pubkey.is_op_return(),
+      })
+      .collect(),
+    inscriptions_to_fee: Vec::new(),
+    unbound_inscriptions: Vec::new(),
+    rare_sats_to_fee: None,
+    runes_burned: Vec::new(),
+    cenotaph: false,
+    etched_rune: None,
+  };
+
+  let index_inscriptions = index.index_inscriptions && height >= index.first_inscription_height();
+
+  if index_inscriptions || index.index_sats {
+    let input_utxo_entries = load_input_utxo_entries(index, tx, input_values)?;
+    let input_utxo_entries = input_utxo_entries
+      .iter()
+      .map(|entry| entry.parse(index))
+      .collect::<Vec<ParsedUtxoEntry>>();
+
+    let total_input_value = input_utxo_entries
+      .iter()
+      .map(|entry| entry.total_value())
+      .sum::<u64>();
+    let total_output_value = tx
+      .output
+      .iter()
+      .map(|txout| txout.value.to_sat())
+      .sum::<u64>();
+    if total_input_value < total_output_value {
+      bail!("outputs spend {total_output_value} sats but inputs only hold {total_input_value}");
+    }
+
+    if index_inscriptions {
+      simulate_inscriptions(index, tx, height, &input_utxo_entries, &mut simulation)?;
+    }
+
+    if index.index_sats {
+      simulate_sats(tx, &input_utxo_entries, &mut simulation)?;
+    }
+  }
+
+  if index.index_runes && height >= index.first_rune_height() {
+    simulate_runes(index, tx, height, &mut simulation);
+  }
+
+  Ok(simulation)
+}
+
+fn load_input_utxo_entries(
+  index: &Index,
+  tx: &Transaction,
+  input_values: &[Option<u64>],
+) -> Result<Vec<UtxoEntryBuf>> {
+  tx.input
+    .iter()
+    .enumerate()
+    .map(|(i, txin)| {
+      let outpoint = txin.previous_output;
+      if let Some(entry) = mem_get_outpoint_to_utxo_entry(outpoint.store()) {
+        return Ok(entry.to_buf());
+      }
+
+      if index.index_sats {
+        bail!("input {outpoint} is not indexed, sat ranges are unknown");
+      }
+
+      let value = input_values
+        .get(i)
+        .copied()
+        .flatten()
+        .ok_or_else(|| anyhow!("input {outpoint} is not indexed, provide its value in a PSBT"))?;
+
+      let mut entry = UtxoEntryBuf::new();
+      entry.push_value(value, index);
+      if index.index_addresses {
+        entry.push_script_pubkey(&[], index);
+      }
+      Ok(entry)
+    })
+    .collect()
+}
+
+fn simulate_inscriptions(
+  index: &Index,
+  tx: &Transaction,
+  height: u32,
+  input_utxo_entries: &[ParsedUtxoEntry],
+  simulation: &mut Simulation,
+) -> Result {
+  let mut inscription_updater = InscriptionUpdater {
+    blessed_inscription_count: 0,
+    cursed_inscription_count: 0,
+    flotsam: Vec::new(),
+    height,
+    home_inscription_count: 0,
+    lost_sats: 0,
+    next_sequence_number: 0,
+    reward: Height(height).subsidy(),
+    transaction_buffer: Vec::new(),
+    timestamp: 0,
+    unbound_inscriptions: 0,
+    events: Events::new(),
+  };
+
+  let flow = inscription_updater.flow(tx, tx.compute_txid(), input_utxo_entries, index)?;
+
+  for (satpoint, flotsam, _op_return) in flow.located {
+    if let Origin::New { unbound: true, .. } = flotsam.origin {
+      simulation.unbound_inscriptions.push(flotsam.inscription_id);
+      continue;
+    }
+
+    simulation.outputs[usize::try_from(satpoint.outpoint.vout).unwrap()]
+      .inscriptions
+      .push(flotsam.inscription_id);
+  }
+
+  simulation.inscriptions_to_fee = flow
+    .unlocated
+    .into_iter()
+    .map(|flotsam| flotsam.inscription_id)
+    .collect();
+
+  Ok(())
+}
+
+fn simulate_sats(
+  tx: &Transaction,
+  input_utxo_entries: &[ParsedUtxoEntry],
+  simulation: &mut Simulation,
+) -> Result {
+  let mut input_sat_ranges = input_utxo_entries
+    .iter()
+    .flat_map(|entry| entry.sat_ranges().chunks_exact(11))
+    .map(|chunk| SatRange::load(chunk.try_into().unwrap()));
+  let mut pending_input_sat_range = None;
+
+  for (vout, output) in tx.output.iter().enumerate() {
+    let rare_sats = simulation.outputs[vout].rare_sats.get_or_insert_with(Vec::new);
+
+    let mut remaining = output.value.to_sat();
+    while remaining > 0 {
+      let range = pending_input_sat_range
+        .take()
+        .or_else(|| input_sat_ranges.next())
+        .ok_or_else(|| anyhow!("insufficient inputs for transaction outputs"))?;
+
+      if !Sat(range.0).common() {
+        rare_sats.push(Sat(range.0));
+      }
+
+      let count = range.1 - range.0;
+      if count > remaining {
+        pending_input_sat_range = Some((range.0 + remaining, range.1));
+        remaining = 0;
+      } else {
+        remaining -= count;
+      }
+    }
+  }
+
+  simulation.rare_sats_to_fee = Some(
+    pending_input_sat_range
+      .into_iter()
+      .chain(input_sat_ranges)
+      .map(|range| Sat(range.0))
+      .filter(|sat| !sat.common())
+      .collect(),
+  );
+
+  Ok(())
+}
+
+fn simulate_runes(index: &Index, tx: &Transaction, height: u32, simulation: &mut Simulation) {
+  let artifact = Runestone::decipher(tx);
+
+  let mut unallocated: HashMap<RuneId, Lot> = HashMap::new();
+  for txin in &tx.input {
+    if let Some(rune_balances) = mem_get_outpoint_to_rune_balances(txin.previous_output.store()) {
+      for rune_balance in rune_balances.balances {
+        *unallocated.entry(rune_balance.rune_id).or_default() += rune_balance.balance;
+      }
+    }
+  }
+
+  let mut etched = None;
+
+  if let Some(artifact) = &artifact {
+    if let Some(id) = artifact.mint() {
+      if let Some(amount) = mem_get_rune_id_to_rune_entry(id.store())
+        .and_then(|rune_entry| rune_entry.mintable(height.into()).ok())
+      {
+        *unallocated.entry(id).or_default() += amount;
+      }
+    }
+
+    etched = etched_rune(index, artifact, height).map(|rune| (RuneId::default(), rune));
+
+    if let (Some((_, rune)), Artifact::Runestone(runestone)) = (etched, artifact) {
+      if runestone.etching.and_then(|etching| etching.rune).is_some() {
+        simulation.etched_rune = Some(SpacedRune {
+          rune,
+          spacers: runestone
+            .etching
+            .and_then(|etching| etching.spacers)
+            .unwrap_or_default(),
+        });
+      }
+    }
+  }
+
+  simulation.cenotaph = matches!(artifact, Some(Artifact::Cenotaph(_)));
+
+  let (allocated, burned) = allocate(tx, artifact.as_ref(), etched, unallocated);
+
+  for (vout, balances) in allocated.into_iter().enumerate() {
+    let mut runes = balances
+      .into_iter()
+      .map(|(id, balance)| (id, balance.n()))
+      .collect::<Vec<(RuneId, u128)>>();
+    runes.sort();
+    simulation.outputs[vout].runes = runes;
+  }
+
+  let mut runes_burned = burned
+    .into_iter()
+    .map(|(id, balance)| (id, balance.n()))
+    .collect::<Vec<(RuneId, u128)>>();
+  runes_burned.sort();
+  simulation.runes_burned = runes_burned;
+}
+
+/// Mirrors `RuneUpdater::etched`, minus the commitment check, which needs an
+/// outcall and is assumed to pass.
+fn etched_rune(index: &Index, artifact: &Artifact, height: u32) -> Option<Rune> {
+  let rune = match artifact {
+    Artifact::Runestone(runestone) => runestone.etching?.rune,
+    Artifact::Cenotaph(cenotaph) => Some(cenotaph.etching?),
+  };
+
+  match rune {
+    Some(rune) => {
+      if rune < Rune::minimum_at_height(index.chain().network(), Height(height))
+        || rune.is_reserved()
+        || mem_get_rune_to_rune_id(rune.store()).is_some()
+      {
+        None
+      } else {
+        Some(rune)
+      }
+    }
+    // the reserved name depends on the transaction's position in the block
+    None => Some(Rune::reserved(height.into(), 0)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bitcoin::{absolute::LockTime, transaction::Version, Amount, TxOut};
+  use ordinals::{Edict, Etching};
+
+  use super::*;
+  use crate::{
+    config::Config,
+    index::{
+      entry::{RuneBalance, RuneBalances},
+      mem_insert_outpoint_to_rune_balances, mem_insert_outpoint_to_utxo_entry,
+    },
+    test::{address, insert_inscription, inscription_id, outpoint, rune_id, tx_in, tx_out},
+  };
+
+  fn transaction(inputs: u32, output: Vec<TxOut>) -> Transaction {
+    Transaction {
+      version: Version::TWO,
+      lock_time: LockTime::ZERO,
+      input: (1..=inputs).map(|n| tx_in(outpoint(n))).collect(),
+      output,
+    }
+  }
+
+  fn op_return(runestone: &Runestone) -> TxOut {
+    TxOut {
+      value: Amount::ZERO,
+      script_pubkey: runestone.encipher(),
+    }
+  }
+
+  /// What the updater allocates to each output and burns, in the form of the
+  /// simulation.
+  fn reference(
+    tx: &Transaction,
+    etched: Option<(RuneId, Rune)>,
+    unallocated: HashMap<RuneId, Lot>,
+  ) -> (Vec<Vec<(RuneId, u128)>>, Vec<(RuneId, u128)>) {
+    let sorted = |balances: HashMap<RuneId, Lot>| {
+      let mut balances = balances
+        .into_iter()
+        .map(|(id, balance)| (id, balance.n()))
+        .collect::<Vec<(RuneId, u128)>>();
+      balances.sort();
+      balances
+    };
+    let (allocated, burned) = allocate(tx, Runestone::decipher(tx).as_ref(), etched, unallocated);
+    (allocated.into_iter().map(sorted).collect(), sorted(burned))
+  }
+
+  fn runes(simulation: &Simulation) -> Vec<Vec<(RuneId, u128)>> {
+    simulation
+      .outputs
+      .iter()
+      .map(|output| output.runes.clone())
+      .collect()
+  }
+
+  #[test]
+  fn etching() {
+    let index = Index::from_config(&Config::default());
+    let rune = Rune(10u128.pow(30));
+    let tx = transaction(
+      1,
+      vec![
+        op_return(&Runestone {
+          etching: Some(Etching {
+            premine: Some(1_000),
+            rune: Some(rune),
+            ..Default::default()
+          }),
+          ..Default::default()
+        }),
+        tx_out(1_000, address(0)),
+      ],
+    );
+
+    let simulation = simulate_transaction(&index, &tx, &[Some(1_000)]).unwrap();
+
+    assert_eq!(simulation.etched_rune, Some(SpacedRune { rune, spacers: 0 }));
+    assert!(!simulation.cenotaph);
+    assert_eq!(
+      (runes(&simulation), simulation.runes_burned),
+      reference(&tx, Some((RuneId::default(), rune)), HashMap::new())
+    );
+    assert_eq!(simulation.outputs[1].runes, vec![(RuneId::default(), 1_000)]);
+  }
+
+  #[test]
+  fn short_runes_unlock_on_the_configured_network() {
+    let index = Index::from_config(&Config::default());
+    let genesis = bitcoin::constants::genesis_block(bitcoin::Network::Regtest);
+    crate::index::mem_insert_block_header(210_000, genesis.header.store());
+    let rune = Rune(1_000);
+    let tx = transaction(
+      1,
+      vec![
+        op_return(&Runestone {
+          etching: Some(Etching {
+            rune: Some(rune),
+            ..Default::default()
+          }),
+          ..Default::default()
+        }),
+        tx_out(1_000, address(0)),
+      ],
+    );
+
+    let simulation = simulate_transaction(&index, &tx, &[Some(1_000)]).unwrap();
+
+    assert!(rune < Rune::minimum_at_height(bitcoin::Network::Bitcoin, Height(210_001)));
+    assert_eq!(simulation.etched_rune, Some(SpacedRune { rune, spacers: 0 }));
+  }
+
+  #[test]
+  fn transfer_with_edicts() {
+    let index = Index::from_config(&Config::default());
+    mem_insert_outpoint_to_rune_balances(
+      outpoint(1).store(),
+      RuneBalances {
+        balances: vec![RuneBalance {
+          rune_id: rune_id(1),
+          balance: 1_000,
+        }],
+      },
+    );
+    let tx = transaction(
+      1,
+      vec![
+        op_return(&Runestone {
+          edicts: vec![
+            Edict {
+              id: rune_id(1),
+              amount: 400,
+              output: 2,
+            },
+            Edict {
+              id: rune_id(1),
+              amount: 100,
+              output: 0,
+            },
+          ],
+          ..Default::default()
+        }),
+        tx_out(1_000, address(0)),
+        tx_out(1_000, address(1)),
+      ],
+    );
+
+    let simulation = simulate_transaction(&index, &tx, &[Some(2_000)]).unwrap();
+
+    assert_eq!(
+      (runes(&simulation), simulation.runes_burned.clone()),
+      reference(&tx, None, [(rune_id(1), Lot(1_000))].into())
+    );
+    assert_eq!(simulation.outputs[1].runes, vec![(rune_id(1), 500)]);
+    assert_eq!(simulation.outputs[2].runes, vec![(rune_id(1), 400)]);
+    assert_eq!(simulation.runes_burned, vec![(rune_id(1), 100)]);
+  }
+
+  #[test]
+  fn inscription_move() {
+    let index = Index::from_config(&Config::default());
+    insert_inscription(0, inscription_id(1));
+    let mut entry = UtxoEntryBuf::new();
+    entry.push_value(10_000, &index);
+    entry.push_inscription(0, 5_000, &index);
+    mem_insert_outpoint_to_utxo_entry(outpoint(1).store(), entry.as_ref().clone());
+
+    let tx = transaction(1, vec![tx_out(1_000, address(0)), tx_out(8_000, address(1))]);
+
+    let simulation = simulate_transaction(&index, &tx, &[]).unwrap();
+
+    assert!(simulation.outputs[0].inscriptions.is_empty());
+    assert_eq!(simulation.outputs[1].inscriptions, vec![inscription_id(1)]);
+    assert!(simulation.inscriptions_to_fee.is_empty());
+
+    // the fee is taken from the end, so an inscription past the outputs goes to it
+    let tx = transaction(1, vec![tx_out(4_000, address(0))]);
+    let simulation = simulate_transaction(&index, &tx, &[]).unwrap();
+    assert!(simulation.outputs[0].inscriptions.is_empty());
+    assert_eq!(simulation.inscriptions_to_fee, vec![inscription_id(1)]);
+  }
+}
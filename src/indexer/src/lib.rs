@@ -4,6 +4,7 @@ mod rpc;
 mod config;
 mod chain;
 mod bitcoin_api;
+mod block_source;
 mod index;
 mod inscriptions;
 mod macros;
@@ -13,6 +14,8 @@ mod test;
 mod re;
 mod runes;
 mod into_usize;
+#[cfg(feature = "canbench-rs")]
+mod benches;
 
 use anyhow::Error;
 use bitcoin::{hashes::Hash, OutPoint};
@@ -35,4 +38,38 @@ fn timestamp(seconds: u64) -> DateTime<Utc> {
     Utc
       .timestamp_opt(seconds.try_into().unwrap_or(i64::MAX), 0)
       .unwrap()
-  }
\ No newline at end of file
+  }
+/// Instructions the current message has used so far.
+#[cfg(not(test))]
+fn instruction_counter() -> u64 {
+    ic_cdk::api::instruction_counter()
+}
+
+#[cfg(test)]
+thread_local! {
+    static INSTRUCTION_COUNTER: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+/// Native tests have no counter, so each reading stands for a million
+/// instructions on top of what `set_instruction_counter` left it at.
+#[cfg(test)]
+fn instruction_counter() -> u64 {
+    INSTRUCTION_COUNTER.with(|counter| counter.replace(counter.get().saturating_add(1_000_000)))
+}
+
+#[cfg(test)]
+fn set_instruction_counter(instructions: u64) {
+    INSTRUCTION_COUNTER.with(|counter| counter.set(instructions));
+}
+
+/// Nanoseconds since the epoch, as of the current message.
+#[cfg(not(test))]
+fn time() -> u64 {
+    ic_cdk::api::time()
+}
+
+/// Native tests have no clock, so time stands still at the epoch.
+#[cfg(test)]
+fn time() -> u64 {
+    0
+}
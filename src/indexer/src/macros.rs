@@ -34,3 +34,13 @@ macro_rules! assert_matches {
     }
   }
 }
+
+/// Opens a `canbench` scope lasting until the end of the enclosing block, when
+/// built with the benchmarks.
+#[macro_export]
+macro_rules! bench_scope {
+  ($name:literal) => {
+    #[cfg(feature = "canbench-rs")]
+    let _scope = canbench_rs::bench_scope($name);
+  };
+}
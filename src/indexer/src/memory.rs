@@ -9,13 +9,16 @@ pub const HEIGHT_TO_LAST_SEQUENCE_NUMBER_MEMORY_ID: MemoryId = MemoryId::new(2);
 pub const HOME_INSCRIPTIONS_MEMORY_ID: MemoryId = MemoryId::new(3);
 pub const INSCRIPTION_ID_TO_SEQUENCE_NUMBER_MEMORY_ID: MemoryId = MemoryId::new(4);
 pub const INSCRIPTION_NUMBER_TO_SEQUENCE_NUMBER_MEMORY_ID: MemoryId = MemoryId::new(5);
-pub const OUTPOINT_TO_RUNE_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(6);
+// bincode-encoded in schema version 2, emptied by the migration to version 3
+pub const LEGACY_OUTPOINT_TO_RUNE_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(6);
 pub const OUTPOINT_TO_HEIGHT_MEMORY_ID: MemoryId = MemoryId::new(7);
 pub const OUTPOINT_TO_UTXO_ENTRY_MEMORY_ID: MemoryId = MemoryId::new(8);
-pub const RUNE_ID_TO_RUNE_ENTRY_MEMORY_ID: MemoryId = MemoryId::new(9);
+// bincode-encoded in schema version 2, emptied by the migration to version 3
+pub const LEGACY_RUNE_ID_TO_RUNE_ENTRY_MEMORY_ID: MemoryId = MemoryId::new(9);
 pub const RUNE_TO_RUNE_ID_MEMORY_ID: MemoryId = MemoryId::new(10);
 pub const SAT_TO_SATPOINT_MEMORY_ID: MemoryId = MemoryId::new(11);
-pub const SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY_MEMORY_ID: MemoryId = MemoryId::new(12);
+// bincode-encoded in schema version 2, emptied by the migration to version 3
+pub const LEGACY_SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY_MEMORY_ID: MemoryId = MemoryId::new(12);
 pub const SEQUENCE_NUMBER_TO_RUNE_ID_MEMORY_ID: MemoryId = MemoryId::new(13);
 pub const SEQUENCE_NUMBER_TO_SATPOINT_MEMORY_ID: MemoryId = MemoryId::new(14);
 pub const STATISTIC_TO_COUNT_MEMORY_ID: MemoryId = MemoryId::new(15);
@@ -23,7 +26,7 @@ pub const TRANSACTION_ID_TO_RUNE_MEMORY_ID: MemoryId = MemoryId::new(16);
 pub const TRANSACTION_ID_TO_TRANSACTION_MEMORY_ID: MemoryId = MemoryId::new(17);
 // pub const WRITE_TRANSACTION_STARTING_BLOCK_COUNT_TO_TIMESTAMP_MEMORY_ID: MemoryId = MemoryId::new(18);
 // pub const HEIGHT_TO_EVENTS_MEMORY_ID: MemoryId = MemoryId::new(19);
-// multimap memories
+// Vec-valued multimap memories of schema version 1, emptied by the migration to version 2
 pub const SAT_TO_SEQUENCE_NUMBERS_MEMORY_ID: MemoryId = MemoryId::new(20);
 pub const SEQUENCE_NUMBER_TO_CHILDRENS_MEMORY_ID: MemoryId = MemoryId::new(21);
 pub const SCRIPT_PUBKEY_TO_OUTPOINTS_MEMORY_ID: MemoryId = MemoryId::new(22);
@@ -32,6 +35,36 @@ pub const SCRIPT_PUBKEY_TO_OUTPOINTS_MEMORY_ID: MemoryId = MemoryId::new(22);
 pub const HEIGHT_TO_CHANGE_RECORD_RUNE_MEMORY_ID: MemoryId = MemoryId::new(23);
 pub const HEIGHT_TO_STATISTIC_RUNES_MEMORY_ID: MemoryId = MemoryId::new(24);
 pub const HEIGHT_TO_STATISTIC_RESERVED_RUNES_MEMORY_ID: MemoryId = MemoryId::new(25);
+pub const SEQUENCE_NUMBER_TO_INSCRIPTION_ATTRIBUTES_MEMORY_ID: MemoryId = MemoryId::new(26);
+pub const TRANSACTION_ID_TO_CACHED_TRANSACTION_MEMORY_ID: MemoryId = MemoryId::new(27);
+pub const LAST_USED_TO_CACHED_TRANSACTION_ID_MEMORY_ID: MemoryId = MemoryId::new(28);
+pub const HEIGHT_TO_BLOCK_SUMMARY_MEMORY_ID: MemoryId = MemoryId::new(29);
+pub const BLOCK_HASH_TO_HEIGHT_MEMORY_ID: MemoryId = MemoryId::new(30);
+pub const INDEXER_RUNNING_MEMORY_ID: MemoryId = MemoryId::new(31);
+pub const CONFIG_CHANGES_MEMORY_ID: MemoryId = MemoryId::new(32);
+pub const PRINCIPAL_TO_ROLE_MEMORY_ID: MemoryId = MemoryId::new(33);
+pub const FEATURE_TO_BACKFILL_MEMORY_ID: MemoryId = MemoryId::new(34);
+pub const HEIGHT_TO_CHECKPOINT_MEMORY_ID: MemoryId = MemoryId::new(35);
+pub const CHECKPOINT_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(36);
+pub const SCHEMA_VERSION_MEMORY_ID: MemoryId = MemoryId::new(37);
+pub const HEIGHT_TO_COMMITMENT_MEMORY_ID: MemoryId = MemoryId::new(38);
+pub const SNAPSHOT_MEMORY_ID: MemoryId = MemoryId::new(39);
+// multimap memories
+pub const SAT_TO_SEQUENCE_NUMBER_MEMORY_ID: MemoryId = MemoryId::new(40);
+pub const SEQUENCE_NUMBER_TO_CHILD_MEMORY_ID: MemoryId = MemoryId::new(41);
+pub const SCRIPT_PUBKEY_TO_OUTPOINT_MEMORY_ID: MemoryId = MemoryId::new(42);
+// compact encodings
+pub const OUTPOINT_TO_RUNE_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(43);
+pub const RUNE_ID_TO_RUNE_ENTRY_MEMORY_ID: MemoryId = MemoryId::new(44);
+pub const SEQUENCE_NUMBER_TO_INSCRIPTION_ENTRY_MEMORY_ID: MemoryId = MemoryId::new(45);
+pub const SEQUENCE_NUMBER_TO_SCRIPT_PUBKEY_MEMORY_ID: MemoryId = MemoryId::new(46);
+pub const UNFINISHED_BLOCK_MEMORY_ID: MemoryId = MemoryId::new(47);
+pub const CHECKPOINT_UNDO_MEMORY_ID: MemoryId = MemoryId::new(48);
+// certified trees
+pub const CERTIFIED_RUNE_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(49);
+pub const CERTIFIED_INSCRIPTION_SATPOINTS_MEMORY_ID: MemoryId = MemoryId::new(50);
+pub const CERTIFIED_DIRTY_MEMORY_ID: MemoryId = MemoryId::new(51);
+pub const CERTIFIED_REBUILD_MEMORY_ID: MemoryId = MemoryId::new(52);
 pub type VMemory = VirtualMemory<DefaultMemoryImpl>;
 
 thread_local! {
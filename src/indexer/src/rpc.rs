@@ -6,6 +6,7 @@ use bitcoin::{consensus::encode, Block};
 use bitcoin::{BlockHash, Txid};
 use bitcoincore_rpc_json::{GetBlockHeaderResult, GetRawTransactionResult};
 use ic_canister_log::log;
+use crate::config::url_host;
 use ic_cdk::api::management_canister::http_request::*;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -130,6 +131,20 @@ async fn make_single_request(
   args: CanisterHttpRequestArgument,
   estimate_cycle: u128,
 ) -> Result<HttpResponse> {
+  let config = crate::index::mem_get_config();
+  let max_cycles = config.max_cycles_per_outcall.map(u128::from);
+  let deadline = config
+    .rpc_timeout_seconds
+    .map(|seconds| ic_cdk::api::time() + seconds * 1_000_000_000);
+  if let Some(max_cycles) = max_cycles {
+    if estimate_cycle > max_cycles {
+      return Err(anyhow!(
+        "make_single_request: outcall needs {} cycles, above the limit of {}",
+        estimate_cycle,
+        max_cycles
+      ));
+    }
+  }
   let mut retry = 0;
   let mut cycles = estimate_cycle;
   loop {
@@ -137,8 +152,13 @@ async fn make_single_request(
     match response {
       Ok((response,)) => return Ok(response),
       Err((code, e)) => {
+        // reject messages may quote the url
+        let e = e.replace(&args.url, url_host(&args.url));
         retry += 1;
         cycles += cycles / 10;
+        if let Some(max_cycles) = max_cycles {
+          cycles = cycles.min(max_cycles);
+        }
         if retry > 3 {
           log!(
             ERROR,
@@ -148,6 +168,15 @@ async fn make_single_request(
           );
           break Err(anyhow!("make_single_request: retry limit exceeded {}", e));
         }
+        if deadline.is_some_and(|deadline| ic_cdk::api::time() > deadline) {
+          log!(
+            ERROR,
+            "rpc error: {:?} => {}; won't retry(exceeds timeout)",
+            code,
+            e
+          );
+          break Err(anyhow!("make_single_request: timed out {}", e));
+        }
         log!(
           ERROR,
           "rpc error: {:?} => {}; will retry with extra {} cycles",
@@ -160,6 +189,28 @@ async fn make_single_request(
   }
 }
 
+/// Sends `request` to each configured endpoint in turn until one succeeds.
+async fn with_fallback<R, F, Fut>(request: F) -> Result<R>
+where
+  F: Fn(String, u64) -> Fut,
+  Fut: std::future::Future<Output = Result<R>>,
+{
+  let config = crate::index::mem_get_config();
+  let subnet_nodes = config.get_subnet_nodes();
+  let mut error = anyhow!("no rpc endpoint configured");
+  for (i, url) in config.get_rpc_urls().into_iter().enumerate() {
+    let host = url_host(&url).to_string();
+    match request(url, subnet_nodes).await {
+      Ok(result) => return Ok(result),
+      Err(e) => {
+        log!(ERROR, "rpc endpoint {} ({}) failed: {:?}", i, host, e);
+        error = e;
+      }
+    }
+  }
+  Err(error)
+}
+
 pub(crate) async fn make_rpc<R>(
   url: impl ToString,
   endpoint: &'static str,
@@ -259,13 +310,9 @@ async fn inner_get_block(
 }
 
 pub(crate) async fn get_block(hash: BlockHash) -> Result<crate::index::updater::BlockData> {
-  let config = crate::index::mem_get_config();
-  let block = inner_get_block(
-    &config.bitcoin_rpc_url,
-    MAX_RESPONSE_BYTES,
-    config.get_subnet_nodes(),
-    hash,
-  )
+  let block = with_fallback(|url, subnet_nodes| async move {
+    inner_get_block(&url, MAX_RESPONSE_BYTES, subnet_nodes, hash).await
+  })
   .await?;
 
   if block.block_hash() != hash {
@@ -306,14 +353,9 @@ pub(crate) async fn get_raw_transaction_info(
   txid: &Txid,
   block_hash: Option<&BlockHash>,
 ) -> Result<GetRawTransactionResult> {
-  let config = crate::index::mem_get_config();
-  inner_get_raw_transaction_info(
-    &config.bitcoin_rpc_url,
-    4_096,
-    config.get_subnet_nodes(),
-    txid,
-    block_hash,
-  )
+  with_fallback(|url, subnet_nodes| async move {
+    inner_get_raw_transaction_info(&url, 4_096, subnet_nodes, txid, block_hash).await
+  })
   .await
 }
 
@@ -339,13 +381,9 @@ async fn inner_get_block_header_info(
 pub(crate) async fn get_block_header_info(
   hash: &bitcoin::BlockHash,
 ) -> Result<GetBlockHeaderResult> {
-  let config = crate::index::mem_get_config();
-  inner_get_block_header_info(
-    &config.bitcoin_rpc_url,
-    1_024,
-    config.get_subnet_nodes(),
-    hash,
-  )
+  with_fallback(|url, subnet_nodes| async move {
+    inner_get_block_header_info(&url, 1_024, subnet_nodes, hash).await
+  })
   .await
 }
 
@@ -368,13 +406,32 @@ async fn inner_get_block_hash(
 }
 
 pub(crate) async fn get_block_hash(height: u32) -> Result<BlockHash> {
-  let config = crate::index::mem_get_config();
-  inner_get_block_hash(
-    &config.bitcoin_rpc_url,
-    256,
-    config.get_subnet_nodes(),
-    height,
+  with_fallback(|url, subnet_nodes| async move {
+    inner_get_block_hash(&url, 256, subnet_nodes, height).await
+  })
+  .await
+}
+
+async fn inner_get_block_count(
+  url: &str,
+  max_response_bytes: u64,
+  subnet_nodes: u64,
+) -> Result<u32> {
+  let res: u32 = make_rpc(
+    url,
+    "getblockcount",
+    Vec::<serde_json::Value>::new(),
+    max_response_bytes,
+    subnet_nodes,
   )
+  .await?;
+  Ok(res)
+}
+
+pub(crate) async fn get_block_count() -> Result<u32> {
+  with_fallback(|url, subnet_nodes| async move {
+    inner_get_block_count(&url, 256, subnet_nodes).await
+  })
   .await
 }
 
@@ -4,6 +4,11 @@ use {
   ordinals::{RuneId, SatPoint}
 };
 
+// tests index these through the block source, only the benchmarks directly
+#[cfg(any(test, feature = "canbench-rs"))]
+#[cfg_attr(not(feature = "canbench-rs"), allow(dead_code))]
+pub(crate) mod blocks;
+
 pub(crate) fn rune_id(tx: u32) -> RuneId {
   RuneId { block: 1, tx }
 }
@@ -125,6 +130,24 @@ pub(crate) fn inscription_id(n: u32) -> InscriptionId {
   format!("{}i{n}", hex.repeat(64)).parse().unwrap()
 }
 
+/// Stores a bare inscription entry, numbered after its sequence number.
+pub(crate) fn insert_inscription(sequence_number: u32, id: InscriptionId) {
+  crate::index::mem_insert_sequence_number_to_inscription_entry(
+    sequence_number,
+    crate::index::entry::InscriptionEntry {
+      charms: 0,
+      fee: 0,
+      height: 0,
+      id,
+      inscription_number: sequence_number.try_into().unwrap(),
+      parents: Vec::new(),
+      sat: None,
+      sequence_number,
+      timestamp: 0,
+    },
+  );
+}
+
 pub(crate) fn envelope(payload: &[&[u8]]) -> Witness {
   let mut builder = script::Builder::new()
     .push_opcode(opcodes::OP_FALSE)
@@ -0,0 +1,240 @@
+//! Synthetic blocks shared by the benchmarks and the native indexing tests.
+
+use bitcoin::{
+  absolute::LockTime,
+  block::{Header, Version},
+  hashes::Hash,
+  script,
+  transaction, Amount, Block, BlockHash, CompactTarget, OutPoint, Sequence, Transaction, TxIn,
+  TxMerkleNode, TxOut, Txid, Witness,
+};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ordinals::{Edict, Etching, RuneId, Runestone, Terms};
+
+use super::{address, envelope, tx_in, tx_out};
+use crate::index::{
+  entry::Entry,
+  mem_get_config, mem_insert_block_header, mem_insert_outpoint_to_utxo_entry, mem_insert_script_pubkey_to_outpoints,
+  mem_set_config, status,
+  updater::{index_block, BlockData},
+  utxo_entry::UtxoEntryBuf,
+  Index,
+};
+
+/// Outputs of the etching, each holding an equal share of the premine.
+pub(crate) const RUNE_OUTPUTS: u32 = 500;
+pub(crate) const INSCRIPTIONS: u32 = 100;
+pub(crate) const MINTS: u32 = 100;
+
+/// The rune etched by the second synthetic block.
+pub(crate) const RUNE_ID: RuneId = RuneId { block: 1, tx: 1 };
+
+/// A regtest chain indexing every feature but sats, built from synthetic
+/// blocks:
+///
+/// 0. a coinbase with an output to spend for each later etching, inscription
+///    and mint
+/// 1. an etching with a premine split over `RUNE_OUTPUTS` outputs, and
+///    `INSCRIPTIONS` inscriptions
+/// 2. a transfer of every rune output and inscription, and `MINTS` mints
+pub(crate) struct Chain {
+  index: Index,
+  height: u32,
+  prev_blockhash: BlockHash,
+  funding: Txid,
+  etching: Txid,
+  inscriptions: Vec<Txid>,
+}
+
+impl Chain {
+  pub(crate) fn new() -> Self {
+    let mut config = mem_get_config();
+    config.network = BitcoinNetwork::Regtest;
+    config.index_addresses = Some(true);
+    config.index_inscriptions = Some(true);
+    config.index_runes = Some(true);
+    config.index_sats = Some(false);
+    let index = Index::from_config(&config);
+    mem_set_config(config).unwrap();
+
+    Self {
+      index,
+      height: 0,
+      prev_blockhash: BlockHash::all_zeros(),
+      funding: Txid::all_zeros(),
+      etching: Txid::all_zeros(),
+      inscriptions: Vec::new(),
+    }
+  }
+
+  pub(crate) fn index(&self) -> &Index {
+    &self.index
+  }
+
+  pub(crate) fn etching(&self) -> Txid {
+    self.etching
+  }
+
+  pub(crate) fn inscriptions(&self) -> &[Txid] {
+    &self.inscriptions
+  }
+
+  /// Indexes the next block and records its header, like the update loop.
+  pub(crate) fn index_block(&mut self, block: BlockData) {
+    futures::executor::block_on(index_block(self.height, &block, &self.index, status::generation())).unwrap();
+    mem_insert_block_header(self.height, block.header.store());
+    self.prev_blockhash = block.header.block_hash();
+    self.height += 1;
+  }
+
+  fn block(&self, coinbase_outputs: Vec<TxOut>, transactions: Vec<Transaction>) -> BlockData {
+    let coinbase = Transaction {
+      version: transaction::Version::TWO,
+      lock_time: LockTime::ZERO,
+      input: vec![TxIn {
+        previous_output: OutPoint::null(),
+        script_sig: script::Builder::new().push_int(self.height.into()).into_script(),
+        sequence: Sequence::MAX,
+        witness: Witness::new(),
+      }],
+      output: coinbase_outputs,
+    };
+
+    Block {
+      header: Header {
+        version: Version::TWO,
+        prev_blockhash: self.prev_blockhash,
+        merkle_root: TxMerkleNode::all_zeros(),
+        time: 1_713_571_767 + self.height * 600,
+        bits: CompactTarget::from_consensus(0x207f_ffff),
+        nonce: 0,
+      },
+      txdata: [coinbase].into_iter().chain(transactions).collect(),
+    }
+    .into()
+  }
+
+  /// A block whose only transaction is a recorded `coinbase`, to fund the
+  /// inputs of a recorded block.
+  pub(crate) fn coinbase_block(&self, coinbase: Transaction) -> BlockData {
+    let mut block = self.block(Vec::new(), Vec::new());
+    block.txdata = vec![(coinbase.clone(), coinbase.compute_txid())];
+    block
+  }
+
+  /// Moves the chain to `height` with the recorded `prevouts` unspent, the way
+  /// the update loop records outputs it fetches, so a recorded block spending
+  /// them can be indexed there.
+  pub(crate) fn skip_to(&mut self, height: u32, prevouts: impl IntoIterator<Item = (OutPoint, TxOut)>) {
+    for (outpoint, txout) in prevouts {
+      let mut entry = UtxoEntryBuf::new();
+      entry.push_value(txout.value.to_sat(), &self.index);
+      if self.index.index_addresses {
+        entry.push_script_pubkey(txout.script_pubkey.as_bytes(), &self.index);
+        mem_insert_script_pubkey_to_outpoints(txout.script_pubkey.to_bytes(), outpoint);
+      }
+      mem_insert_outpoint_to_utxo_entry(outpoint.store(), entry.as_ref().clone());
+    }
+    self.height = height;
+  }
+
+  pub(crate) fn funding_block(&mut self) -> BlockData {
+    let block = self.block(
+      (0..1 + INSCRIPTIONS + MINTS)
+        .map(|n| tx_out(1_000_000, address(n % 10)))
+        .collect(),
+      Vec::new(),
+    );
+    self.funding = block.txdata[0].1;
+    block
+  }
+
+  pub(crate) fn etching_block(&mut self) -> BlockData {
+    let runestone = Runestone {
+      edicts: vec![Edict {
+        id: RuneId::default(),
+        amount: 0,
+        output: RUNE_OUTPUTS + 1,
+      }],
+      etching: Some(Etching {
+        divisibility: Some(2),
+        premine: Some(u128::from(RUNE_OUTPUTS) * 1_000),
+        symbol: Some('ᚠ'),
+        terms: Some(Terms {
+          amount: Some(1_000),
+          cap: Some(u128::from(MINTS)),
+          ..Default::default()
+        }),
+        ..Default::default()
+      }),
+      ..Default::default()
+    };
+
+    let etching = transaction(
+      vec![tx_in(OutPoint { txid: self.funding, vout: 0 })],
+      [op_return(&runestone)]
+        .into_iter()
+        .chain((0..RUNE_OUTPUTS).map(|n| tx_out(1_000, address(n % 10))))
+        .collect(),
+    );
+
+    let inscriptions = (0..INSCRIPTIONS).map(|n| {
+      let mut input = tx_in(OutPoint { txid: self.funding, vout: 1 + n });
+      input.witness = envelope(&[b"ord", &[1], b"text/plain;charset=utf-8", &[], &n.to_le_bytes()]);
+      transaction(vec![input], vec![tx_out(10_000, address(n % 10))])
+    });
+
+    let block = self.block(
+      vec![tx_out(5_000_000_000, address(0))],
+      [etching].into_iter().chain(inscriptions).collect(),
+    );
+    self.etching = block.txdata[1].1;
+    self.inscriptions = block.txdata[2..].iter().map(|(_, txid)| *txid).collect();
+    block
+  }
+
+  pub(crate) fn transfer_block(&mut self) -> BlockData {
+    let transfers = (0..RUNE_OUTPUTS).map(|n| {
+      let mut inputs = vec![tx_in(OutPoint { txid: self.etching, vout: 1 + n })];
+      if let Some(txid) = self.inscriptions.get(n as usize) {
+        inputs.push(tx_in(OutPoint { txid: *txid, vout: 0 }));
+      }
+      transaction(inputs, vec![tx_out(500, address((n + 1) % 10)), tx_out(500, address(n % 10))])
+    });
+
+    let mint = Runestone {
+      mint: Some(RUNE_ID),
+      ..Default::default()
+    };
+    let mints = (0..MINTS).map(|n| {
+      transaction(
+        vec![tx_in(OutPoint {
+          txid: self.funding,
+          vout: 1 + INSCRIPTIONS + n,
+        })],
+        vec![op_return(&mint), tx_out(990_000, address(n % 10))],
+      )
+    });
+
+    self.block(
+      vec![tx_out(5_000_000_000, address(0))],
+      transfers.chain(mints).collect(),
+    )
+  }
+}
+
+fn transaction(input: Vec<TxIn>, output: Vec<TxOut>) -> Transaction {
+  Transaction {
+    version: transaction::Version::TWO,
+    lock_time: LockTime::ZERO,
+    input,
+    output,
+  }
+}
+
+fn op_return(runestone: &Runestone) -> TxOut {
+  TxOut {
+    value: Amount::ZERO,
+    script_pubkey: runestone.encipher(),
+  }
+}
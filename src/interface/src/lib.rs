@@ -1,5 +1,6 @@
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Principal};
 use serde::Serialize;
+use serde_bytes::ByteBuf;
 
 #[derive(Debug, CandidType, Deserialize)]
 pub struct InscriptionEntry {
@@ -56,6 +57,204 @@ pub struct RuneBalance {
   pub symbol: Option<String>,
 }
 
+#[derive(Debug, CandidType, Deserialize)]
+pub enum Direction {
+  Ascending,
+  Descending,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct InscriptionPage {
+  pub inscriptions: Vec<String>,
+  /// The `from_sequence` to pass to fetch the next page, if there is one.
+  pub next: Option<u32>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct OutputAssets {
+  pub inscriptions: bool,
+  pub runes: bool,
+  pub rare_sats: Option<bool>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct RuneAmount {
+  pub rune_id: String,
+  pub amount: u128,
+}
+
+/// `certificate` is the IC certificate over the canister's certified data and
+/// `witness` the CBOR-encoded hash tree proving the answer at `height` against it.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct CertifiedRuneBalances {
+  pub height: u32,
+  pub balances: Option<Vec<RuneAmount>>,
+  pub certificate: ByteBuf,
+  pub witness: ByteBuf,
+}
+
+/// Certified like [`CertifiedRuneBalances`], with `satpoint` as `txid:vout:offset`.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct CertifiedInscriptionLocation {
+  pub height: u32,
+  pub satpoint: Option<String>,
+  pub certificate: ByteBuf,
+  pub witness: ByteBuf,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct SimulatedOutput {
+  pub inscriptions: Vec<String>,
+  pub runes: Vec<RuneAmount>,
+  pub rare_sats: Option<Vec<u64>>,
+  pub op_return: bool,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct TransactionSimulation {
+  pub outputs: Vec<SimulatedOutput>,
+  pub inscriptions_to_fee: Vec<String>,
+  pub unbound_inscriptions: Vec<String>,
+  pub rare_sats_to_fee: Option<Vec<u64>>,
+  pub runes_burned: Vec<RuneAmount>,
+  pub cenotaph: bool,
+  pub etched_rune: Option<String>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct BlockHeader {
+  pub height: u32,
+  pub hash: String,
+  pub version: i32,
+  pub previous_block_hash: String,
+  pub merkle_root: String,
+  pub timestamp: u32,
+  pub bits: u32,
+  pub nonce: u32,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct BlockInfo {
+  pub header: BlockHeader,
+  pub transaction_count: u32,
+  pub fees: u64,
+  pub inscriptions_created: u64,
+  pub inscription_transfers: u64,
+  pub runes_etched: u64,
+  pub rune_mints: u64,
+  pub rune_burns: u64,
+  pub rune_transfers: u64,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub enum Role {
+  Reader,
+  Operator,
+  Admin,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct ConfigChange {
+  pub index: u64,
+  pub timestamp: u64,
+  pub caller: Principal,
+  pub change: String,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub enum IndexFeature {
+  Addresses,
+  Inscriptions,
+  Runes,
+  Sats,
+  Transactions,
+}
+
+/// `target_height` is the indexed tip the backfill has to reach.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct BackfillProgress {
+  pub feature: IndexFeature,
+  pub start_height: u32,
+  pub next_height: u32,
+  pub target_height: Option<u32>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct SnapshotTable {
+  pub name: String,
+  pub entries: u64,
+}
+
+/// Describes a snapshot of the index at `height`. `hash` chains the hashes of
+/// all `chunks` in order, and `state_commitment` can be compared against
+/// `get_state_commitment(height)` of a trusted indexer.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct SnapshotManifest {
+  pub schema_version: u32,
+  pub network: String,
+  pub features: Vec<IndexFeature>,
+  pub height: u32,
+  pub block_hash: String,
+  pub state_commitment: Option<String>,
+  pub tables: Vec<SnapshotTable>,
+  pub chunks: u32,
+  pub hash: String,
+}
+
+/// Position of a snapshot chunk: a table and the last key before it.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct SnapshotCursor {
+  pub table: u8,
+  pub after: Option<ByteBuf>,
+}
+
+/// Raw keys and values of one table. `next` is `None` on the last chunk.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct SnapshotChunk {
+  pub table: u8,
+  pub entries: Vec<(ByteBuf, ByteBuf)>,
+  pub hash: String,
+  pub next: Option<SnapshotCursor>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub enum IndexerState {
+  Running,
+  Stopped,
+  Stalled,
+}
+
+/// Times are nanoseconds since the epoch.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct IndexerStatus {
+  pub state: IndexerState,
+  pub last_indexed_height: Option<u32>,
+  pub last_indexed_at: Option<u64>,
+  pub last_error: Option<String>,
+  pub last_error_at: Option<u64>,
+  pub consecutive_failures: u32,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+pub struct Statistics {
+  pub blessed_inscriptions: u64,
+  pub cursed_inscriptions: u64,
+  pub unbound_inscriptions: u64,
+  pub lost_sats: u64,
+  pub outputs_traversed: u64,
+  pub sat_ranges: u64,
+  pub commits: u64,
+  pub runes: u64,
+  pub reserved_runes: u64,
+  pub transaction_cache_bytes: u64,
+  pub indexed_height: Option<u32>,
+  /// The chain tip last reported by the bitcoin RPC, asked for at least every
+  /// five minutes while indexing runs, and unknown until the indexer first asks.
+  pub rpc_tip_height: Option<u32>,
+  pub sync_lag: Option<u32>,
+  pub tables: Vec<(String, u64)>,
+}
+
 #[derive(Debug, CandidType, Deserialize)]
 pub struct GetEtchingResult {
   pub confirmations: u32,
@@ -91,4 +290,18 @@ pub struct RuneEntry {
 #[derive(Debug, CandidType, Deserialize)]
 pub enum Error {
   MaxOutpointsExceeded,
+  /// A checkpoint is being restored, so the outputs may not match any block yet.
+  CheckpointRestoring,
+  /// The stable maps are still being migrated to the schema of this build.
+  MigrationPending,
+}
+
+impl std::fmt::Display for Error {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Self::MaxOutpointsExceeded => write!(f, "too many outpoints"),
+      Self::CheckpointRestoring => write!(f, "a checkpoint is being restored"),
+      Self::MigrationPending => write!(f, "the stable maps are still being migrated"),
+    }
+  }
 }
\ No newline at end of file
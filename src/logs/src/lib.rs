@@ -3,6 +3,7 @@ pub mod http;
 use ic_canister_log::{declare_log_buffer, export as export_logs, GlobalBuffer, Sink};
 use serde::Deserialize;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
 
 // High-priority error messages.
 declare_log_buffer!(name = ERROR_BUF, capacity = 1000);
@@ -16,15 +17,26 @@ declare_log_buffer!(name = DEBUG_BUF, capacity = 1000);
 // Trace of HTTP requests and responses.
 declare_log_buffer!(name = TRACE_HTTP_BUF, capacity = 1000);
 
-pub const ERROR: PrintProxySink = PrintProxySink("ERROR", &ERROR_BUF);
-pub const INFO: PrintProxySink = PrintProxySink("INFO", &INFO_BUF);
-pub const DEBUG: PrintProxySink = PrintProxySink("DEBUG", &DEBUG_BUF);
-pub const TRACE_HTTP: PrintProxySink = PrintProxySink("TRACE_HTTP", &TRACE_HTTP_BUF);
+pub const ERROR: PrintProxySink = PrintProxySink("ERROR", &ERROR_BUF, Priority::Error);
+pub const INFO: PrintProxySink = PrintProxySink("INFO", &INFO_BUF, Priority::Info);
+pub const DEBUG: PrintProxySink = PrintProxySink("DEBUG", &DEBUG_BUF, Priority::Debug);
+pub const TRACE_HTTP: PrintProxySink = PrintProxySink("TRACE_HTTP", &TRACE_HTTP_BUF, Priority::TraceHttp);
 
-pub struct PrintProxySink(&'static str, &'static GlobalBuffer);
+// Most verbose priority that is still recorded, everything by default.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(Priority::Debug.verbosity());
+
+/// Drops messages more verbose than `level`.
+pub fn set_log_level(level: Priority) {
+    LOG_LEVEL.store(level.verbosity(), Ordering::Relaxed);
+}
+
+pub struct PrintProxySink(&'static str, &'static GlobalBuffer, Priority);
 
 impl Sink for PrintProxySink {
     fn append(&self, entry: ic_canister_log::LogEntry) {
+        if self.2.verbosity() > LOG_LEVEL.load(Ordering::Relaxed) {
+            return;
+        }
         ic_cdk::println!("{} {}:{} {}", self.0, entry.file, entry.line, entry.message);
         self.1.append(entry)
     }
@@ -38,6 +50,16 @@ pub enum Priority {
     Debug,
 }
 
+impl Priority {
+    const fn verbosity(self) -> u8 {
+        match self {
+            Priority::Error => 0,
+            Priority::Info => 1,
+            Priority::TraceHttp | Priority::Debug => 2,
+        }
+    }
+}
+
 impl FromStr for Priority {
     type Err = String;
 